@@ -0,0 +1,362 @@
+use std::future::Future;
+use std::io;
+use std::iter;
+use std::time::{Duration, Instant};
+
+use futures::select;
+use futures_util::future::FutureExt;
+use futures_util::sink::SinkExt;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Framed};
+
+use rtalk_codec::{Event, EventCodec};
+
+use crate::stats::Samples;
+
+// Bench messages start with this, followed by when they were sent, so that
+// they can be told apart from anything else said while the bench runs.
+const TAG: &str = "rtalk-bench";
+
+// How long clients keep listening once the last message has been sent, for
+// the ones still on their way.
+const DRAIN: Duration = Duration::from_secs(2);
+
+// How many messages a client can be told to send before it has to catch up.
+// Past that its turn is skipped, and counted.
+const BACKLOG: usize = 16;
+
+pub struct Settings {
+    pub clients: usize,
+    // new connections per second
+    pub join_rate: f64,
+    // messages per second, taking turns between the clients
+    pub message_rate: f64,
+    // bytes of text in each message
+    pub message_size: usize,
+    // how long to keep sending for once everyone has joined
+    pub duration: Duration,
+    // how long each client waits to be let in
+    pub join_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            clients: 100,
+            join_rate: 50.0,
+            message_rate: 100.0,
+            message_size: 64,
+            duration: Duration::from_secs(30),
+            join_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// What one simulated client saw.
+#[derive(Default)]
+struct Tally {
+    sent: u64,
+    received: u64,
+    warnings: u64,
+    disconnected: bool,
+    // how long each bench message took to come back, in microseconds
+    latencies: Vec<u64>,
+    // when the last one did, in microseconds since the bench started
+    last_received: u64,
+}
+
+impl Tally {
+    // Takes note of an event from the server, returning false once the
+    // connection is gone.
+    fn record(&mut self, event: Option<io::Result<Event>>, start: Instant) -> bool {
+        match event {
+            Some(Ok(Event::MessageReceived(_, _, text))) => {
+                if let Some(sent) = sent_at(&text) {
+                    let now = micros(start);
+                    self.received += 1;
+                    self.latencies.push(now.saturating_sub(sent));
+                    self.last_received = now;
+                }
+            }
+            Some(Ok(Event::Warning(_))) => self.warnings += 1,
+            Some(Ok(Event::Disconnect(_))) | Some(Err(_)) | None => {
+                self.disconnected = true;
+                return false;
+            }
+            Some(Ok(_)) => {}
+        }
+        true
+    }
+}
+
+// Joins `settings.clients` clients over the transports `connect` opens, has
+// them talk to each other for `settings.duration` and reports how that went.
+// Every client gets every message, its own included, so the latencies are
+// for the whole round trip through the server's broadcast.
+pub async fn run<C, F, T>(settings: &Settings, mut connect: C) -> Value
+where
+    C: FnMut() -> F,
+    F: Future<Output = io::Result<T>> + Send + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let start = Instant::now();
+    let mut joining = time::interval(per_second(settings.join_rate));
+    let mut clients = Vec::new();
+    for id in 0..settings.clients {
+        joining.tick().await;
+        let (joined, joined_rx) = oneshot::channel();
+        let (orders, orders_rx) = mpsc::channel(BACKLOG);
+        let task = simulate(
+            id,
+            connect(),
+            settings.message_size,
+            settings.join_timeout,
+            start,
+            joined,
+            orders_rx,
+        );
+        clients.push((orders, joined_rx, tokio::spawn(task)));
+    }
+
+    // messages only start once everyone who is going to join has
+    let mut join_times = Samples::default();
+    let mut joined = 0;
+    let mut orders = Vec::new();
+    let mut tasks = Vec::new();
+    for (order, joined_rx, task) in clients {
+        if let Ok(Some(took)) = joined_rx.await {
+            join_times.push(took.as_micros() as u64);
+            joined += 1;
+            orders.push(order);
+        }
+        tasks.push(task);
+    }
+
+    let mut sending = time::interval(per_second(settings.message_rate));
+    let began = micros(start);
+    let mut skipped = 0;
+    let mut next = 0;
+    while !orders.is_empty() && micros(start) - began < settings.duration.as_micros() as u64 {
+        sending.tick().await;
+        if orders[next % orders.len()].try_send(()).is_err() {
+            skipped += 1;
+        }
+        next += 1;
+    }
+    drop(orders);
+
+    let mut latencies = Samples::default();
+    let mut sent = 0;
+    let mut received = 0;
+    let mut warnings = 0;
+    let mut disconnected = 0;
+    let mut last_received = began;
+    for task in tasks {
+        if let Ok(tally) = task.await {
+            sent += tally.sent;
+            received += tally.received;
+            warnings += tally.warnings;
+            disconnected += tally.disconnected as usize;
+            last_received = last_received.max(tally.last_received);
+            latencies.extend(tally.latencies);
+        }
+    }
+
+    let sending_secs = settings.duration.as_secs_f64();
+    let receiving_secs = (last_received - began) as f64 / 1_000_000.0;
+    json!({
+        "settings": {
+            "clients": settings.clients,
+            "join_rate": settings.join_rate,
+            "message_rate": settings.message_rate,
+            "message_size": settings.message_size,
+            "duration_secs": sending_secs,
+        },
+        "clients": {
+            "joined": joined,
+            "failed": settings.clients - joined,
+            "disconnected": disconnected,
+        },
+        "join_ms": join_times.summary(),
+        "messages": {
+            "sent": sent,
+            "skipped": skipped,
+            "warnings": warnings,
+            // everyone who joined should have got every message sent
+            "expected": sent * joined as u64,
+            "received": received,
+        },
+        "throughput": {
+            "sent_per_sec": rate(sent, sending_secs),
+            "received_per_sec": rate(received, receiving_secs),
+        },
+        "latency_ms": latencies.summary(),
+    })
+}
+
+// One client: joins, says that it has (or hasn't), sends a message each time
+// it's told to and keeps track of the bench messages it gets back.
+async fn simulate<F, T>(
+    id: usize,
+    connect: F,
+    message_size: usize,
+    join_timeout: Duration,
+    start: Instant,
+    joined: oneshot::Sender<Option<Duration>>,
+    mut orders: mpsc::Receiver<()>,
+) -> Tally
+where
+    F: Future<Output = io::Result<T>>,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut tally = Tally::default();
+    let began = Instant::now();
+    let mut framed = match time::timeout(join_timeout, join(id, connect)).await {
+        Ok(Ok(framed)) => {
+            let _ = joined.send(Some(began.elapsed()));
+            framed
+        }
+        _ => {
+            let _ = joined.send(None);
+            return tally;
+        }
+    };
+
+    loop {
+        select! {
+            event = framed.next().fuse() => {
+                if !tally.record(event, start) {
+                    return tally;
+                }
+            },
+            order = orders.recv().fuse() => match order {
+                Some(()) => {
+                    let text = payload(micros(start), message_size);
+                    if framed.send(Event::MessageSend(text)).await.is_err() {
+                        tally.disconnected = true;
+                        return tally;
+                    }
+                    tally.sent += 1;
+                },
+                None => break,
+            },
+        }
+    }
+
+    let _ = time::timeout(DRAIN, async {
+        while tally.record(framed.next().await, start) {}
+    })
+    .await;
+    let _ = framed.send(Event::Leave()).await;
+    tally
+}
+
+async fn join<F, T>(id: usize, connect: F) -> io::Result<Framed<T, EventCodec>>
+where
+    F: Future<Output = io::Result<T>>,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = EventCodec.framed(connect.await?);
+    framed
+        .send(Event::RequestJoin(format!("bench{}", id)))
+        .await?;
+
+    while let Some(event) = framed.next().await {
+        match event? {
+            Event::UserList(_) => return Ok(framed),
+            Event::Disconnect(reason) => return Err(io::Error::other(reason)),
+            _ => {}
+        }
+    }
+    Err(io::ErrorKind::UnexpectedEof.into())
+}
+
+// A message saying when it was sent, padded out to `size` bytes if it isn't
+// that long already.
+fn payload(sent: u64, size: usize) -> String {
+    let mut text = format!("{} {} ", TAG, sent);
+    let padding = size.saturating_sub(text.len());
+    text.extend(iter::repeat_n('x', padding));
+    text
+}
+
+fn sent_at(text: &str) -> Option<u64> {
+    let mut words = text.split(' ');
+    if words.next()? != TAG {
+        return None;
+    }
+    words.next()?.parse().ok()
+}
+
+fn micros(start: Instant) -> u64 {
+    start.elapsed().as_micros() as u64
+}
+
+fn per_second(rate: f64) -> Duration {
+    Duration::from_secs_f64(1.0 / rate)
+}
+
+fn rate(count: u64, secs: f64) -> f64 {
+    if secs > 0.0 {
+        count as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::io::duplex;
+
+    use rtalk_server::testing;
+
+    #[test]
+    fn payloads_say_when_they_were_sent() {
+        let text = payload(1234, 100);
+        assert_eq!(text.len(), 100);
+        assert_eq!(sent_at(&text), Some(1234));
+
+        // too small to pad, but still readable
+        assert_eq!(sent_at(&payload(1234, 0)), Some(1234));
+        assert_eq!(sent_at("rtalk-bench? 1234"), None);
+    }
+
+    #[tokio::test]
+    async fn everyone_hears_every_message() {
+        let mut config = testing::config();
+        config.rate_limit.burst = 1000;
+        config.rate_limit.ip_burst = 1000;
+        config.rate_limit.max_connections_per_ip = 100;
+        let server = testing::server_with(config);
+
+        let settings = Settings {
+            clients: 4,
+            join_rate: 100.0,
+            message_rate: 20.0,
+            duration: Duration::from_millis(500),
+            ..Settings::default()
+        };
+        let mut port = 0;
+        let report = run(&settings, || {
+            port += 1;
+            let (client, server_end) = duplex(64 * 1024);
+            server.connect(server_end, SocketAddr::from(([127, 0, 0, 1], port)));
+            async { Ok(client) }
+        })
+        .await;
+
+        assert_eq!(report["clients"]["joined"], 4);
+        let sent = report["messages"]["sent"].as_u64().unwrap();
+        assert!(sent > 0);
+        assert_eq!(report["messages"]["received"], sent * 4);
+        assert_eq!(report["latency_ms"]["count"], sent * 4);
+    }
+}
@@ -0,0 +1,76 @@
+#![recursion_limit = "256"]
+
+mod bench;
+mod stats;
+
+use std::env;
+use std::error::Error;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use bench::Settings;
+
+const SERVER_ADDR: &str = "127.0.0.1:3215";
+
+// The server's rate limits apply to the bench like to anyone else, and all
+// its clients come from the one address, so the server needs a config that
+// leaves room for them, like bench-server.toml.
+const USAGE: &str = "\
+Usage: rtalk-bench [options]
+  --addr <host:port>          the server to connect to (127.0.0.1:3215)
+  --clients <n>               how many clients to simulate (100)
+  --join-rate <n>             clients to connect per second (50)
+  --message-rate <n>          messages per second, between them all (100)
+  --message-size <bytes>      how long each message is (64)
+  --duration <secs>           how long to keep sending for (30)
+  --join-timeout <secs>       how long each client waits to be let in (10)";
+
+// The value that goes with a numeric option.
+fn number(value: Option<String>, option: &str) -> Result<f64, Box<dyn Error>> {
+    value
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite() && *value >= 0.0)
+        .ok_or_else(|| format!("{} needs a number", option).into())
+}
+
+// The same, for rates, which can't be nothing.
+fn rate(value: Option<String>, option: &str) -> Result<f64, Box<dyn Error>> {
+    match number(value, option)? {
+        rate if rate > 0.0 => Ok(rate),
+        _ => Err(format!("{} has to be more than 0", option).into()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut addr = SERVER_ADDR.to_string();
+    let mut settings = Settings::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--addr" => addr = args.next().ok_or("--addr needs an address")?,
+            "--clients" => settings.clients = number(args.next(), "--clients")? as usize,
+            "--join-rate" => settings.join_rate = rate(args.next(), "--join-rate")?,
+            "--message-rate" => settings.message_rate = rate(args.next(), "--message-rate")?,
+            "--message-size" => {
+                settings.message_size = number(args.next(), "--message-size")? as usize
+            }
+            "--duration" => {
+                settings.duration = Duration::from_secs_f64(number(args.next(), "--duration")?)
+            }
+            "--join-timeout" => {
+                settings.join_timeout =
+                    Duration::from_secs_f64(number(args.next(), "--join-timeout")?)
+            }
+            _ => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+        }
+    }
+
+    let report = bench::run(&settings, || TcpStream::connect(addr.clone())).await;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use serde_json::{json, Value};
+
+// Timings in microseconds, summed up in milliseconds.
+#[derive(Default)]
+pub struct Samples(Vec<u64>);
+
+impl Samples {
+    pub fn push(&mut self, micros: u64) {
+        self.0.push(micros);
+    }
+
+    pub fn extend(&mut self, micros: Vec<u64>) {
+        self.0.extend(micros);
+    }
+
+    pub fn summary(mut self) -> Value {
+        self.0.sort_unstable();
+        let sorted = &self.0;
+        let mean = if sorted.is_empty() {
+            0.0
+        } else {
+            sorted.iter().sum::<u64>() as f64 / sorted.len() as f64
+        };
+
+        json!({
+            "count": sorted.len(),
+            "min": ms(percentile(sorted, 0.0)),
+            "mean": mean / 1000.0,
+            "p50": ms(percentile(sorted, 50.0)),
+            "p90": ms(percentile(sorted, 90.0)),
+            "p99": ms(percentile(sorted, 99.0)),
+            "p99.9": ms(percentile(sorted, 99.9)),
+            "max": ms(percentile(sorted, 100.0)),
+        })
+    }
+}
+
+// The nearest-rank percentile of `sorted`, or 0 if there's nothing in it.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    // the nudge down stops rounding error from making 99.9% of 1000 a hair
+    // over 999
+    let rank = (p / 100.0 * sorted.len() as f64 - 1e-9).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+fn ms(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted = (1..=1000).collect::<Vec<u64>>();
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&sorted, 50.0), 500);
+        assert_eq!(percentile(&sorted, 99.9), 999);
+        assert_eq!(percentile(&sorted, 100.0), 1000);
+        assert_eq!(percentile(&[], 50.0), 0);
+    }
+
+    #[test]
+    fn summaries_are_in_milliseconds() {
+        let mut samples = Samples::default();
+        samples.extend(vec![3000, 1000]);
+        samples.push(2000);
+
+        let summary = samples.summary();
+        assert_eq!(summary["count"], 3);
+        assert_eq!(summary["min"], 1.0);
+        assert_eq!(summary["mean"], 2.0);
+        assert_eq!(summary["p50"], 2.0);
+        assert_eq!(summary["max"], 3.0);
+
+        assert_eq!(Samples::default().summary()["p99"], 0.0);
+    }
+}
@@ -0,0 +1,63 @@
+// A small bot built on the client library. It greets people as they arrive
+// and answers a couple of commands said in the chat:
+//
+//   !echo <text>   repeats <text>
+//   !roll          rolls a six sided die
+//
+// Run it next to a server with `cargo run --example bot [addr] [name]`.
+
+use std::env;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio_stream::StreamExt;
+
+use rtalk_client::{Client, Event};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:3215".to_string());
+    let name = args.next().unwrap_or_else(|| "bot".to_string());
+
+    let mut client = Client::connect(addr.as_str()).await?;
+    client.join(&name).await?;
+
+    while let Some(event) = client.next().await {
+        let reply = match event? {
+            // names come with the user's address tacked on the end
+            Event::Joined(who) if !who.starts_with(&format!("{} ", name)) => {
+                Some(format!("Welcome, {}!", who))
+            }
+            Event::MessageReceived(_, _, msg) => respond(&msg),
+            Event::Disconnect(reason) => {
+                println!("disconnected: {}", reason);
+                break;
+            }
+            _ => None,
+        };
+
+        if let Some(reply) = reply {
+            client.send(&reply).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn respond(msg: &str) -> Option<String> {
+    if let Some(text) = msg.strip_prefix("!echo ") {
+        return Some(text.to_string());
+    }
+
+    match msg.trim() {
+        "!roll" => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            Some(format!("rolled a {}", nanos % 6 + 1))
+        }
+        _ => None,
+    }
+}
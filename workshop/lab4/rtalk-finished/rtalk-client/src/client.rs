@@ -0,0 +1,137 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use futures_util::sink::SinkExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Decoder, Framed};
+
+use rtalk_codec::{Event, EventCodec};
+
+use crate::transport::{Connection, QuicStream};
+
+// A connection to an rtalk server. Events from the server are read by using
+// the client as a `Stream`.
+pub struct Client<T = Connection> {
+    framed: Framed<T, EventCodec>,
+}
+
+impl Client<Connection> {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        Ok(Client::new(Box::new(socket)))
+    }
+
+    // Connects over QUIC instead of TCP; see `QuicStream::connect`.
+    pub async fn connect_quic(
+        addr: SocketAddr,
+        server_name: &str,
+        roots: &[u8],
+    ) -> io::Result<Self> {
+        let stream = QuicStream::connect(addr, server_name, roots).await?;
+        Ok(Client::new(Box::new(stream)))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Client<T> {
+    // Speaks the rtalk protocol over an already established connection.
+    pub fn new(transport: T) -> Self {
+        Client {
+            framed: EventCodec.framed(transport),
+        }
+    }
+
+    // Asks to join the chat as `name`. The server answers with `Joined` for
+    // everyone followed by a `UserList` for us.
+    pub async fn join(&mut self, name: &str) -> io::Result<()> {
+        self.send_event(Event::RequestJoin(name.to_string())).await
+    }
+
+    pub async fn send(&mut self, message: &str) -> io::Result<()> {
+        self.send_event(Event::MessageSend(message.to_string()))
+            .await
+    }
+
+    pub async fn leave(&mut self) -> io::Result<()> {
+        self.send_event(Event::Leave()).await
+    }
+
+    pub async fn send_event(&mut self, event: Event) -> io::Result<()> {
+        self.framed.send(event).await
+    }
+
+    // What the client is talking over.
+    pub fn transport(&self) -> &T {
+        self.framed.get_ref()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for Client<T> {
+    type Item = io::Result<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.framed).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::io::{duplex, DuplexStream};
+    use tokio_stream::StreamExt;
+
+    use rtalk_server::{testing, Server};
+
+    fn connect(server: &Server, port: u16) -> Client<DuplexStream> {
+        let (client, server_end) = duplex(64 * 1024);
+        server.connect(server_end, SocketAddr::from(([127, 0, 0, 1], port)));
+        Client::new(client)
+    }
+
+    async fn next_matching<F>(client: &mut Client<DuplexStream>, matches: F) -> Event
+    where
+        F: Fn(&Event) -> bool,
+    {
+        loop {
+            match client.next().await {
+                Some(Ok(event)) if matches(&event) => return event,
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_can_talk() {
+        let server = testing::server();
+
+        let mut alice = connect(&server, 1);
+        let mut bob = connect(&server, 2);
+        alice.join("alice").await.unwrap();
+        next_matching(&mut alice, |e| matches!(e, Event::UserList(_))).await;
+        bob.join("bob").await.unwrap();
+        next_matching(&mut bob, |e| matches!(e, Event::UserList(_))).await;
+
+        bob.send("hello").await.unwrap();
+        match next_matching(&mut alice, |e| matches!(e, Event::MessageReceived(..))).await {
+            Event::MessageReceived(_, who, msg) => {
+                assert!(who.starts_with("bob "));
+                assert_eq!(msg, "hello");
+            }
+            _ => unreachable!(),
+        }
+
+        bob.leave().await.unwrap();
+        next_matching(
+            &mut alice,
+            |e| matches!(e, Event::Left(who) if who.starts_with("bob ")),
+        )
+        .await;
+    }
+}
@@ -0,0 +1,796 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use rtalk_codec::{Event, Presence};
+
+use crate::direct::DirectCommand;
+use crate::state::{bare_name, ChatState};
+use crate::transfer::FileCommand;
+
+// What the frontend should do as a result of a line of input.
+#[derive(Debug)]
+pub enum Action {
+    Send(Event),
+    // file transfers need the filesystem and direct messages need our keys,
+    // both of which the frontend looks after
+    File(FileCommand),
+    Direct(DirectCommand),
+    Print(String),
+    Clear,
+    // switch to or from showing only the messages that mention us
+    Mentions,
+    // save the transcript of this session
+    Export(PathBuf),
+    Quit,
+}
+
+// What came of carrying out a file transfer or direct message: an event for
+// the server, a line to show the user, or both.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub event: Option<Event>,
+    pub note: Option<String>,
+}
+
+impl Outcome {
+    pub fn note(note: String) -> Self {
+        Outcome {
+            event: None,
+            note: Some(note),
+        }
+    }
+
+    pub fn send(event: Event, note: String) -> Self {
+        Outcome {
+            event: Some(event),
+            note: Some(note),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    Usage(&'static str),
+    UnterminatedQuote,
+    Invalid(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => {
+                write!(f, "unknown command /{}, try /help", name)
+            }
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::UnterminatedQuote => write!(f, "unterminated quote"),
+            CommandError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+// The arguments that follow a command name. `words` is the input split on
+// whitespace with double quotes grouping words together, while `rest` is the
+// raw text after the command name for commands that take free form text.
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub words: Vec<String>,
+    pub rest: String,
+}
+
+impl Args {
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.words.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Message(String),
+    Command(String, Args),
+}
+
+pub fn parse(line: &str) -> Result<Input, CommandError> {
+    // kept around from before we had slash commands
+    if line == "!q" {
+        return Ok(Input::Command("quit".to_string(), tokenize("")?));
+    }
+
+    let line = match line.strip_prefix('/') {
+        Some(line) => line,
+        None => return Ok(Input::Message(line.to_string())),
+    };
+
+    // "//" escapes a message that really starts with a slash
+    if line.starts_with('/') {
+        return Ok(Input::Message(line.to_string()));
+    }
+
+    let (name, rest) = match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].trim()),
+        None => (line, ""),
+    };
+
+    Ok(Input::Command(name.to_lowercase(), tokenize(rest)?))
+}
+
+fn tokenize(rest: &str) -> Result<Args, CommandError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut chars = rest.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+                in_word = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if quoted {
+        return Err(CommandError::UnterminatedQuote);
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(Args {
+        words,
+        rest: rest.to_string(),
+    })
+}
+
+pub struct Invocation<'a> {
+    pub registry: &'a Registry,
+    pub args: Args,
+}
+
+pub type Handler = fn(&Invocation) -> Result<Action, CommandError>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub min_args: usize,
+    // `None` means any number of arguments
+    pub max_args: Option<usize>,
+    pub handler: Handler,
+}
+
+impl Command {
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+}
+
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|cmd| cmd.matches(name))
+    }
+
+    // Parses a line of input and turns it into an action for the frontend.
+    // Anything that isn't a command is sent as a chat message.
+    pub fn execute(&self, line: &str) -> Result<Action, CommandError> {
+        let (name, args) = match parse(line)? {
+            Input::Message(msg) => return Ok(Action::Send(Event::MessageSend(msg))),
+            Input::Command(name, args) => (name, args),
+        };
+
+        let command = self
+            .find(&name)
+            .ok_or_else(|| CommandError::Unknown(name.clone()))?;
+
+        let too_many = command.max_args.is_some_and(|max| args.len() > max);
+        if args.len() < command.min_args || too_many {
+            return Err(CommandError::Usage(command.usage));
+        }
+
+        (command.handler)(&Invocation {
+            registry: self,
+            args,
+        })
+    }
+
+    // Returns the candidates for completing the last word of `line`. Command
+    // names are completed at the start of the line and member names
+    // everywhere else.
+    pub fn complete(&self, state: &ChatState, line: &str) -> Vec<String> {
+        let start = line.rfind(char::is_whitespace).map_or(0, |pos| pos + 1);
+        let (head, word) = line.split_at(start);
+
+        let mut candidates = if let (0, Some(prefix)) = (start, word.strip_prefix('/')) {
+            self.commands
+                .iter()
+                .flat_map(|cmd| std::iter::once(&cmd.name).chain(cmd.aliases.iter()))
+                .filter(|name| name.starts_with(prefix))
+                .map(|name| format!("/{} ", name))
+                .collect::<Vec<_>>()
+        } else if word.is_empty() {
+            Vec::new()
+        } else {
+            state
+                .members
+                .iter()
+                .map(|member| bare_name(member))
+                .filter(|name| name.starts_with(word))
+                .map(|name| format!("{}{} ", head, name))
+                .collect::<Vec<_>>()
+        };
+
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    pub fn help(&self) -> String {
+        self.commands
+            .iter()
+            .map(|cmd| format!("{:<24} {}", cmd.usage, cmd.help))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry::new();
+
+        registry.register(Command {
+            name: "quit",
+            aliases: &["q", "exit"],
+            usage: "/quit",
+            help: "leave the chat and exit",
+            min_args: 0,
+            max_args: Some(0),
+            handler: |_| Ok(Action::Quit),
+        });
+
+        registry.register(Command {
+            name: "help",
+            aliases: &["?"],
+            usage: "/help [command]",
+            help: "list commands or show help for one",
+            min_args: 0,
+            max_args: Some(1),
+            handler: help,
+        });
+
+        registry.register(Command {
+            name: "who",
+            aliases: &[],
+            usage: "/who",
+            help: "list who is connected",
+            min_args: 0,
+            max_args: Some(0),
+            handler: |_| Ok(Action::Send(Event::ListUsers())),
+        });
+
+        registry.register(Command {
+            name: "me",
+            aliases: &[],
+            usage: "/me <action>",
+            help: "describe what you are doing",
+            min_args: 1,
+            max_args: None,
+            handler: |inv| {
+                Ok(Action::Send(Event::MessageSend(format!(
+                    "* {}",
+                    inv.args.rest
+                ))))
+            },
+        });
+
+        registry.register(Command {
+            name: "nick",
+            aliases: &["name"],
+            usage: "/nick <name>",
+            help: "change your name",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| {
+                let name = inv.args.get(0).unwrap_or_default().to_string();
+                Ok(Action::Send(Event::RequestJoin(name)))
+            },
+        });
+
+        registry.register(Command {
+            name: "away",
+            aliases: &[],
+            usage: "/away [message]",
+            help: "mark yourself as away",
+            min_args: 0,
+            max_args: None,
+            handler: |inv| Ok(set_presence(Presence::Away, inv)),
+        });
+
+        registry.register(Command {
+            name: "busy",
+            aliases: &["dnd"],
+            usage: "/busy [message]",
+            help: "mark yourself as busy",
+            min_args: 0,
+            max_args: None,
+            handler: |inv| Ok(set_presence(Presence::Busy, inv)),
+        });
+
+        registry.register(Command {
+            name: "back",
+            aliases: &[],
+            usage: "/back [message]",
+            help: "mark yourself as online again",
+            min_args: 0,
+            max_args: None,
+            handler: |inv| Ok(set_presence(Presence::Online, inv)),
+        });
+
+        registry.register(Command {
+            name: "op",
+            aliases: &[],
+            usage: "/op <name>",
+            help: "make someone an operator (operators only)",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| Ok(Action::Send(Event::Op(target(inv)))),
+        });
+
+        registry.register(Command {
+            name: "kick",
+            aliases: &[],
+            usage: "/kick <name> [reason]",
+            help: "disconnect someone (operators only)",
+            min_args: 1,
+            max_args: None,
+            handler: |inv| Ok(Action::Send(Event::Kick(target(inv), reason(inv, 1)))),
+        });
+
+        registry.register(Command {
+            name: "ban",
+            aliases: &[],
+            usage: "/ban <name|ip> [duration] [reason]",
+            help: "ban a name or address, for good if no duration is given (operators only)",
+            min_args: 1,
+            max_args: None,
+            handler: |inv| {
+                // the duration is optional so a reason can follow the target
+                // directly
+                let (secs, reason) = match inv.args.get(1).map(parse_duration) {
+                    Some(Ok(secs)) => (secs, reason(inv, 2)),
+                    _ => (0, reason(inv, 1)),
+                };
+                Ok(Action::Send(Event::Ban(target(inv), secs, reason)))
+            },
+        });
+
+        registry.register(Command {
+            name: "unban",
+            aliases: &[],
+            usage: "/unban <name|ip>",
+            help: "lift a ban (operators only)",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| Ok(Action::Send(Event::Unban(target(inv)))),
+        });
+
+        registry.register(Command {
+            name: "mute",
+            aliases: &[],
+            usage: "/mute <name> [duration]",
+            help: "stop someone from talking, for 5 minutes by default (operators only)",
+            min_args: 1,
+            max_args: Some(2),
+            handler: |inv| {
+                let secs = match inv.args.get(1) {
+                    Some(duration) => parse_duration(duration)?,
+                    None => DEFAULT_MUTE_SECS,
+                };
+                Ok(Action::Send(Event::Mute(target(inv), secs)))
+            },
+        });
+
+        registry.register(Command {
+            name: "unmute",
+            aliases: &[],
+            usage: "/unmute <name>",
+            help: "let someone talk again (operators only)",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| Ok(Action::Send(Event::Mute(target(inv), 0))),
+        });
+
+        registry.register(Command {
+            name: "edit",
+            aliases: &[],
+            usage: "/edit <message id> <text>",
+            help: "change something you said",
+            min_args: 2,
+            max_args: None,
+            handler: |inv| Ok(Action::Send(Event::Edit(message_id(inv)?, free_text(inv)))),
+        });
+
+        registry.register(Command {
+            name: "delete",
+            aliases: &["del"],
+            usage: "/delete <message id>",
+            help: "take back something you said",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| Ok(Action::Send(Event::Delete(message_id(inv)?))),
+        });
+
+        registry.register(Command {
+            name: "react",
+            aliases: &[],
+            usage: "/react <message id> <emoji>",
+            help: "react to a message, or take your reaction back",
+            min_args: 2,
+            max_args: Some(2),
+            handler: |inv| {
+                let emoji = inv.args.get(1).unwrap_or_default().to_string();
+                Ok(Action::Send(Event::React(message_id(inv)?, emoji)))
+            },
+        });
+
+        registry.register(Command {
+            name: "msg",
+            aliases: &["dm"],
+            usage: "/msg <name> <text>",
+            help: "send someone a message only they can read",
+            min_args: 2,
+            max_args: None,
+            handler: |inv| {
+                Ok(Action::Direct(DirectCommand::Send(
+                    target(inv),
+                    free_text(inv),
+                )))
+            },
+        });
+
+        registry.register(Command {
+            name: "fingerprint",
+            aliases: &["fp"],
+            usage: "/fingerprint [name]",
+            help: "show the key fingerprint for you or someone else, to check with them",
+            min_args: 0,
+            max_args: Some(1),
+            handler: |inv| {
+                let name = inv.args.get(0).map(str::to_string);
+                Ok(Action::Direct(DirectCommand::Fingerprint(name)))
+            },
+        });
+
+        registry.register(Command {
+            name: "send",
+            aliases: &[],
+            usage: "/send <name> <path>",
+            help: "offer someone a file",
+            min_args: 2,
+            max_args: Some(2),
+            handler: |inv| {
+                let path = PathBuf::from(inv.args.get(1).unwrap_or_default());
+                Ok(Action::File(FileCommand::Send(target(inv), path)))
+            },
+        });
+
+        registry.register(Command {
+            name: "accept",
+            aliases: &[],
+            usage: "/accept <offer> [path]",
+            help: "save a file you were offered, carrying on from a partial download",
+            min_args: 1,
+            max_args: Some(2),
+            handler: |inv| {
+                let path = inv.args.get(1).map(PathBuf::from);
+                Ok(Action::File(FileCommand::Accept(offer_number(inv)?, path)))
+            },
+        });
+
+        registry.register(Command {
+            name: "reject",
+            aliases: &["decline"],
+            usage: "/reject <offer>",
+            help: "turn down a file you were offered",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| Ok(Action::File(FileCommand::Reject(offer_number(inv)?))),
+        });
+
+        registry.register(Command {
+            name: "clear",
+            aliases: &[],
+            usage: "/clear",
+            help: "clear the screen",
+            min_args: 0,
+            max_args: Some(0),
+            handler: |_| Ok(Action::Clear),
+        });
+
+        registry.register(Command {
+            name: "mentions",
+            aliases: &[],
+            usage: "/mentions",
+            help: "show only messages that mention you, or everything again",
+            min_args: 0,
+            max_args: Some(0),
+            handler: |_| Ok(Action::Mentions),
+        });
+
+        registry.register(Command {
+            name: "export",
+            aliases: &[],
+            usage: "/export <path>",
+            help: "save everything from this session, as HTML or JSON lines if the name ends in .html or .jsonl",
+            min_args: 1,
+            max_args: Some(1),
+            handler: |inv| {
+                let path = PathBuf::from(inv.args.get(0).unwrap_or_default());
+                Ok(Action::Export(path))
+            },
+        });
+
+        registry
+    }
+}
+
+const DEFAULT_MUTE_SECS: u64 = 5 * 60;
+
+// Parses durations like "90", "90s", "15m", "2h" or "1d" into seconds.
+pub fn parse_duration(text: &str) -> Result<u64, CommandError> {
+    let invalid =
+        || CommandError::Invalid(format!("{} is not a duration, try 30s, 5m or 2h", text));
+
+    let (digits, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => text.split_at(pos),
+        None => (text, "s"),
+    };
+
+    let value = digits.parse::<u64>().map_err(|_| invalid())?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    value
+        .checked_mul(scale)
+        .ok_or_else(|| CommandError::Invalid(format!("{} is too long", text)))
+}
+
+fn target(inv: &Invocation) -> String {
+    inv.args.get(0).unwrap_or_default().to_string()
+}
+
+// Everything from the `from`th word onwards, for free form reasons.
+fn reason(inv: &Invocation, from: usize) -> String {
+    inv.args.words.get(from..).unwrap_or_default().join(" ")
+}
+
+// Everything after the first word exactly as it was typed, for message text.
+fn free_text(inv: &Invocation) -> String {
+    let rest = &inv.args.rest;
+    match rest.find(char::is_whitespace) {
+        Some(pos) => rest[pos..].trim().to_string(),
+        None => String::new(),
+    }
+}
+
+// Messages are shown as "#12 bob:> ..." so accept the ID with or without the
+// hash.
+fn message_id(inv: &Invocation) -> Result<u64, CommandError> {
+    let text = inv.args.get(0).unwrap_or_default();
+    text.trim_start_matches('#')
+        .parse()
+        .map_err(|_| CommandError::Invalid(format!("{} is not a message ID", text)))
+}
+
+fn offer_number(inv: &Invocation) -> Result<u64, CommandError> {
+    let text = inv.args.get(0).unwrap_or_default();
+    text.trim_start_matches('#')
+        .parse()
+        .map_err(|_| CommandError::Invalid(format!("{} is not an offer number", text)))
+}
+
+fn set_presence(presence: Presence, inv: &Invocation) -> Action {
+    let status = if inv.args.rest.is_empty() {
+        None
+    } else {
+        Some(inv.args.rest.clone())
+    };
+
+    Action::Send(Event::SetPresence(presence, status))
+}
+
+fn help(inv: &Invocation) -> Result<Action, CommandError> {
+    match inv.args.get(0) {
+        None => Ok(Action::Print(inv.registry.help())),
+        Some(name) => {
+            let name = name.trim_start_matches('/');
+            let command = inv
+                .registry
+                .find(name)
+                .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+            Ok(Action::Print(format!(
+                "{} - {}",
+                command.usage, command.help
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(input: Input) -> Vec<String> {
+        match input {
+            Input::Command(_, args) => args.words,
+            Input::Message(msg) => panic!("expected a command, got message {}", msg),
+        }
+    }
+
+    #[test]
+    fn plain_text_is_a_message() {
+        assert_eq!(parse("hello").unwrap(), Input::Message("hello".to_string()));
+        assert_eq!(parse("//etc").unwrap(), Input::Message("/etc".to_string()));
+    }
+
+    #[test]
+    fn parses_command_and_arguments() {
+        match parse("/Nick  alice ").unwrap() {
+            Input::Command(name, args) => {
+                assert_eq!(name, "nick");
+                assert_eq!(args.words, vec!["alice"]);
+                assert_eq!(args.rest, "alice");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn groups_quoted_words() {
+        let kick = words(parse(r#"/kick bob "too \"loud\" today" x"#).unwrap());
+        assert_eq!(kick, vec!["bob", "too \"loud\" today", "x"]);
+
+        assert_eq!(words(parse(r#"/x """#).unwrap()), vec![""]);
+        assert_eq!(
+            parse(r#"/x "oops"#).unwrap_err(),
+            CommandError::UnterminatedQuote
+        );
+    }
+
+    #[test]
+    fn checks_command_arity() {
+        let registry = Registry::default();
+
+        assert_eq!(
+            registry.execute("/nick").unwrap_err(),
+            CommandError::Usage("/nick <name>")
+        );
+        assert_eq!(
+            registry.execute("/bogus").unwrap_err(),
+            CommandError::Unknown("bogus".to_string())
+        );
+        match registry.execute("/me waves hello").unwrap() {
+            Action::Send(Event::MessageSend(msg)) => assert_eq!(msg, "* waves hello"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("45"), Ok(45));
+        assert_eq!(parse_duration("45s"), Ok(45));
+        assert_eq!(parse_duration("15m"), Ok(900));
+        assert_eq!(parse_duration("2h"), Ok(7200));
+        assert_eq!(parse_duration("1d"), Ok(86400));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+
+    #[test]
+    fn ban_duration_is_optional() {
+        let registry = Registry::default();
+
+        match registry.execute("/ban mallory 1h being rude").unwrap() {
+            Action::Send(event) => assert_eq!(
+                event,
+                Event::Ban("mallory".to_string(), 3600, "being rude".to_string())
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        match registry.execute("/ban 10.0.0.1 spam").unwrap() {
+            Action::Send(event) => assert_eq!(
+                event,
+                Event::Ban("10.0.0.1".to_string(), 0, "spam".to_string())
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn edits_keep_the_text_as_typed() {
+        let registry = Registry::default();
+
+        match registry.execute("/edit #12 it's  \"fine\" now").unwrap() {
+            Action::Send(event) => {
+                assert_eq!(event, Event::Edit(12, "it's  \"fine\" now".to_string()))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(registry.execute("/delete twelve").is_err());
+    }
+
+    #[test]
+    fn file_paths_can_be_quoted() {
+        let registry = Registry::default();
+
+        match registry.execute(r#"/send bob "my notes.txt""#).unwrap() {
+            Action::File(command) => assert_eq!(
+                command,
+                FileCommand::Send("bob".to_string(), PathBuf::from("my notes.txt"))
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        match registry.execute("/accept 2").unwrap() {
+            Action::File(command) => assert_eq!(command, FileCommand::Accept(2, None)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn completes_commands_and_members() {
+        let registry = Registry::default();
+        let mut state = ChatState::new("me".to_string());
+        state.apply(&Event::Joined("alice [127.0.0.1:1000]".to_string()));
+        state.apply(&Event::Joined("albert [127.0.0.1:1001]".to_string()));
+
+        assert_eq!(registry.complete(&state, "/ni"), vec!["/nick "]);
+        assert_eq!(
+            registry.complete(&state, "hi al"),
+            vec!["hi albert ", "hi alice "]
+        );
+        assert!(registry.complete(&state, "hi ").is_empty());
+    }
+}
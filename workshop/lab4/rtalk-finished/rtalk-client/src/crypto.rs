@@ -0,0 +1,383 @@
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// Mixed into every message key so that they can't be mistaken for keys made
+// for anything else.
+const CONTEXT: &[u8] = b"rtalk direct message v1";
+
+// The same for the answer to the server's challenge for our key, which the
+// server works out from its side of the exchange.
+const PROOF_CONTEXT: &[u8] = b"rtalk key proof v1";
+
+// Our key pair for direct messages. The public half goes to the server's key
+// directory; the secret half never leaves this machine.
+//
+// Messages are encrypted with ChaCha20-Poly1305 under a key derived from the
+// X25519 shared secret of the two ends and which way the message is going.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    // Reads the key pair kept at `path`, making a new one there if there
+    // isn't one yet, so that our fingerprint stays the same between runs.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let bytes: [u8; KEY_LEN] = bytes
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| invalid("the key file is damaged"))?;
+                Ok(Identity::from_secret(StaticSecret::from(bytes)))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                options.open(path)?.write_all(&identity.secret.to_bytes())?;
+
+                Ok(identity)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Identity { secret, public }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.public.as_bytes())
+    }
+
+    // Answers the server's challenge, showing that we hold the secret half
+    // of the key we published without giving it away. Messages left for us
+    // while we were gone are only handed over once we have.
+    pub fn prove(&self, challenge: &[u8]) -> io::Result<Vec<u8>> {
+        let challenge = parse_key(challenge)?;
+        let shared = self.secret.diffie_hellman(&challenge);
+        if !shared.was_contributory() {
+            return Err(invalid("that challenge can't be answered"));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(PROOF_CONTEXT);
+        hasher.update(shared.as_bytes());
+        Ok(hasher.finalize().to_vec())
+    }
+
+    // Encrypts `text` for whoever holds the secret half of `recipient`,
+    // returning the nonce and the ciphertext.
+    pub fn seal(&self, recipient: &[u8], text: &str) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let recipient = parse_key(recipient)?;
+        let cipher = self.cipher(&recipient, &self.public, &recipient)?;
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, text.as_bytes())
+            .map_err(|_| invalid("the message could not be encrypted"))?;
+
+        Ok((nonce.to_vec(), ciphertext))
+    }
+
+    // Decrypts a message `sender` sealed for us. This fails if it was sealed
+    // for anyone else, by anyone else, or has been tampered with on the way.
+    pub fn open(&self, sender: &[u8], nonce: &[u8], ciphertext: &[u8]) -> io::Result<String> {
+        let sender = parse_key(sender)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(invalid("the nonce is the wrong length"));
+        }
+
+        let cipher = self.cipher(&sender, &sender, &self.public)?;
+        let text = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| invalid("the message could not be decrypted"))?;
+
+        String::from_utf8(text).map_err(|_| invalid("the message is not text"))
+    }
+
+    fn cipher(
+        &self,
+        other: &PublicKey,
+        from: &PublicKey,
+        to: &PublicKey,
+    ) -> io::Result<ChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(other);
+
+        // a key that makes the shared secret come out the same whatever our
+        // own key is would let anyone read the messages
+        if !shared.was_contributory() {
+            return Err(invalid("that public key can't be used"));
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(CONTEXT);
+        hasher.update(shared.as_bytes());
+        hasher.update(from.as_bytes());
+        hasher.update(to.as_bytes());
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&hasher.finalize())))
+    }
+}
+
+// A short digest of a public key for people to compare with each other, so
+// that they know the server hasn't handed them a key of its own.
+pub fn fingerprint(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_key(key: &[u8]) -> io::Result<PublicKey> {
+    let key: [u8; KEY_LEN] = key
+        .try_into()
+        .map_err(|_| invalid("that is not a public key"))?;
+    Ok(PublicKey::from(key))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+    use tokio_stream::StreamExt;
+
+    use rtalk_codec::Event;
+    use rtalk_server::{testing, Server};
+
+    use crate::Client;
+
+    #[test]
+    fn only_the_recipient_can_read_a_message() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let eve = Identity::generate();
+
+        let (nonce, ciphertext) = alice.seal(&bob.public_key(), "meet at noon").unwrap();
+        assert_eq!(
+            bob.open(&alice.public_key(), &nonce, &ciphertext).unwrap(),
+            "meet at noon"
+        );
+
+        assert!(eve.open(&alice.public_key(), &nonce, &ciphertext).is_err());
+        assert!(bob.open(&eve.public_key(), &nonce, &ciphertext).is_err());
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(bob.open(&alice.public_key(), &nonce, &tampered).is_err());
+
+        // a message can't be bounced back to its sender as if it came from
+        // the other side
+        assert!(alice.open(&bob.public_key(), &nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn keys_are_kept_between_runs() {
+        let path = std::env::temp_dir().join(format!("rtalk-key-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let first = Identity::load_or_create(&path).unwrap();
+        let second = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.fingerprint().len(), 39);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Keeps a copy of everything the server reads from a connection.
+    struct Tap {
+        inner: DuplexStream,
+        seen: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl AsyncRead for Tap {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let before = buf.filled().len();
+            let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+            let read = buf.filled()[before..].to_vec();
+            self.seen.lock().unwrap().extend_from_slice(&read);
+            poll
+        }
+    }
+
+    impl AsyncWrite for Tap {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    async fn connect(
+        server: &Server,
+        seen: &Arc<Mutex<Vec<u8>>>,
+        name: &str,
+        port: u16,
+    ) -> Client<DuplexStream> {
+        let (client, server_end) = duplex(64 * 1024);
+        let tap = Tap {
+            inner: server_end,
+            seen: seen.clone(),
+        };
+        server.connect(tap, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        let mut client = Client::new(client);
+        client.join(name).await.unwrap();
+        next_matching(&mut client, |e| matches!(e, Event::UserList(_))).await;
+        client
+    }
+
+    async fn next_matching<F>(client: &mut Client<DuplexStream>, matches: F) -> Event
+    where
+        F: Fn(&Event) -> bool,
+    {
+        loop {
+            match client.next().await {
+                Some(Ok(event)) if matches(&event) => return event,
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_are_proven_to_the_server() {
+        let server = testing::server();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let alice_keys = Identity::generate();
+        let mut alice = connect(&server, &seen, "alice", 1).await;
+        alice
+            .send_event(Event::PublishKey(alice_keys.public_key()))
+            .await
+            .unwrap();
+        let proof = match next_matching(&mut alice, |e| matches!(e, Event::KeyChallenge(_))).await {
+            Event::KeyChallenge(challenge) => alice_keys.prove(&challenge).unwrap(),
+            _ => unreachable!(),
+        };
+        alice.send_event(Event::KeyProof(proof)).await.unwrap();
+
+        // only a proven key is kept for when alice has gone
+        let mut bob = connect(&server, &seen, "bob", 2).await;
+        alice.leave().await.unwrap();
+        next_matching(&mut bob, |e| matches!(e, Event::Left(_))).await;
+        bob.send_event(Event::KeyRequest("alice".to_string()))
+            .await
+            .unwrap();
+        match next_matching(&mut bob, |e| {
+            matches!(e, Event::PublicKey(..) | Event::Warning(_))
+        })
+        .await
+        {
+            Event::PublicKey(_, key) => assert_eq!(key, alice_keys.public_key()),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn the_server_only_sees_ciphertext() {
+        const SECRET: &str = "the password is swordfish";
+
+        let server = testing::server();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let alice_keys = Identity::generate();
+        let bob_keys = Identity::generate();
+        let mut alice = connect(&server, &seen, "alice", 1).await;
+        let mut bob = connect(&server, &seen, "bob", 2).await;
+        alice
+            .send_event(Event::PublishKey(alice_keys.public_key()))
+            .await
+            .unwrap();
+        bob.send_event(Event::PublishKey(bob_keys.public_key()))
+            .await
+            .unwrap();
+        // bob's key is only there to ask for once the server has taken it
+        next_matching(&mut bob, |e| matches!(e, Event::KeyChallenge(_))).await;
+
+        alice
+            .send_event(Event::KeyRequest("bob".to_string()))
+            .await
+            .unwrap();
+        let key = match next_matching(&mut alice, |e| matches!(e, Event::PublicKey(..))).await {
+            Event::PublicKey(_, key) => key,
+            _ => unreachable!(),
+        };
+        assert_eq!(fingerprint(&key), bob_keys.fingerprint());
+
+        let (nonce, ciphertext) = alice_keys.seal(&key, SECRET).unwrap();
+        alice
+            .send_event(Event::EncryptedSend("bob".to_string(), nonce, ciphertext))
+            .await
+            .unwrap();
+        match next_matching(&mut bob, |e| matches!(e, Event::EncryptedReceived(..))).await {
+            Event::EncryptedReceived(_, key, nonce, ciphertext) => {
+                assert_eq!(bob_keys.open(&key, &nonce, &ciphertext).unwrap(), SECRET);
+            }
+            _ => unreachable!(),
+        }
+
+        // the tap does see what is sent in the clear, just not the secret
+        let seen = seen.lock().unwrap();
+        assert!(contains(&seen, b"alice"));
+        assert!(!contains(&seen, SECRET.as_bytes()));
+        assert!(!contains(&seen, &bob_keys.secret.to_bytes()));
+    }
+}
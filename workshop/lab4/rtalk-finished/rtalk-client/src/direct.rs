@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use rtalk_client::{fingerprint, Identity};
+use rtalk_codec::Event;
+
+use crate::commands::Outcome;
+use crate::state::bare_name;
+
+#[derive(Debug, PartialEq)]
+pub enum DirectCommand {
+    // recipient, text
+    Send(String, String),
+    // whose fingerprint to show; ours when no one is named
+    Fingerprint(Option<String>),
+}
+
+// Direct messages, which are encrypted and decrypted here so that the server
+// only ever passes on ciphertext.
+pub struct Direct {
+    identity: Identity,
+    // the public key we were given for each name. Once we have a key for
+    // someone, a different one turning up is worth warning about.
+    keys: HashMap<String, Vec<u8>>,
+    // messages waiting on a key from the directory, by recipient
+    waiting: HashMap<String, Vec<String>>,
+}
+
+impl Direct {
+    pub fn new(identity: Identity) -> Self {
+        Direct {
+            identity,
+            keys: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    // The event that puts our key in the server's directory.
+    pub fn publish(&self) -> Event {
+        Event::PublishKey(self.identity.public_key())
+    }
+
+    pub fn execute(&mut self, command: DirectCommand) -> Outcome {
+        match command {
+            DirectCommand::Send(to, text) => match self.keys.get(&to) {
+                Some(key) => self.seal(&to, key.clone(), &text),
+                None => {
+                    self.waiting.entry(to.clone()).or_default().push(text);
+                    Outcome::send(
+                        Event::KeyRequest(to.clone()),
+                        format!("KEY:> asking the server for {}'s key", to),
+                    )
+                }
+            },
+            DirectCommand::Fingerprint(None) => Outcome::note(format!(
+                "KEY:> your fingerprint is {}",
+                self.identity.fingerprint()
+            )),
+            DirectCommand::Fingerprint(Some(name)) => match self.keys.get(&name) {
+                Some(key) => Outcome::note(format!(
+                    "KEY:> {}'s fingerprint is {}",
+                    name,
+                    fingerprint(key)
+                )),
+                None => Outcome {
+                    event: Some(Event::KeyRequest(name)),
+                    note: None,
+                },
+            },
+        }
+    }
+
+    // Handles keys and messages from the server. A key arriving can set off
+    // any messages that were waiting for it.
+    pub fn on_event(&mut self, event: &Event) -> Vec<Outcome> {
+        match event {
+            Event::PublicKey(who, key) => {
+                let name = bare_name(who).to_string();
+                let mut outcomes = self.learn(&name, key).into_iter().collect::<Vec<_>>();
+                for text in self.waiting.remove(&name).unwrap_or_default() {
+                    outcomes.push(self.seal(&name, key.clone(), &text));
+                }
+                outcomes
+            }
+            Event::EncryptedReceived(who, key, nonce, ciphertext) => {
+                let name = bare_name(who).to_string();
+                let mut outcomes = self.learn(&name, key).into_iter().collect::<Vec<_>>();
+                let note = match self.identity.open(key, nonce, ciphertext) {
+                    Ok(text) => format!("DM from {}:> {}", name, text),
+                    Err(err) => format!("DM:> can't read a message from {}: {}", name, err),
+                };
+                outcomes.push(Outcome::note(note));
+                outcomes
+            }
+            // the server wants to know the key we published is really ours
+            Event::KeyChallenge(challenge) => match self.identity.prove(challenge) {
+                Ok(proof) => vec![Outcome {
+                    event: Some(Event::KeyProof(proof)),
+                    note: None,
+                }],
+                Err(err) => vec![Outcome::note(format!(
+                    "KEY:> can't prove our key to the server: {}",
+                    err
+                ))],
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn seal(&self, to: &str, key: Vec<u8>, text: &str) -> Outcome {
+        match self.identity.seal(&key, text) {
+            Ok((nonce, ciphertext)) => Outcome::send(
+                Event::EncryptedSend(to.to_string(), nonce, ciphertext),
+                format!("DM to {}:> {}", to, text),
+            ),
+            Err(err) => Outcome::note(format!("DM:> can't send to {}: {}", to, err)),
+        }
+    }
+
+    // Remembers someone's key, saying so if it is new to us or has changed.
+    fn learn(&mut self, name: &str, key: &[u8]) -> Option<Outcome> {
+        let note = match self.keys.get(name) {
+            Some(known) if known.as_slice() == key => return None,
+            Some(_) => format!(
+                "KEY:> WARNING: {}'s key has changed! The new fingerprint is {}. \
+                 Check it with them before trusting it.",
+                name,
+                fingerprint(key)
+            ),
+            None => format!(
+                "KEY:> {}'s fingerprint is {}, check it with them to be sure",
+                name,
+                fingerprint(key)
+            ),
+        };
+
+        self.keys.insert(name.to_string(), key.to_vec());
+        Some(Outcome::note(note))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(outcome: &Outcome) -> (Vec<u8>, Vec<u8>) {
+        match &outcome.event {
+            Some(Event::EncryptedSend(_, nonce, ciphertext)) => (nonce.clone(), ciphertext.clone()),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn messages_wait_for_a_key_and_changed_keys_are_flagged() {
+        let mut alice = Direct::new(Identity::generate());
+        let mut bob = Direct::new(Identity::generate());
+        let mallory = Identity::generate();
+        let bob_key = match bob.publish() {
+            Event::PublishKey(key) => key,
+            _ => unreachable!(),
+        };
+        let alice_key = match alice.publish() {
+            Event::PublishKey(key) => key,
+            _ => unreachable!(),
+        };
+
+        let asked = alice.execute(DirectCommand::Send("bob".to_string(), "psst".to_string()));
+        assert_eq!(asked.event, Some(Event::KeyRequest("bob".to_string())));
+
+        let outcomes = alice.on_event(&Event::PublicKey("bob [127.0.0.1:2]".to_string(), bob_key));
+        assert!(outcomes[0].note.as_ref().unwrap().contains("fingerprint"));
+        let (nonce, ciphertext) = sent(&outcomes[1]);
+
+        let received = bob.on_event(&Event::EncryptedReceived(
+            "alice [127.0.0.1:1]".to_string(),
+            alice_key,
+            nonce.clone(),
+            ciphertext.clone(),
+        ));
+        assert_eq!(
+            received.last().unwrap().note.as_deref(),
+            Some("DM from alice:> psst")
+        );
+
+        // someone else's key turning up under alice's name is a red flag,
+        // and what they send can't be read as if alice wrote it anyway
+        let received = bob.on_event(&Event::EncryptedReceived(
+            "alice [127.0.0.1:1]".to_string(),
+            mallory.public_key(),
+            nonce,
+            ciphertext,
+        ));
+        assert!(received[0].note.as_ref().unwrap().contains("has changed"));
+        assert!(received[1].note.as_ref().unwrap().contains("can't read"));
+    }
+}
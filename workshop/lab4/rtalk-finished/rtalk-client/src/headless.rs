@@ -0,0 +1,433 @@
+use std::error::Error;
+use std::future::Future;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use futures::{future, select};
+use futures_util::future::FutureExt;
+use regex::Regex;
+use tokio::io::AsyncRead;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use rtalk_client::{Client, Delivery, Outbox};
+use rtalk_codec::Event;
+
+use crate::state::bare_name;
+
+// Exit statuses, so that scripts can tell what went wrong. Anything else
+// that stops us, like bad arguments, exits with 1.
+pub const CONNECT_FAILED: i32 = 2;
+pub const JOIN_FAILED: i32 = 3;
+pub const CONNECTION_LOST: i32 = 4;
+pub const TIMED_OUT: i32 = 5;
+pub const NOT_DELIVERED: i32 = 6;
+const INPUT_FAILED: i32 = 1;
+
+// How often messages the server hasn't acknowledged are looked at again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// How many times to try connecting again after losing the connection, and
+// how long to wait before the first try. The wait doubles after each one.
+const RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+pub struct Options {
+    // how long to leave between sending one line and the next
+    pub pace: Duration,
+    // once everything has been sent, wait for this many messages from
+    // other people...
+    pub replies: Option<usize>,
+    // ...or for one that matches this, whichever comes first
+    pub expect: Option<Regex>,
+    // how long to wait for the server to let us join, and for replies
+    pub timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            pace: Duration::from_millis(500),
+            replies: None,
+            expect: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+// Connects with `connect` and joins as `user_name`, sends each line of
+// `input` as a message and waits for whatever `options` says to, writing
+// every event received to `out` as a line of JSON. A lost connection is made
+// again and whatever the server hadn't acknowledged is sent again over it.
+// Returns the status to exit with.
+pub async fn run<C, F, R, W>(
+    mut connect: C,
+    user_name: &str,
+    options: Options,
+    input: R,
+    out: &mut W,
+) -> i32
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<Client, Box<dyn Error>>>,
+    R: AsyncRead + Unpin,
+    W: Write,
+{
+    let mut outbox = Outbox::default();
+    let mut client = match connect().await {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("rtalk-client: couldn't connect: {}", err);
+            return CONNECT_FAILED;
+        }
+    };
+    if let Err(status) = join(&mut client, user_name, &options, &outbox, out).await {
+        return status;
+    }
+
+    let mut lines = FramedRead::new(input, LinesCodec::new());
+    let mut pacing = time::interval(options.pace);
+    let mut ticks = time::interval(RETRY_INTERVAL);
+    let mut reading = true;
+    let mut replies = 0;
+    let mut matched = false;
+    let mut undelivered = 0;
+    let mut deadline = None;
+
+    loop {
+        if !reading && outbox.is_empty() {
+            let done = match (options.replies, &options.expect) {
+                (None, None) => true,
+                (wanted, _) => wanted.is_some_and(|wanted| replies >= wanted) || matched,
+            };
+            if done {
+                break;
+            }
+            deadline.get_or_insert_with(|| Instant::now() + options.timeout);
+        }
+
+        select! {
+            event = client.next().fuse() => {
+                let event = match event {
+                    Some(Ok(event)) => event,
+                    _ => {
+                        client = match reconnect(&mut connect, user_name, &options, &mut outbox, out).await {
+                            Ok(client) => client,
+                            Err(status) => return status,
+                        };
+                        continue;
+                    }
+                };
+                print_event(out, &event);
+
+                if let Some(update) = outbox.on_event(&event) {
+                    if let Delivery::Failed(reason) = update.delivery {
+                        eprintln!("rtalk-client: \"{}\" wasn't sent: {}", update.text, reason);
+                        undelivered += 1;
+                    }
+                }
+                match &event {
+                    Event::MessageReceived(_, who, text) if bare_name(who) != user_name => {
+                        replies += 1;
+                        matched |= options.expect.as_ref().is_some_and(|expect| expect.is_match(text));
+                    }
+                    Event::Disconnect(reason) => {
+                        eprintln!("rtalk-client: disconnected: {}", reason);
+                        return CONNECTION_LOST;
+                    }
+                    _ => {}
+                }
+            },
+            line = async {
+                if reading {
+                    pacing.tick().await;
+                    lines.next().await
+                } else {
+                    future::pending().await
+                }
+            }.fuse() => {
+                match line {
+                    Some(Ok(line)) if line.trim().is_empty() => {},
+                    // if this doesn't go out, the connection is gone and the
+                    // message goes again over the next one
+                    Some(Ok(line)) => {
+                        let (_, event) = outbox.send(line);
+                        let _ = client.send_event(event).await;
+                    },
+                    Some(Err(err)) => {
+                        eprintln!("rtalk-client: couldn't read input: {}", err);
+                        return INPUT_FAILED;
+                    },
+                    None => reading = false,
+                }
+            },
+            _ = ticks.tick().fuse() => {
+                let now = Instant::now();
+                let (resend, failed) = outbox.retry(now);
+                for update in failed {
+                    eprintln!("rtalk-client: \"{}\" wasn't sent: the server didn't answer", update.text);
+                    undelivered += 1;
+                }
+                for event in resend {
+                    let _ = client.send_event(event).await;
+                }
+
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    eprintln!("rtalk-client: gave up waiting for replies");
+                    let _ = client.leave().await;
+                    return TIMED_OUT;
+                }
+            },
+        }
+    }
+
+    let _ = client.leave().await;
+    if undelivered > 0 {
+        NOT_DELIVERED
+    } else {
+        0
+    }
+}
+
+// Asks to join and waits for the server to let us in, then says which
+// client we are.
+async fn join<W: Write>(
+    client: &mut Client,
+    user_name: &str,
+    options: &Options,
+    outbox: &Outbox,
+    out: &mut W,
+) -> Result<(), i32> {
+    let joined = time::timeout(options.timeout, async {
+        client
+            .join(user_name)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        while let Some(event) = client.next().await {
+            let event = event.map_err(|err| err.to_string())?;
+            print_event(out, &event);
+            match event {
+                Event::UserList(_) => {
+                    return client
+                        .send_event(outbox.hello())
+                        .await
+                        .map_err(|err| err.to_string())
+                }
+                Event::Disconnect(reason) => return Err(reason),
+                _ => {}
+            }
+        }
+        Err("the server hung up".to_string())
+    })
+    .await;
+
+    let reason = match joined {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(reason)) => reason,
+        Err(_) => "the server didn't answer".to_string(),
+    };
+    eprintln!("rtalk-client: couldn't join: {}", reason);
+    Err(JOIN_FAILED)
+}
+
+// Connects and joins again after losing the connection, and sends again
+// everything the server hadn't acknowledged.
+async fn reconnect<C, F, W>(
+    connect: &mut C,
+    user_name: &str,
+    options: &Options,
+    outbox: &mut Outbox,
+    out: &mut W,
+) -> Result<Client, i32>
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<Client, Box<dyn Error>>>,
+    W: Write,
+{
+    eprintln!("rtalk-client: lost the connection to the server");
+
+    let mut delay = RECONNECT_DELAY;
+    for _ in 0..RECONNECT_ATTEMPTS {
+        time::sleep(delay).await;
+        delay *= 2;
+
+        let mut client = match connect().await {
+            Ok(client) => client,
+            Err(err) => {
+                eprintln!("rtalk-client: couldn't connect: {}", err);
+                continue;
+            }
+        };
+        if join(&mut client, user_name, options, outbox, out)
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        for event in outbox.resend(Instant::now()) {
+            let _ = client.send_event(event).await;
+        }
+        return Ok(client);
+    }
+    Err(CONNECTION_LOST)
+}
+
+// Writes `event` out as JSON, the same way it would be sent over a WebSocket.
+fn print_event<W: Write>(out: &mut W, event: &Event) {
+    if let Ok(json) = serde_json::to_string(event) {
+        let _ = writeln!(out, "{}", json);
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use futures_util::sink::SinkExt;
+    use tokio::io::duplex;
+    use tokio_util::codec::Decoder;
+
+    use rtalk_codec::EventCodec;
+    use rtalk_server::testing::{self, server, TestClient};
+    use rtalk_server::Server;
+
+    type Connected = future::Ready<Result<Client, Box<dyn Error>>>;
+
+    fn connect(server: &Server) -> impl FnMut() -> Connected + '_ {
+        move || {
+            let (client, server_end) = duplex(64 * 1024);
+            server.connect(server_end, SocketAddr::from(([127, 0, 0, 1], 1)));
+            future::ready(Ok(Client::new(Box::new(client))))
+        }
+    }
+
+    fn options() -> Options {
+        Options {
+            pace: Duration::from_millis(10),
+            timeout: Duration::from_secs(2),
+            ..Options::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_lines_and_waits_for_an_answer() {
+        let server = server();
+        let mut bob = TestClient::join(&server, "bob").await;
+        tokio::spawn(async move {
+            bob.recv_until(|e| matches!(e, Event::MessageReceived(_, _, text) if text == "ping"))
+                .await;
+            bob.send(Event::MessageSend("pong 42".to_string())).await;
+        });
+
+        let options = Options {
+            expect: Some(Regex::new(r"^pong \d+$").unwrap()),
+            ..options()
+        };
+        let mut out = Vec::new();
+        let input = &b"\nping\n"[..];
+        let status = run(connect(&server), "alice", options, input, &mut out).await;
+        assert_eq!(status, 0);
+
+        let events = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Event>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e, Event::MessageAck(1, _))));
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Event::MessageReceived(_, _, text) if text == "pong 42")));
+    }
+
+    #[tokio::test]
+    async fn exit_status_says_what_went_wrong() {
+        let mut config = testing::config();
+        config.rate_limit.max_connections_per_ip = 0;
+        let full = testing::server_with(config);
+        let status = run(
+            connect(&full),
+            "alice",
+            options(),
+            &b""[..],
+            &mut Vec::new(),
+        )
+        .await;
+        assert_eq!(status, JOIN_FAILED);
+
+        // nobody is there to answer
+        let server = server();
+        let options = Options {
+            replies: Some(1),
+            ..options()
+        };
+        let status = run(
+            connect(&server),
+            "alice",
+            options,
+            &b"anyone?"[..],
+            &mut Vec::new(),
+        )
+        .await;
+        assert_eq!(status, TIMED_OUT);
+    }
+
+    #[tokio::test]
+    async fn messages_go_again_after_the_connection_drops() {
+        let server = server();
+        let mut bob = TestClient::join(&server, "bob").await;
+        tokio::spawn(async move {
+            bob.recv_until(|e| matches!(e, Event::MessageReceived(_, _, text) if text == "ping"))
+                .await;
+            bob.send(Event::MessageSend("pong".to_string())).await;
+        });
+
+        // the first connection lets alice in, then drops in the middle of
+        // sending the message
+        let mut real = connect(&server);
+        let mut first = true;
+        let connect = move || {
+            if !std::mem::take(&mut first) {
+                return real();
+            }
+            let (client, far_end) = duplex(64 * 1024);
+            tokio::spawn(async move {
+                let mut far_end = EventCodec.framed(far_end);
+                while let Some(Ok(event)) = far_end.next().await {
+                    match event {
+                        Event::RequestJoin(_) => {
+                            far_end.send(Event::UserList(Vec::new())).await.unwrap()
+                        }
+                        Event::TrackedSend(..) => break,
+                        _ => {}
+                    }
+                }
+            });
+            future::ready(Ok(Client::new(Box::new(client))))
+        };
+
+        let options = Options {
+            expect: Some(Regex::new("^pong$").unwrap()),
+            ..options()
+        };
+        let mut out = Vec::new();
+        let status = run(connect, "alice", options, &b"ping\n"[..], &mut out).await;
+        assert_eq!(status, 0);
+
+        let events = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Event>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(events.iter().any(|e| matches!(e, Event::MessageAck(1, _))));
+        let pings = events
+            .iter()
+            .filter(|e| matches!(e, Event::MessageReceived(_, _, text) if text == "ping"))
+            .count();
+        assert_eq!(pings, 1);
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::{self, Write};
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use rtalk_codec::Event;
+
+use crate::state::{bare_name, ChatMessage, ChatState};
+
+// Decides which messages should catch the user's eye: the ones the server
+// says mention them, and any with one of their highlight keywords in. Those
+// are flagged in the chat state and the user is alerted to them.
+#[derive(Default)]
+pub struct Highlights {
+    // lowercased, as they match whatever the case
+    keywords: Vec<String>,
+    // a shell command to run for each one, e.g. to show a desktop
+    // notification; it gets the sender and text in $RTALK_SENDER and
+    // $RTALK_MESSAGE
+    hook: Option<String>,
+}
+
+impl Highlights {
+    pub fn new(keywords: Vec<String>, hook: Option<String>) -> Self {
+        Highlights {
+            keywords: keywords.iter().map(|word| word.to_lowercase()).collect(),
+            hook,
+        }
+    }
+
+    // Flags the message `event` draws attention to, if there is one, and
+    // returns its ID the first time.
+    pub fn on_event(&self, state: &mut ChatState, event: &Event) -> Option<u64> {
+        let id = match event {
+            Event::Mentioned(id, _, _) => *id,
+            Event::MessageReceived(id, who, text)
+                if bare_name(who) != state.user_name && self.matches(text) =>
+            {
+                *id
+            }
+            _ => return None,
+        };
+
+        if !state.flag(id) {
+            return None;
+        }
+        if let Some(message) = state.message(id) {
+            self.alert(message);
+        }
+        Some(id)
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keywords
+            .iter()
+            .any(|word| text.contains(word.as_str()))
+    }
+
+    // Rings the terminal bell and runs the hook, without waiting for it.
+    fn alert(&self, message: &ChatMessage) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x07");
+        let _ = stdout.flush();
+
+        // a hook that won't start shouldn't get in the way of the chat
+        if let Some(hook) = &self.hook {
+            let _ = Command::new("sh")
+                .arg("-c")
+                .arg(hook)
+                .env("RTALK_SENDER", bare_name(&message.who))
+                .env("RTALK_MESSAGE", message.text.as_deref().unwrap_or_default())
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(id: u64, who: &str, text: &str) -> Event {
+        Event::MessageReceived(id, who.to_string(), text.to_string())
+    }
+
+    #[test]
+    fn keywords_and_mentions_are_flagged_once() {
+        let highlights = Highlights::new(vec!["Deploy".to_string()], None);
+        let mut state = ChatState::new("alice".to_string());
+
+        let event = received(1, "bob [127.0.0.1:2]", "deploying now");
+        state.apply(&event);
+        assert_eq!(highlights.on_event(&mut state, &event), Some(1));
+
+        // our own messages never count
+        let event = received(2, "alice [127.0.0.1:1]", "deploy done");
+        state.apply(&event);
+        assert_eq!(highlights.on_event(&mut state, &event), None);
+
+        let event = received(3, "bob [127.0.0.1:2]", "@alice thanks");
+        state.apply(&event);
+        assert_eq!(highlights.on_event(&mut state, &event), None);
+        let mention = Event::Mentioned(
+            3,
+            "bob [127.0.0.1:2]".to_string(),
+            "@alice thanks".to_string(),
+        );
+        state.apply(&mention);
+        assert_eq!(highlights.on_event(&mut state, &mention), Some(3));
+        assert_eq!(highlights.on_event(&mut state, &mention), None);
+
+        let ids = state.mentions().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3]);
+    }
+}
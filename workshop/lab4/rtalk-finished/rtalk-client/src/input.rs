@@ -0,0 +1,166 @@
+// A single line text editor with history, used by the full screen UI for
+// the input line.
+#[derive(Default)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    stash: String,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor::default()
+    }
+
+    pub fn text(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.buffer.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.cursor += 1;
+        }
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.buffer.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.set_text("");
+    }
+
+    pub fn history_prev(&mut self) {
+        let pos = match self.history_pos {
+            Some(0) => return,
+            Some(pos) => pos - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                // remember what was being typed so that we can restore it
+                self.stash = self.text();
+                self.history.len() - 1
+            }
+        };
+
+        self.history_pos = Some(pos);
+        let line = self.history[pos].clone();
+        self.set_text(&line);
+    }
+
+    pub fn history_next(&mut self) {
+        match self.history_pos {
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.history_pos = Some(pos + 1);
+                let line = self.history[pos + 1].clone();
+                self.set_text(&line);
+            }
+            Some(_) => {
+                self.history_pos = None;
+                let stash = std::mem::take(&mut self.stash);
+                self.set_text(&stash);
+            }
+            None => {}
+        }
+    }
+
+    // Takes the current line out of the editor and records it in the history.
+    pub fn submit(&mut self) -> String {
+        let line = self.text();
+        if !line.is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        self.history_pos = None;
+        self.stash.clear();
+        self.clear();
+
+        line
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.buffer = text.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_str(editor: &mut LineEditor, s: &str) {
+        for c in s.chars() {
+            editor.insert(c);
+        }
+    }
+
+    #[test]
+    fn edits_in_the_middle_of_the_line() {
+        let mut editor = LineEditor::new();
+        type_str(&mut editor, "hllo");
+        editor.home();
+        editor.right();
+        editor.insert('e');
+        assert_eq!(editor.text(), "hello");
+        assert_eq!(editor.cursor(), 2);
+
+        editor.end();
+        editor.backspace();
+        editor.home();
+        editor.delete();
+        assert_eq!(editor.text(), "ell");
+    }
+
+    #[test]
+    fn walks_history_and_restores_current_line() {
+        let mut editor = LineEditor::new();
+        type_str(&mut editor, "one");
+        editor.submit();
+        type_str(&mut editor, "two");
+        editor.submit();
+        type_str(&mut editor, "thr");
+
+        editor.history_prev();
+        assert_eq!(editor.text(), "two");
+        editor.history_prev();
+        assert_eq!(editor.text(), "one");
+        editor.history_prev();
+        assert_eq!(editor.text(), "one");
+
+        editor.history_next();
+        assert_eq!(editor.text(), "two");
+        editor.history_next();
+        assert_eq!(editor.text(), "thr");
+    }
+}
@@ -0,0 +1,10 @@
+mod client;
+mod crypto;
+mod outbox;
+mod transport;
+
+pub use client::Client;
+pub use crypto::{fingerprint, Identity};
+pub use outbox::{Delivery, Outbox, Update};
+pub use rtalk_codec::{Event, Presence, UserInfo};
+pub use transport::{Connection, QuicStream, Transport};
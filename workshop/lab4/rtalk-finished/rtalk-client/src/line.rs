@@ -0,0 +1,130 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use futures::{future, select};
+use futures_util::future::FutureExt;
+use tokio::io;
+use tokio::{task, time};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use rtalk_client::{Client, Outbox};
+
+use crate::commands::{Action, Outcome, Registry};
+use crate::direct::Direct;
+use crate::highlight::Highlights;
+use crate::state::ChatState;
+use crate::transcript::Transcript;
+use crate::transfer::Transfers;
+use crate::{render_event, render_update};
+
+// How often messages the server hasn't acknowledged are looked at again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub async fn run(
+    mut client: Client,
+    user_name: String,
+    mut direct: Direct,
+    highlights: Highlights,
+    mut transcript: Transcript,
+) -> Result<(), Box<dyn Error>> {
+    let mut stdin = FramedRead::new(io::stdin(), LinesCodec::new());
+    let registry = Registry::default();
+    let mut state = ChatState::new(user_name);
+    let mut transfers = Transfers::default();
+    let mut outbox = Outbox::default();
+    let mut ticks = time::interval(RETRY_INTERVAL);
+
+    loop {
+        let uploading = transfers.uploading();
+
+        select! {
+            event = client.next().fuse() => {
+                if let Some(Ok(event)) = event {
+                    if let Err(err) = transcript.record(&event) {
+                        println!("ERROR:> transcript stopped: {}", err);
+                    }
+                    state.apply(&event);
+                    highlights.on_event(&mut state, &event);
+                    if let Some(line) = render_event(&event) {
+                        println!("{}", line);
+                    }
+                    if let Some(line) = outbox.on_event(&event).as_ref().and_then(render_update) {
+                        println!("{}", line);
+                    }
+                    carry_out(&mut client, transfers.on_event(&event)).await;
+                    for outcome in direct.on_event(&event) {
+                        carry_out(&mut client, outcome).await;
+                    }
+                }
+            },
+            msg = stdin.next().fuse() => {
+                if let Some(Ok(msg)) = msg {
+                    match registry.execute(&msg) {
+                        Ok(Action::Send(event)) => {
+                            let event = outbox.track(event);
+                            client.send_event(event).await.expect("Message send failed.");
+                        },
+                        Ok(Action::File(command)) => {
+                            carry_out(&mut client, transfers.execute(command)).await;
+                        },
+                        Ok(Action::Direct(command)) => {
+                            carry_out(&mut client, direct.execute(command)).await;
+                        },
+                        Ok(Action::Print(text)) => println!("{}", text),
+                        Ok(Action::Clear) => {},
+                        Ok(Action::Export(path)) => match transcript.export(&path) {
+                            Ok(count) => println!("EXPORTED:> {} events to {}", count, path.display()),
+                            Err(err) => println!("ERROR:> couldn't export to {}: {}", path.display(), err),
+                        },
+                        Ok(Action::Mentions) => {
+                            for (id, message) in state.mentions() {
+                                println!("{}", message.render(id));
+                            }
+                        },
+                        Ok(Action::Quit) => {
+                            client.leave().await.expect("Message send failed.");
+                            break;
+                        },
+                        Err(err) => println!("ERROR:> {}", err),
+                    }
+                }
+            },
+
+            // files go out a chunk at a time in between everything else
+            _ = async {
+                if uploading {
+                    task::yield_now().await
+                } else {
+                    future::pending().await
+                }
+            }.fuse() => {
+                carry_out(&mut client, transfers.next_chunk()).await;
+            },
+            _ = ticks.tick().fuse() => {
+                let (resend, failed) = outbox.retry(Instant::now());
+                for update in failed.iter().filter_map(render_update) {
+                    println!("{}", update);
+                }
+                for event in resend {
+                    client.send_event(event).await.expect("Message send failed.");
+                }
+            },
+            complete => break,
+        }
+    }
+
+    Ok(())
+}
+
+async fn carry_out(client: &mut Client, outcome: Outcome) {
+    if let Some(note) = outcome.note {
+        println!("{}", note);
+    }
+    if let Some(event) = outcome.event {
+        client
+            .send_event(event)
+            .await
+            .expect("Message send failed.");
+    }
+}
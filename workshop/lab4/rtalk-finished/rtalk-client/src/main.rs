@@ -1,73 +1,253 @@
 #![recursion_limit = "256"]
 
+mod commands;
+mod direct;
+mod headless;
+mod highlight;
+mod input;
+mod line;
+mod state;
+mod transcript;
+mod transfer;
+mod tui;
+
 use std::env;
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use crossterm::tty::IsTty;
+use regex::Regex;
+use tokio::io::AsyncRead;
+
+use direct::Direct;
+use headless::Options;
+use highlight::Highlights;
+use rtalk_client::{Client, Delivery, Event, Identity, Presence, Update};
+use state::describe_reactions;
+use transcript::{Format, LogFile, Transcript};
+
+const SERVER_ADDR: &str = "127.0.0.1:3215";
+const SERVER_QUIC_ADDR: &str = "127.0.0.1:3219";
+
+// The name the server's certificate has to be for when connecting over QUIC.
+const SERVER_NAME: &str = "localhost";
+
+const USAGE: &str = "\
+Usage: rtalk-client [options] <user_name>
+  --plain                     line based output even on a terminal
+  --quic <cert.pem>           connect over QUIC, trusting this certificate
+  --highlight <word>          flag messages with this in, like mentions
+  --notify <command>          run this for every flagged message
+  --log <path>                keep a transcript of everything received
+  --log-format <format>       text, json or html, instead of by extension
+  --headless                  send lines from stdin and print events as JSON
+  --input <path>              read the lines from a file instead
+  --pace <ms>                 time between lines
+  --replies <n>               then wait for this many replies...
+  --expect <regex>            ...or for one that matches
+  --timeout <secs>            how long to wait to join and for replies";
+
+// Turns an event received from the server into a line of text for display.
+pub(crate) fn render_event(event: &Event) -> Option<String> {
+    match event {
+        Event::Joined(who) => Some(format!("JOINED:> {}", who)),
+        Event::Left(who) => Some(format!("LEFT:> {}", who)),
+        Event::MessageReceived(id, who, msg) => Some(format!("#{} {}:> {}", id, who, msg)),
+        Event::Mentioned(id, who, msg) => Some(format!("MENTIONED:> #{} {}:> {}", id, who, msg)),
+        Event::MessageEdited(id, text) => Some(format!("EDITED:> #{} {}", id, text)),
+        Event::MessageDeleted(id) => Some(format!("DELETED:> #{}", id)),
+        Event::Reactions(id, reactions) => Some(format!(
+            "REACTIONS:> #{} {}",
+            id,
+            describe_reactions(reactions)
+        )),
+        Event::UserList(users) => {
+            let mut lines = vec![format!("USERS:> {} online", users.len())];
+            for user in users {
+                let mut line = format!(
+                    "  {} (connected {}, idle {})",
+                    user.name,
+                    format_duration(user.connected_secs),
+                    format_duration(user.idle_secs)
+                );
+                if user.presence != Presence::Online || user.status.is_some() {
+                    line.push_str(&format!(
+                        " {}",
+                        describe_presence(user.presence, &user.status)
+                    ));
+                }
+                lines.push(line);
+            }
+            Some(lines.join("\n"))
+        }
+        Event::PresenceChanged(who, presence, status) => Some(format!(
+            "PRESENCE:> {} is {}",
+            who,
+            describe_presence(*presence, status)
+        )),
+        Event::Warning(msg) => Some(format!("WARNING:> {}", msg)),
+        Event::Disconnect(reason) => Some(format!("DISCONNECTED:> {}", reason)),
+        Event::Notice(msg) => Some(format!("NOTICE:> {}", msg)),
+        Event::Queued(id, to) => Some(format!(
+            "QUEUED:> {} isn't here, message {} will be handed over when they're back",
+            to, id
+        )),
+        Event::Delivered(id, to) => Some(format!("DELIVERED:> message {} reached {}", id, to)),
+        _ => None,
+    }
+}
+
+// Says what became of a message we sent, if it's worth mentioning. Messages
+// that got through show up like everyone else's.
+pub(crate) fn render_update(update: &Update) -> Option<String> {
+    match &update.delivery {
+        Delivery::Failed(reason) => Some(format!(
+            "FAILED:> \"{}\" wasn't sent: {}",
+            update.text, reason
+        )),
+        _ => None,
+    }
+}
+
+pub(crate) fn describe_presence(presence: Presence, status: &Option<String>) -> String {
+    let state = match presence {
+        Presence::Online => "online",
+        Presence::Away => "away",
+        Presence::Busy => "busy",
+    };
 
-use futures::select;
-use futures_util::{future::FutureExt, sink::SinkExt};
-use tokio::io;
-use tokio::net::TcpStream;
-use tokio::stream::StreamExt;
-use tokio_util::codec::{Decoder, FramedRead, LinesCodec};
+    match status {
+        Some(status) => format!("{} ({})", state, status),
+        None => state.to_string(),
+    }
+}
 
-use rtalk_codec::{Event, EventCodec};
+// Where our key pair for direct messages is kept: $RTALK_KEY_FILE, or
+// ~/.rtalk-key by default.
+fn key_path() -> PathBuf {
+    if let Some(path) = env::var_os("RTALK_KEY_FILE") {
+        return PathBuf::from(path);
+    }
+
+    let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".rtalk-key")
+}
+
+// The value that goes with a numeric option.
+fn number(value: Option<String>, option: &str) -> Result<u64, Box<dyn Error>> {
+    value
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("{} needs a number", option).into())
+}
+
+async fn connect(quic_roots: Option<String>) -> Result<Client, Box<dyn Error>> {
+    let client = match quic_roots {
+        Some(path) => {
+            let roots = fs::read(path)?;
+            Client::connect_quic(SERVER_QUIC_ADDR.parse()?, SERVER_NAME, &roots).await?
+        }
+        None => Client::connect(SERVER_ADDR).await?,
+    };
+    Ok(client)
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() < 2 {
-        println!("Usage: rtalk-client <user_name>");
-        return Ok(());
+    let mut plain = false;
+    let mut quic_roots = None;
+    let mut keywords = Vec::new();
+    let mut hook = None;
+    let mut log_path = None;
+    let mut log_format = None;
+    let mut headless = false;
+    let mut input = None;
+    let mut options = Options::default();
+    let mut user_name = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--plain" => plain = true,
+            // the PEM file with the certificate to trust for QUIC
+            "--quic" => quic_roots = args.next(),
+            // messages with this in are flagged like mentions
+            "--highlight" => keywords.extend(args.next()),
+            // a shell command to run for each of those and for mentions
+            "--notify" => hook = args.next(),
+            // keep a transcript of everything we receive
+            "--log" => log_path = args.next().map(PathBuf::from),
+            // "text", "json" or "html"; by default it goes by the extension
+            "--log-format" => log_format = args.next(),
+            "--headless" => headless = true,
+            "--input" => input = args.next().map(PathBuf::from),
+            "--pace" => options.pace = Duration::from_millis(number(args.next(), "--pace")?),
+            "--replies" => options.replies = Some(number(args.next(), "--replies")? as usize),
+            "--expect" => options.expect = Some(Regex::new(&args.next().unwrap_or_default())?),
+            "--timeout" => options.timeout = Duration::from_secs(number(args.next(), "--timeout")?),
+            _ => user_name = Some(arg),
+        }
     }
-    let user_name = args[1].clone();
-
-    let socket = TcpStream::connect("127.0.0.1:3215").await?;
-    let codec = EventCodec;
-    let mut framed = codec.framed(socket);
-
-    // send a join message
-    framed
-        .send(Event::RequestJoin(user_name))
-        .await
-        .expect("Message send failed.");
-
-    let mut stdin = FramedRead::new(io::stdin(), LinesCodec::new());
-
-    loop {
-        select! {
-            event = framed.next().fuse() => {
-                if let Some(Ok(event)) = event {
-                    // dbg!(&event);
-                    match event {
-                        Event::Joined(who) => {
-                            println!("JOINED:> {}", who);
-                        },
-                        Event::Left(who) => {
-                            println!("LEFT:> {}", who);
-                        },
-                        Event::MessageReceived(who, msg) => {
-                            println!("{}:> {}", who, msg);
-                        },
-                        _ => unreachable!(),
-                    }
-                }
-            },
-            msg = stdin.next().fuse() => {
-                if let Some(Ok(msg)) = msg {
-                    match msg.as_ref() {
-                        "!q" => {
-                            framed.send(Event::Leave()).await.expect("Message send failed.");
-                            break;
-                        },
-                        _ => framed.send(Event::MessageSend(msg)).await.expect("Message send failed."),
-                    }
-                }
-            },
-            complete => break,
+
+    let user_name = match user_name {
+        Some(user_name) => user_name,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
         }
+    };
+
+    // scripts get told what went wrong by the exit status, and events come
+    // out as JSON lines
+    if headless {
+        let input: Box<dyn AsyncRead + Unpin + Send> = match input {
+            Some(path) => Box::new(tokio::fs::File::open(path).await?),
+            None => Box::new(tokio::io::stdin()),
+        };
+        let connect = || connect(quic_roots.clone());
+        let status = headless::run(connect, &user_name, options, input, &mut io::stdout()).await;
+        process::exit(status);
     }
 
-    Ok(())
+    // the full screen UI only makes sense when we're attached to a terminal;
+    // fall back to the line based mode when input or output is piped
+    let use_tui = !plain && io::stdin().is_tty() && io::stdout().is_tty();
+
+    let log = match log_path {
+        Some(path) => {
+            let format = match log_format {
+                Some(name) => Format::from_name(&name)
+                    .ok_or_else(|| format!("unknown log format {}", name))?,
+                None => Format::for_path(&path),
+            };
+            Some(LogFile::open(path, format)?)
+        }
+        None => None,
+    };
+    let transcript = Transcript::new(log);
+
+    let direct = Direct::new(Identity::load_or_create(&key_path())?);
+
+    let mut client = connect(quic_roots).await?;
+    client.join(&user_name).await?;
+    client.send_event(direct.publish()).await?;
+
+    let highlights = Highlights::new(keywords, hook);
+    if use_tui {
+        tui::run(client, user_name, direct, highlights, transcript).await
+    } else {
+        line::run(client, user_name, direct, highlights, transcript).await
+    }
 }
@@ -0,0 +1,223 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+use rtalk_codec::Event;
+
+// How long to wait for the server to acknowledge a message before sending it
+// again. The wait doubles with every attempt.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A message is given up on once it has been sent this many times.
+const MAX_ATTEMPTS: u32 = 4;
+
+// How far a message we sent has got.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delivery {
+    // waiting to hear back from the server
+    Sent,
+    // the server sent it out with this message ID
+    Delivered(u64),
+    // turned down by the server, or it never answered
+    Failed(String),
+}
+
+// News of one of our messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+    pub seq: u64,
+    pub text: String,
+    pub delivery: Delivery,
+}
+
+struct Pending {
+    text: String,
+    attempts: u32,
+    due: Instant,
+}
+
+// Chat messages we've sent that the server hasn't acknowledged yet. They are
+// sent again until it does, so every message gets through at least once; the
+// server makes sure that nobody sees one twice. That holds across connections
+// too, as long as the outbox is kept and its client ID is given on each.
+pub struct Outbox {
+    client_id: u64,
+    next_seq: u64,
+    pending: BTreeMap<u64, Pending>,
+    timeout: Duration,
+    max_attempts: u32,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox::new(ACK_TIMEOUT, MAX_ATTEMPTS)
+    }
+}
+
+impl Outbox {
+    pub fn new(timeout: Duration, max_attempts: u32) -> Self {
+        Outbox {
+            client_id: OsRng.next_u64(),
+            next_seq: 0,
+            pending: BTreeMap::new(),
+            timeout,
+            max_attempts,
+        }
+    }
+
+    // Tells the server who we are, so it recognises messages sent again over
+    // a new connection. It goes first on every connection.
+    pub fn hello(&self) -> Event {
+        Event::ClientId(self.client_id)
+    }
+
+    // Numbers a new message, returning its sequence number and the event
+    // that sends it.
+    pub fn send(&mut self, text: String) -> (u64, Event) {
+        self.next_seq += 1;
+        let seq = self.next_seq;
+
+        self.pending.insert(
+            seq,
+            Pending {
+                text: text.clone(),
+                attempts: 1,
+                due: Instant::now() + self.timeout,
+            },
+        );
+        (seq, Event::TrackedSend(seq, text))
+    }
+
+    // Chat messages are numbered and tracked; anything else goes through as
+    // it is.
+    pub fn track(&mut self, event: Event) -> Event {
+        match event {
+            Event::MessageSend(text) => self.send(text).1,
+            event => event,
+        }
+    }
+
+    // Picks out the server's answers to our messages. Answers to messages
+    // that were sent more than once only count the first time.
+    pub fn on_event(&mut self, event: &Event) -> Option<Update> {
+        let (seq, delivery) = match event {
+            Event::MessageAck(seq, message_id) => (*seq, Delivery::Delivered(*message_id)),
+            Event::MessageFailed(seq, reason) => (*seq, Delivery::Failed(reason.clone())),
+            _ => return None,
+        };
+
+        let pending = self.pending.remove(&seq)?;
+        Some(Update {
+            seq,
+            text: pending.text,
+            delivery,
+        })
+    }
+
+    // Sends again whatever has waited too long for an answer, and gives up
+    // on messages that have been tried too often. Returns the events to send
+    // and the messages that failed.
+    pub fn retry(&mut self, now: Instant) -> (Vec<Event>, Vec<Update>) {
+        let mut resend = Vec::new();
+        let mut given_up = Vec::new();
+
+        for (seq, pending) in self.pending.iter_mut() {
+            if pending.due > now {
+                continue;
+            }
+
+            if pending.attempts >= self.max_attempts {
+                given_up.push(*seq);
+                continue;
+            }
+
+            pending.due = now + self.timeout * 2u32.pow(pending.attempts);
+            pending.attempts += 1;
+            resend.push(Event::TrackedSend(*seq, pending.text.clone()));
+        }
+
+        let failed = given_up
+            .into_iter()
+            .filter_map(|seq| {
+                let pending = self.pending.remove(&seq)?;
+                Some(Update {
+                    seq,
+                    text: pending.text,
+                    delivery: Delivery::Failed("The server didn't answer.".to_string()),
+                })
+            })
+            .collect();
+        (resend, failed)
+    }
+
+    // Everything still waiting for an answer, to send again over a new
+    // connection. Whatever went out over the old one may never have arrived.
+    pub fn resend(&mut self, now: Instant) -> Vec<Event> {
+        let due = now + self.timeout;
+        self.pending
+            .iter_mut()
+            .map(|(seq, pending)| {
+                pending.due = due;
+                Event::TrackedSend(*seq, pending.text.clone())
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resent(outbox: &mut Outbox, now: Instant) -> Vec<Event> {
+        outbox.retry(now).0
+    }
+
+    #[test]
+    fn messages_are_sent_again_until_acknowledged() {
+        let mut outbox = Outbox::new(Duration::from_secs(1), 4);
+        let start = Instant::now();
+        let (seq, event) = outbox.send("hello".to_string());
+        assert_eq!(event, Event::TrackedSend(seq, "hello".to_string()));
+
+        assert!(resent(&mut outbox, start).is_empty());
+        assert_eq!(
+            resent(&mut outbox, start + Duration::from_secs(2)),
+            vec![event]
+        );
+
+        // the wait has doubled
+        assert!(resent(&mut outbox, start + Duration::from_secs(3)).is_empty());
+
+        let ack = Event::MessageAck(seq, 9);
+        let update = outbox.on_event(&ack).unwrap();
+        assert_eq!(update.delivery, Delivery::Delivered(9));
+        assert_eq!(update.text, "hello");
+        assert_eq!(outbox.on_event(&ack), None);
+        assert!(resent(&mut outbox, start + Duration::from_secs(60)).is_empty());
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn messages_fail_when_the_server_never_answers() {
+        let mut outbox = Outbox::new(Duration::from_secs(1), 2);
+        let start = Instant::now();
+        let (seq, _) = outbox.send("hello?".to_string());
+
+        let (resend, failed) = outbox.retry(start + Duration::from_secs(2));
+        assert_eq!(resend.len(), 1);
+        assert!(failed.is_empty());
+
+        let (resend, failed) = outbox.retry(start + Duration::from_secs(10));
+        assert!(resend.is_empty());
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].seq, seq);
+        assert!(matches!(failed[0].delivery, Delivery::Failed(_)));
+        assert!(outbox.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use rtalk_codec::{Event, Presence, Reaction};
+
+// How long someone is shown as typing after we last heard they were.
+const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+// Messages are remembered so that they can be shown again when they change,
+// up to this many of the most recent ones.
+const MESSAGE_HISTORY: usize = 1000;
+
+pub struct ChatMessage {
+    pub who: String,
+    // `None` once the message has been deleted
+    pub text: Option<String>,
+    pub edited: bool,
+    pub reactions: Vec<Reaction>,
+    // one of ours that the server has acknowledged
+    pub delivered: bool,
+    // it mentions us or has one of our highlight keywords in
+    pub flagged: bool,
+}
+
+impl ChatMessage {
+    pub fn render(&self, id: u64) -> String {
+        let mut line = match &self.text {
+            Some(text) => format!("#{} {}:> {}", id, self.who, text),
+            None => format!("#{} {}:> (deleted)", id, self.who),
+        };
+
+        if self.edited && self.text.is_some() {
+            line.push_str(" (edited)");
+        }
+        if self.delivered {
+            line.push_str(" \u{2713}");
+        }
+        if !self.reactions.is_empty() {
+            line.push_str(&format!(" [{}]", describe_reactions(&self.reactions)));
+        }
+
+        line
+    }
+}
+
+pub fn describe_reactions(reactions: &[Reaction]) -> String {
+    reactions
+        .iter()
+        .map(|reaction| format!("{} {}", reaction.emoji, reaction.count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// What the client knows about the chat so far, built up from the events the
+// server sends us.
+pub struct ChatState {
+    pub user_name: String,
+    pub members: BTreeSet<String>,
+    // only members that aren't plainly online are tracked here
+    presence: HashMap<String, (Presence, Option<String>)>,
+    typing: HashMap<String, Instant>,
+    messages: BTreeMap<u64, ChatMessage>,
+}
+
+impl ChatState {
+    pub fn new(user_name: String) -> Self {
+        ChatState {
+            user_name,
+            members: BTreeSet::new(),
+            presence: HashMap::new(),
+            typing: HashMap::new(),
+            messages: BTreeMap::new(),
+        }
+    }
+
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            Event::Joined(who) => {
+                self.members.insert(who.clone());
+            }
+            Event::Left(who) => {
+                self.members.remove(who);
+                self.presence.remove(who);
+                self.typing.remove(who);
+            }
+            Event::UserList(users) => {
+                self.members = users.iter().map(|user| user.name.clone()).collect();
+                self.presence = HashMap::new();
+                for user in users {
+                    self.set_presence(&user.name, user.presence, &user.status);
+                }
+            }
+            Event::PresenceChanged(who, presence, status) => {
+                self.set_presence(who, *presence, status);
+            }
+            Event::UserTyping(who) => {
+                self.typing.insert(who.clone(), Instant::now());
+            }
+            Event::MessageReceived(id, who, text) => {
+                self.typing.remove(who);
+                self.messages.insert(
+                    *id,
+                    ChatMessage {
+                        who: who.clone(),
+                        text: Some(text.clone()),
+                        edited: false,
+                        reactions: Vec::new(),
+                        delivered: false,
+                        flagged: false,
+                    },
+                );
+
+                if self.messages.len() > MESSAGE_HISTORY {
+                    let oldest = *self.messages.keys().next().unwrap();
+                    self.messages.remove(&oldest);
+                }
+            }
+            // mentions from while we were away come without the message
+            Event::Mentioned(id, who, text) if !self.messages.contains_key(id) => {
+                self.apply(&Event::MessageReceived(*id, who.clone(), text.clone()));
+            }
+            Event::MessageEdited(id, text) => {
+                if let Some(message) = self.messages.get_mut(id) {
+                    message.text = Some(text.clone());
+                    message.edited = true;
+                }
+            }
+            Event::MessageDeleted(id) => {
+                if let Some(message) = self.messages.get_mut(id) {
+                    message.text = None;
+                    message.reactions.clear();
+                }
+            }
+            Event::Reactions(id, reactions) => {
+                if let Some(message) = self.messages.get_mut(id) {
+                    message.reactions = reactions.clone();
+                }
+            }
+            Event::MessageAck(_, id) => {
+                if let Some(message) = self.messages.get_mut(id) {
+                    message.delivered = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn message(&self, id: u64) -> Option<&ChatMessage> {
+        self.messages.get(&id)
+    }
+
+    // Flags message `id`, returning whether it wasn't already.
+    pub fn flag(&mut self, id: u64) -> bool {
+        match self.messages.get_mut(&id) {
+            Some(message) if !message.flagged => {
+                message.flagged = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // The messages that have been flagged, oldest first.
+    pub fn mentions(&self) -> impl Iterator<Item = (u64, &ChatMessage)> {
+        self.messages
+            .iter()
+            .filter(|(_, message)| message.flagged)
+            .map(|(id, message)| (*id, message))
+    }
+
+    pub fn presence_of(&self, member: &str) -> Option<&(Presence, Option<String>)> {
+        self.presence.get(member)
+    }
+
+    // Members we've recently been told are typing.
+    pub fn typing(&self) -> Vec<&str> {
+        let mut typing = self
+            .typing
+            .iter()
+            .filter(|(_, at)| at.elapsed() < TYPING_EXPIRY)
+            .map(|(who, _)| bare_name(who))
+            .collect::<Vec<_>>();
+        typing.sort();
+        typing
+    }
+
+    fn set_presence(&mut self, who: &str, presence: Presence, status: &Option<String>) {
+        if presence == Presence::Online && status.is_none() {
+            self.presence.remove(who);
+        } else {
+            self.presence
+                .insert(who.to_string(), (presence, status.clone()));
+        }
+    }
+}
+
+// The server decorates names with the peer address, i.e. "name [ip:port]";
+// this strips that back down to the name the user picked.
+pub fn bare_name(member: &str) -> &str {
+    match member.rfind(" [") {
+        Some(pos) => &member[..pos],
+        None => member,
+    }
+}
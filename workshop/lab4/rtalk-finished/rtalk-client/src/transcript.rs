@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rtalk_codec::Event;
+
+use crate::render_event;
+
+// A log file is moved aside once it would grow past this...
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+// ...and this many old ones are kept, as <path>.1 (the newest) to <path>.N.
+const OLD_LOGS: usize = 5;
+
+// Events kept for `/export`. Anything older is only in the log file, if
+// there is one.
+const SESSION_LIMIT: usize = 100_000;
+
+const HTML_HEADER: &str = "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+<title>rtalk transcript</title>\n\
+<style>body { font-family: monospace; } time { color: gray; }</style>\n\
+</head>\n<body>\n";
+
+const HTML_FOOTER: &str = "</body>\n</html>\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    // one JSON object per line, with the event as the server sent it
+    Json,
+    Html,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    // Guesses the format from a file name, falling back to plain text.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") | Some("jsonl") => Format::Json,
+            Some("html") | Some("htm") => Format::Html,
+            _ => Format::Text,
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            Format::Html => HTML_HEADER,
+            _ => "",
+        }
+    }
+
+    fn footer(self) -> &'static str {
+        match self {
+            Format::Html => HTML_FOOTER,
+            _ => "",
+        }
+    }
+
+    // How an event shows up in a transcript, ending in a newline. Text and
+    // HTML transcripts read like the chat, so events the user never sees are
+    // left out of them.
+    fn entry(self, at: SystemTime, event: &Event) -> Option<String> {
+        let at = timestamp(at);
+        match self {
+            Format::Text => Some(format!("[{}] {}\n", at, render_event(event)?)),
+            Format::Json => {
+                let entry = serde_json::json!({ "at": at, "event": event });
+                Some(format!("{}\n", entry))
+            }
+            Format::Html => Some(format!(
+                "<p><time>{}</time> {}</p>\n",
+                at,
+                escape(&render_event(event)?).replace('\n', "<br>\n")
+            )),
+        }
+    }
+}
+
+// A transcript file that is rotated as it grows.
+pub struct LogFile {
+    path: PathBuf,
+    format: Format,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    // Carries on with the file at `path` if there is one.
+    pub fn open(path: PathBuf, format: Format) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut size = file.metadata()?.len();
+        if size == 0 {
+            file.write_all(format.header().as_bytes())?;
+            size = format.header().len() as u64;
+        }
+
+        Ok(LogFile {
+            path,
+            format,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, entry: &str) -> io::Result<()> {
+        let header = self.format.header().len() as u64;
+        if self.size > header && self.size + entry.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+
+        self.file.write_all(entry.as_bytes())?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..OLD_LOGS).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                fs::rename(from, numbered(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+
+        *self = LogFile::open(self.path.clone(), self.format)?;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Everything received from the server, fed in by the frontend alongside its
+// own handling of each event. It is kept for `/export` and written to the
+// log file as it comes in, if there is one.
+pub struct Transcript {
+    session: VecDeque<(SystemTime, Event)>,
+    log: Option<LogFile>,
+}
+
+impl Transcript {
+    pub fn new(log: Option<LogFile>) -> Self {
+        Transcript {
+            session: VecDeque::new(),
+            log,
+        }
+    }
+
+    // Takes note of an event. If the log file can't be written to, logging
+    // stops and the error is returned, once.
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        // what files contain isn't part of the conversation, only that they
+        // were offered and how that went
+        if let Event::FileChunk(..) = event {
+            return Ok(());
+        }
+
+        let now = SystemTime::now();
+        self.session.push_back((now, event.clone()));
+        if self.session.len() > SESSION_LIMIT {
+            self.session.pop_front();
+        }
+
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let result = match log.format.entry(now, event) {
+            Some(entry) => log.write(&entry),
+            None => Ok(()),
+        };
+        if result.is_err() {
+            self.log = None;
+        }
+        result
+    }
+
+    // Writes out this session so far in the format `path` calls for,
+    // returning how many events it had.
+    pub fn export(&self, path: &Path) -> io::Result<usize> {
+        let format = Format::for_path(path);
+        let mut out = String::from(format.header());
+        for (at, event) in &self.session {
+            if let Some(entry) = format.entry(*at, event) {
+                out.push_str(&entry);
+            }
+        }
+        out.push_str(format.footer());
+
+        fs::write(path, out)?;
+        Ok(self.session.len())
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// UTC, as in "2024-03-01T09:30:00Z".
+fn timestamp(at: SystemTime) -> String {
+    let secs = at
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// Turns days since 1970-01-01 into a date, after Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn temp_path(test: &str, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rtalk-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    fn said(text: &str) -> Event {
+        Event::MessageReceived(1, "bob".to_string(), text.to_string())
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(timestamp(at), "2023-11-14T22:13:20Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn sessions_export_in_every_format() {
+        let mut transcript = Transcript::new(None);
+        transcript.record(&said("<b>hi</b>")).unwrap();
+        transcript.record(&Event::MessageAck(1, 1)).unwrap();
+
+        let html = temp_path("export", "chat.html");
+        assert_eq!(transcript.export(&html).unwrap(), 2);
+        let html = fs::read_to_string(html).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("bob:&gt; &lt;b&gt;hi&lt;/b&gt;"));
+        assert!(html.ends_with("</html>\n"));
+
+        let json = temp_path("export", "chat.jsonl");
+        transcript.export(&json).unwrap();
+        let lines = fs::read_to_string(json).unwrap();
+        let events = lines
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["event"]["MessageAck"][1], 1);
+
+        // the text transcript only has what the user saw
+        let text = temp_path("export", "chat.txt");
+        transcript.export(&text).unwrap();
+        let text = fs::read_to_string(text).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.ends_with("#1 bob:> <b>hi</b>\n"));
+    }
+
+    #[test]
+    fn log_files_are_rotated() {
+        let path = temp_path("rotate", "chat.log");
+        let log = LogFile::open(path.clone(), Format::Text).unwrap();
+        let mut transcript = Transcript::new(Some(log));
+
+        let line = "x".repeat(1024 * 1024);
+        for _ in 0..25 {
+            transcript.record(&said(&line)).unwrap();
+        }
+
+        assert!(fs::metadata(&path).unwrap().len() <= MAX_LOG_SIZE);
+        assert!(numbered(&path, 1).exists());
+        assert!(numbered(&path, 2).exists());
+        assert!(!numbered(&path, 3).exists());
+    }
+}
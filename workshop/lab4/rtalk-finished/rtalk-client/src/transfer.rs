@@ -0,0 +1,529 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use rtalk_codec::Event;
+
+use crate::commands::Outcome;
+
+// Files go out a chunk at a time in between everything else we send, so
+// chunks are kept small enough that chat doesn't notice.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum FileCommand {
+    // recipient, file to send
+    Send(String, PathBuf),
+    // offer number, where to save it
+    Accept(u64, Option<PathBuf>),
+    Reject(u64),
+}
+
+struct Outgoing {
+    to: String,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    // opened once the recipient accepts
+    file: Option<File>,
+    offset: u64,
+}
+
+struct Incoming {
+    transfer_id: u64,
+    from: String,
+    name: String,
+    size: u64,
+    sha256: String,
+    // where the file is going once it has been accepted. Until it is all
+    // here and checked it is written next to there with a ".part" extension,
+    // which is what lets a later offer of the same file pick up from there.
+    save: Option<(PathBuf, File)>,
+    received: u64,
+}
+
+// Files we are sending and being sent.
+#[derive(Default)]
+pub struct Transfers {
+    // by transfer ID
+    outgoing: BTreeMap<u64, Outgoing>,
+    // by a small number that is easier to type than the transfer ID
+    incoming: BTreeMap<u64, Incoming>,
+    counter: u64,
+}
+
+impl Transfers {
+    pub fn execute(&mut self, command: FileCommand) -> Outcome {
+        match command {
+            FileCommand::Send(to, path) => match self.offer(&to, &path) {
+                Ok(event) => Outcome::send(
+                    event,
+                    format!("FILE:> offering {} to {}", path.display(), to),
+                ),
+                Err(err) => Outcome::note(format!("FILE:> can't send {}: {}", path.display(), err)),
+            },
+            FileCommand::Accept(number, path) => match self.accept(number, path) {
+                Ok((event, path)) => {
+                    Outcome::send(event, format!("FILE:> saving to {}", path.display()))
+                }
+                Err(err) => Outcome::note(format!("FILE:> can't accept #{}: {}", number, err)),
+            },
+            FileCommand::Reject(number) => match self.incoming.remove(&number) {
+                Some(incoming) => Outcome::send(
+                    Event::FileReject(incoming.transfer_id, "declined".to_string()),
+                    format!("FILE:> turned down {}", incoming.name),
+                ),
+                None => Outcome::note(format!("FILE:> there is no offer #{}", number)),
+            },
+        }
+    }
+
+    // Whether there is anything to send with `next_chunk`.
+    pub fn uploading(&self) -> bool {
+        self.outgoing
+            .values()
+            .any(|outgoing| outgoing.file.is_some())
+    }
+
+    // Reads the next chunk of a file that is being sent, or finishes it off
+    // once it has all gone.
+    pub fn next_chunk(&mut self) -> Outcome {
+        let transfer_id = match self
+            .outgoing
+            .iter()
+            .find(|(_, outgoing)| outgoing.file.is_some())
+        {
+            Some((transfer_id, _)) => *transfer_id,
+            None => return Outcome::default(),
+        };
+
+        let outgoing = self.outgoing.get_mut(&transfer_id).unwrap();
+        if outgoing.offset >= outgoing.size {
+            let outgoing = self.outgoing.remove(&transfer_id).unwrap();
+            return Outcome::send(
+                Event::FileComplete(transfer_id),
+                format!("FILE:> sent {} to {}", outgoing.name, outgoing.to),
+            );
+        }
+
+        let len = (outgoing.size - outgoing.offset).min(CHUNK_SIZE as u64) as usize;
+        let mut data = vec![0; len];
+        let read = outgoing.file.as_mut().unwrap().read(&mut data);
+        match read {
+            Ok(n) if n > 0 => {
+                data.truncate(n);
+                let offset = outgoing.offset;
+                outgoing.offset += n as u64;
+                Outcome {
+                    event: Some(Event::FileChunk(transfer_id, offset, data)),
+                    note: None,
+                }
+            }
+
+            // the file got shorter since we offered it
+            _ => {
+                let outgoing = self.outgoing.remove(&transfer_id).unwrap();
+                Outcome::send(
+                    Event::FileReject(transfer_id, "the file could not be read".to_string()),
+                    format!("FILE:> could not read {}", outgoing.path.display()),
+                )
+            }
+        }
+    }
+
+    // Keeps track of transfers as events about them arrive.
+    pub fn on_event(&mut self, event: &Event) -> Outcome {
+        match event {
+            Event::FileOffered(transfer_id, from, name, size, sha256) => {
+                self.offered(*transfer_id, from, name, *size, sha256)
+            }
+            Event::FileAccept(transfer_id, offset) => self.accepted(*transfer_id, *offset),
+            Event::FileReject(transfer_id, reason) => {
+                if let Some(outgoing) = self.outgoing.remove(transfer_id) {
+                    return Outcome::note(format!(
+                        "FILE:> {} did not get {}: {}",
+                        outgoing.to, outgoing.name, reason
+                    ));
+                }
+                match self.take_incoming(*transfer_id) {
+                    Some(incoming) => Outcome::note(format!(
+                        "FILE:> {} from {} was called off: {}",
+                        incoming.name, incoming.from, reason
+                    )),
+                    None => Outcome::default(),
+                }
+            }
+            Event::FileChunk(transfer_id, offset, data) => {
+                match self.write_chunk(*transfer_id, *offset, data) {
+                    Ok(()) => Outcome::default(),
+                    Err(err) => self.give_up(*transfer_id, err),
+                }
+            }
+            Event::FileComplete(transfer_id) => self.completed(*transfer_id),
+            _ => Outcome::default(),
+        }
+    }
+
+    fn offer(&mut self, to: &str, path: &Path) -> io::Result<Event> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::other("not a file"));
+        }
+        let size = metadata.len();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::other("no file name"))?;
+        let sha256 = sha256_of(path)?;
+
+        // the server turns down IDs that are already taken, so a random one
+        // is all it takes
+        let transfer_id = RandomState::new().build_hasher().finish();
+        self.outgoing.insert(
+            transfer_id,
+            Outgoing {
+                to: to.to_string(),
+                name: name.clone(),
+                path: path.to_path_buf(),
+                size,
+                file: None,
+                offset: 0,
+            },
+        );
+
+        Ok(Event::FileOffer(
+            transfer_id,
+            to.to_string(),
+            name,
+            size,
+            sha256,
+        ))
+    }
+
+    fn offered(
+        &mut self,
+        transfer_id: u64,
+        from: &str,
+        name: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Outcome {
+        // the name is used to save the file, so it mustn't lead anywhere else
+        if Path::new(name).file_name() != Some(OsStr::new(name)) {
+            return Outcome::send(
+                Event::FileReject(transfer_id, "that is not a file name".to_string()),
+                format!("FILE:> turned down a file from {} with a bad name", from),
+            );
+        }
+
+        self.counter += 1;
+        let number = self.counter;
+        self.incoming.insert(
+            number,
+            Incoming {
+                transfer_id,
+                from: from.to_string(),
+                name: name.to_string(),
+                size,
+                sha256: sha256.to_lowercase(),
+                save: None,
+                received: 0,
+            },
+        );
+
+        Outcome::note(format!(
+            "FILE:> {} offers {} ({}), /accept {} [path] to save it or /reject {}",
+            from,
+            name,
+            describe_size(size),
+            number,
+            number
+        ))
+    }
+
+    fn accept(&mut self, number: u64, path: Option<PathBuf>) -> io::Result<(Event, PathBuf)> {
+        let incoming = match self.incoming.get_mut(&number) {
+            Some(incoming) if incoming.save.is_none() => incoming,
+            Some(_) => return Err(io::Error::other("already accepted")),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no such offer")),
+        };
+
+        let mut path = path.unwrap_or_else(|| PathBuf::from(&incoming.name));
+        if path.is_dir() {
+            path.push(&incoming.name);
+        }
+
+        // carry on from whatever an earlier attempt left behind
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(part_path(&path))?;
+        let mut received = file.metadata()?.len();
+        if received > incoming.size {
+            file.set_len(0)?;
+            received = 0;
+        }
+
+        incoming.save = Some((path.clone(), file));
+        incoming.received = received;
+        Ok((Event::FileAccept(incoming.transfer_id, received), path))
+    }
+
+    fn accepted(&mut self, transfer_id: u64, offset: u64) -> Outcome {
+        let outgoing = match self.outgoing.get_mut(&transfer_id) {
+            Some(outgoing) => outgoing,
+            None => return Outcome::default(),
+        };
+
+        let opened = File::open(&outgoing.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(file)
+        });
+        match opened {
+            Ok(file) => {
+                outgoing.file = Some(file);
+                outgoing.offset = offset;
+                let resuming = if offset > 0 {
+                    format!(", picking up from {}", describe_size(offset))
+                } else {
+                    String::new()
+                };
+                Outcome::note(format!(
+                    "FILE:> {} accepted {}{}",
+                    outgoing.to, outgoing.name, resuming
+                ))
+            }
+            Err(err) => {
+                let outgoing = self.outgoing.remove(&transfer_id).unwrap();
+                Outcome::send(
+                    Event::FileReject(transfer_id, "the file could not be read".to_string()),
+                    format!("FILE:> can't send {}: {}", outgoing.path.display(), err),
+                )
+            }
+        }
+    }
+
+    fn write_chunk(&mut self, transfer_id: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let incoming = match self
+            .incoming
+            .values_mut()
+            .find(|incoming| incoming.transfer_id == transfer_id)
+        {
+            Some(incoming) => incoming,
+            None => return Ok(()),
+        };
+
+        let file = match &mut incoming.save {
+            Some((_, file)) if offset == incoming.received => file,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "chunk out of order",
+                ))
+            }
+        };
+
+        file.write_all(data)?;
+        incoming.received += data.len() as u64;
+        Ok(())
+    }
+
+    fn completed(&mut self, transfer_id: u64) -> Outcome {
+        let incoming = match self.take_incoming(transfer_id) {
+            Some(incoming) => incoming,
+            None => return Outcome::default(),
+        };
+        let (path, file) = match incoming.save {
+            Some(save) => save,
+            None => return Outcome::default(),
+        };
+        drop(file);
+
+        let part = part_path(&path);
+        let complete = incoming.received == incoming.size;
+        let expected = &incoming.sha256;
+        let checked = sha256_of(&part).and_then(|sha256| {
+            if complete && sha256 == *expected {
+                fs::rename(&part, &path).map(|_| true)
+            } else {
+                // there is no telling which part is wrong, so start over
+                // next time
+                fs::remove_file(&part).map(|_| false)
+            }
+        });
+
+        match checked {
+            Ok(true) => Outcome::note(format!(
+                "FILE:> saved {} from {} to {}",
+                incoming.name,
+                incoming.from,
+                path.display()
+            )),
+            Ok(false) => Outcome::note(format!(
+                "FILE:> {} from {} did not match its checksum and was thrown away",
+                incoming.name, incoming.from
+            )),
+            Err(err) => Outcome::note(format!("FILE:> can't save {}: {}", path.display(), err)),
+        }
+    }
+
+    // Calls off a transfer to us that went wrong on our end. Whatever made it
+    // to disk is kept so that it can be resumed.
+    fn give_up(&mut self, transfer_id: u64, err: io::Error) -> Outcome {
+        match self.take_incoming(transfer_id) {
+            Some(incoming) => Outcome::send(
+                Event::FileReject(transfer_id, err.to_string()),
+                format!("FILE:> gave up on {}: {}", incoming.name, err),
+            ),
+            None => Outcome::default(),
+        }
+    }
+
+    fn take_incoming(&mut self, transfer_id: u64) -> Option<Incoming> {
+        let number = *self
+            .incoming
+            .iter()
+            .find(|(_, incoming)| incoming.transfer_id == transfer_id)?
+            .0;
+        self.incoming.remove(&number)
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_os_string();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn sha256_of(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn describe_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rtalk-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Offers a file from `sender` to `recipient`, playing the part of the
+    // server, and returns the transfer ID along with what the recipient saw.
+    fn offer(sender: &mut Transfers, recipient: &mut Transfers, path: &Path) -> (u64, Outcome) {
+        match sender.execute(FileCommand::Send("bob".to_string(), path.to_path_buf())) {
+            Outcome {
+                event: Some(Event::FileOffer(transfer_id, _, name, size, sha256)),
+                ..
+            } => {
+                let offered =
+                    Event::FileOffered(transfer_id, "alice".to_string(), name, size, sha256);
+                (transfer_id, recipient.on_event(&offered))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    // Passes chunks from `sender` to `recipient` until the file is done,
+    // returning the recipient's last word on it.
+    fn relay(sender: &mut Transfers, recipient: &mut Transfers) -> String {
+        let mut chunks = 0;
+        while sender.uploading() {
+            let outcome = sender.next_chunk();
+            if let Some(event) = outcome.event {
+                if let Some(note) = recipient.on_event(&event).note {
+                    return note;
+                }
+            }
+            chunks += 1;
+            assert!(chunks < 1000, "the transfer never finished");
+        }
+        panic!("the sender stopped sending");
+    }
+
+    #[test]
+    fn files_arrive_whole_and_can_be_resumed() {
+        let dir = scratch("transfer");
+        let data = (0..100_000u32).map(|n| n as u8).collect::<Vec<_>>();
+        let source = dir.join("data.bin");
+        fs::write(&source, &data).unwrap();
+
+        let mut alice = Transfers::default();
+        let mut bob = Transfers::default();
+
+        // an earlier attempt got part of the way
+        let saved = dir.join("saved.bin");
+        fs::write(part_path(&saved), &data[..40_000]).unwrap();
+
+        let (transfer_id, offered) = offer(&mut alice, &mut bob, &source);
+        assert!(offered.note.unwrap().contains("/accept 1"));
+
+        let accept = bob.execute(FileCommand::Accept(1, Some(saved.clone())));
+        assert_eq!(accept.event, Some(Event::FileAccept(transfer_id, 40_000)));
+        alice.on_event(&accept.event.unwrap());
+
+        assert!(relay(&mut alice, &mut bob).contains("saved data.bin"));
+        assert_eq!(fs::read(&saved).unwrap(), data);
+        assert!(!part_path(&saved).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_that_do_not_match_their_checksum_are_thrown_away() {
+        let dir = scratch("checksum");
+        let source = dir.join("data.bin");
+        fs::write(&source, b"the right stuff").unwrap();
+
+        let mut alice = Transfers::default();
+        let mut bob = Transfers::default();
+        offer(&mut alice, &mut bob, &source);
+
+        // the file changes after it has been offered
+        fs::write(&source, b"the wrong stuff").unwrap();
+        let saved = dir.join("saved.bin");
+        let accept = bob.execute(FileCommand::Accept(1, Some(saved.clone())));
+        alice.on_event(&accept.event.unwrap());
+
+        assert!(relay(&mut alice, &mut bob).contains("checksum"));
+        assert!(!saved.exists());
+        assert!(!part_path(&saved).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
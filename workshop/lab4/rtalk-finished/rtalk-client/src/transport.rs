@@ -0,0 +1,200 @@
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use quinn::{ClientConfig, Endpoint, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Anything the client can talk to a server over. Events are encoded the same
+// way whatever carries them, so the client only needs to read and write.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+// A connection over whichever transport was picked at runtime.
+pub type Connection = Box<dyn Transport>;
+
+// A QUIC connection, with events going back and forth on one bidirectional
+// stream. Unlike TCP it lives through the client's address changing.
+pub struct QuicStream {
+    // the connection closes if this goes
+    endpoint: Endpoint,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    // Connects to the server at `addr`, which has to show a certificate for
+    // `server_name` signed by one of the PEM certificates in `roots`.
+    pub async fn connect(addr: SocketAddr, server_name: &str, roots: &[u8]) -> io::Result<Self> {
+        let mut store = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &roots[..])? {
+            store
+                .add(&rustls::Certificate(cert))
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        }
+
+        let local: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let mut endpoint = Endpoint::client(local)?;
+        endpoint.set_default_client_config(ClientConfig::with_root_certificates(store));
+
+        let connection = endpoint
+            .connect(addr, server_name)
+            .map_err(to_io_error)?
+            .await
+            .map_err(to_io_error)?;
+        let (send, recv) = connection.open_bi().await.map_err(to_io_error)?;
+
+        Ok(QuicStream {
+            endpoint,
+            send,
+            recv,
+        })
+    }
+
+    // Moves the connection over to `socket`, as happens when a phone
+    // switches networks. The server follows along once it hears from us at
+    // the new address.
+    pub fn rebind(&self, socket: UdpSocket) -> io::Result<()> {
+        self.endpoint.rebind(socket)
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rcgen::Certificate;
+    use tokio_stream::StreamExt;
+
+    use rtalk_codec::Event;
+    use rtalk_server::{bind_quic, testing};
+
+    use crate::Client;
+
+    // Starts a server listening for QUIC on loopback with `cert`, returning
+    // its address. `test` keeps the PEM files of tests running at the same
+    // time apart.
+    fn start_server(cert: &Certificate, test: &str) -> SocketAddr {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let cert_path = dir.join(format!("rtalk-{}-{}-cert.pem", test, id));
+        let key_path = dir.join(format!("rtalk-{}-{}-key.pem", test, id));
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        let endpoint = bind_quic(
+            "127.0.0.1:0".parse().unwrap(),
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+        )
+        .unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let _ = fs::remove_file(&cert_path);
+        let _ = fs::remove_file(&key_path);
+
+        let server = testing::server();
+        tokio::spawn(async move { server.serve_quic(endpoint).await });
+        addr
+    }
+
+    async fn join(addr: SocketAddr, roots: &str, name: &str) -> Client<QuicStream> {
+        let stream = QuicStream::connect(addr, "localhost", roots.as_bytes())
+            .await
+            .unwrap();
+        let mut client = Client::new(stream);
+        client.join(name).await.unwrap();
+        next_matching(&mut client, |e| matches!(e, Event::UserList(_))).await;
+        client
+    }
+
+    async fn next_matching<F>(client: &mut Client<QuicStream>, matches: F) -> Event
+    where
+        F: Fn(&Event) -> bool,
+    {
+        loop {
+            match client.next().await {
+                Some(Ok(event)) if matches(&event) => return event,
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    fn said(event: &Event, text: &str) -> bool {
+        matches!(event, Event::MessageReceived(_, _, msg) if msg == text)
+    }
+
+    #[tokio::test]
+    async fn quic_clients_keep_talking_after_moving() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = start_server(&cert, "moving");
+        let roots = cert.serialize_pem().unwrap();
+
+        let mut alice = join(addr, &roots, "alice").await;
+        let mut bob = join(addr, &roots, "bob").await;
+
+        bob.send("hello").await.unwrap();
+        next_matching(&mut alice, |e| said(e, "hello")).await;
+
+        // alice's phone moves from wifi to mobile data
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        alice.transport().rebind(socket).unwrap();
+
+        alice.send("still here").await.unwrap();
+        next_matching(&mut bob, |e| said(e, "still here")).await;
+        bob.send("welcome back").await.unwrap();
+        next_matching(&mut alice, |e| said(e, "welcome back")).await;
+    }
+
+    #[tokio::test]
+    async fn servers_must_have_a_trusted_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let addr = start_server(&cert, "untrusted");
+
+        let stranger = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let roots = stranger.serialize_pem().unwrap();
+        assert!(QuicStream::connect(addr, "localhost", roots.as_bytes())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crossterm::event::{
+    Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures::{future, select};
+use futures_util::future::FutureExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+use tokio::{task, time};
+use tokio_stream::StreamExt;
+
+use rtalk_client::{Client, Delivery, Outbox, Update};
+use rtalk_codec::{Event, Presence};
+
+use crate::commands::{Action, Outcome, Registry};
+use crate::direct::Direct;
+use crate::highlight::Highlights;
+use crate::input::LineEditor;
+use crate::render_event;
+use crate::state::{bare_name, ChatState};
+use crate::transcript::Transcript;
+use crate::transfer::Transfers;
+
+const MEMBERS_WIDTH: u16 = 28;
+const PAGE_SIZE: usize = 10;
+
+// We let the server know we're typing at most this often.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+// The screen is redrawn at least this often so that typing indicators go away
+// on their own.
+const TICK: Duration = Duration::from_secs(1);
+
+enum KeyAction {
+    None,
+    Typing,
+    Submit(String),
+    Quit,
+}
+
+// A line of the scrollback. Messages are looked up and drawn afresh each
+// time so that edits, deletions and reactions show up in place.
+enum Entry {
+    Text(String),
+    Message(u64),
+    // one of ours that the server hasn't sent out, by sequence number
+    Outgoing(u64),
+}
+
+struct App {
+    state: ChatState,
+    registry: Registry,
+    connected: bool,
+    scrollback: Vec<Entry>,
+    // number of lines the view is scrolled up from the bottom
+    scroll: usize,
+    input: LineEditor,
+    last_typing: Option<Instant>,
+    transfers: Transfers,
+    direct: Direct,
+    outbox: Outbox,
+    // what we're sending and how far it has got, until it comes back
+    outgoing: HashMap<u64, (String, Delivery)>,
+    highlights: Highlights,
+    // whether the scrollback only shows flagged messages
+    mentions_only: bool,
+    transcript: Transcript,
+}
+
+impl App {
+    fn new(
+        user_name: String,
+        direct: Direct,
+        highlights: Highlights,
+        transcript: Transcript,
+    ) -> Self {
+        App {
+            state: ChatState::new(user_name),
+            registry: Registry::default(),
+            connected: true,
+            scrollback: Vec::new(),
+            scroll: 0,
+            input: LineEditor::new(),
+            last_typing: None,
+            transfers: Transfers::default(),
+            direct,
+            outbox: Outbox::default(),
+            outgoing: HashMap::new(),
+            highlights,
+            mentions_only: false,
+            transcript,
+        }
+    }
+
+    fn push_line(&mut self, line: String) {
+        self.push_entry(Entry::Text(line));
+    }
+
+    fn push_entry(&mut self, entry: Entry) {
+        self.scrollback.push(entry);
+
+        // keep the view anchored if the user has scrolled up
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn remove_entry(&mut self, index: usize) {
+        // keep the view anchored if the entry was below it
+        if index >= self.scrollback.len() - self.scroll.min(self.scrollback.len()) {
+            self.scroll = self.scroll.saturating_sub(1);
+        }
+        self.scrollback.remove(index);
+    }
+
+    fn flagged(&self, entry: &Entry) -> bool {
+        match entry {
+            Entry::Message(id) => self
+                .state
+                .message(*id)
+                .is_some_and(|message| message.flagged),
+            _ => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.scrollback.clear();
+        self.scroll = 0;
+    }
+
+    // Takes in an event from the server, returning any events to send back
+    // because of it.
+    fn on_event(&mut self, event: Event) -> Vec<Event> {
+        // mentions from while we were away are the only sign of the message
+        let missed =
+            matches!(&event, Event::Mentioned(id, _, _) if self.state.message(*id).is_none());
+
+        if let Err(err) = self.transcript.record(&event) {
+            self.push_line(format!("*** transcript stopped: {}", err));
+        }
+        self.state.apply(&event);
+        self.highlights.on_event(&mut self.state, &event);
+        if let Some(update) = self.outbox.on_event(&event) {
+            self.update(update);
+        }
+        let mut outcomes = vec![self.transfers.on_event(&event)];
+        outcomes.extend(self.direct.on_event(&event));
+
+        match event {
+            Event::MessageReceived(id, _, _) => self.push_entry(Entry::Message(id)),
+            Event::Mentioned(id, _, _) => {
+                if missed {
+                    self.push_entry(Entry::Message(id));
+                }
+            }
+
+            // these change a message that is already on screen
+            Event::MessageEdited(_, _) | Event::MessageDeleted(_) | Event::Reactions(_, _) => {}
+
+            event => {
+                if let Some(text) = render_event(&event) {
+                    for line in text.lines() {
+                        self.push_line(line.to_string());
+                    }
+                }
+            }
+        }
+
+        outcomes
+            .into_iter()
+            .filter_map(|outcome| self.carry_out(outcome))
+            .collect()
+    }
+
+    // Chat messages are shown as being sent straight away. Once the server
+    // acknowledges one it shows up again like everyone else's, so the
+    // placeholder goes.
+    fn track(&mut self, event: Event) -> Event {
+        if !self.connected {
+            return event;
+        }
+
+        let event = self.outbox.track(event);
+        if let Event::TrackedSend(seq, text) = &event {
+            self.outgoing.insert(*seq, (text.clone(), Delivery::Sent));
+            self.push_entry(Entry::Outgoing(*seq));
+        }
+        event
+    }
+
+    fn update(&mut self, update: Update) {
+        if let Delivery::Failed(_) = update.delivery {
+            self.outgoing
+                .insert(update.seq, (update.text, update.delivery));
+            return;
+        }
+
+        self.outgoing.remove(&update.seq);
+        let placeholder = self
+            .scrollback
+            .iter()
+            .position(|entry| matches!(entry, Entry::Outgoing(seq) if *seq == update.seq));
+        if let Some(index) = placeholder {
+            self.remove_entry(index);
+        }
+    }
+
+    // Returns the messages to send again because the server hasn't
+    // acknowledged them yet.
+    fn retry(&mut self) -> Vec<Event> {
+        let (resend, failed) = self.outbox.retry(Instant::now());
+        for update in failed {
+            self.update(update);
+        }
+        resend
+    }
+
+    // Sends the next chunk of whatever file is going out.
+    fn upload(&mut self) -> Option<Event> {
+        let outcome = self.transfers.next_chunk();
+        self.carry_out(outcome)
+    }
+
+    // Shows what came of a transfer or direct message and hands back the
+    // event to send, if any.
+    fn carry_out(&mut self, outcome: Outcome) -> Option<Event> {
+        if let Some(note) = outcome.note {
+            self.push_line(note);
+        }
+        outcome.event
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> KeyAction {
+        if key.kind == KeyEventKind::Release {
+            return KeyAction::None;
+        }
+
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return KeyAction::Quit,
+            KeyCode::Char('a') if ctrl => self.input.home(),
+            KeyCode::Char('e') if ctrl => self.input.end(),
+            KeyCode::Char('u') if ctrl => self.input.clear(),
+            KeyCode::Char(c) => {
+                self.input.insert(c);
+                return self.typing();
+            }
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.history_prev(),
+            KeyCode::Down => self.input.history_next(),
+            KeyCode::Tab => self.complete(),
+            KeyCode::PageUp => {
+                let max = self.scrollback.len();
+                self.scroll = (self.scroll + PAGE_SIZE).min(max);
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE_SIZE),
+            KeyCode::Enter => {
+                let line = self.input.submit();
+                if !line.is_empty() {
+                    return KeyAction::Submit(line);
+                }
+            }
+            _ => {}
+        }
+
+        KeyAction::None
+    }
+
+    // Decides whether a keystroke should be passed on as a typing
+    // notification. Commands aren't chat so they don't count.
+    fn typing(&mut self) -> KeyAction {
+        if self.input.text().starts_with('/') {
+            return KeyAction::None;
+        }
+
+        match self.last_typing {
+            Some(last) if last.elapsed() < TYPING_INTERVAL => KeyAction::None,
+            _ => {
+                self.last_typing = Some(Instant::now());
+                KeyAction::Typing
+            }
+        }
+    }
+
+    fn complete(&mut self) {
+        let line = self.input.text();
+        let candidates = self.registry.complete(&self.state, &line);
+
+        match candidates.len() {
+            0 => {}
+            1 => self.input.set_text(&candidates[0]),
+            _ => {
+                let prefix = common_prefix(&candidates);
+                if prefix.len() > line.len() {
+                    self.input.set_text(&prefix);
+                }
+
+                let choices = candidates
+                    .iter()
+                    .filter_map(|c| c.split_whitespace().last())
+                    .collect::<Vec<_>>();
+                self.push_line(choices.join("  "));
+            }
+        }
+    }
+
+    // Carries out a command locally, returning the event to send to the
+    // server if there is one.
+    fn execute(&mut self, line: &str) -> Result<Option<Event>, ()> {
+        self.last_typing = None;
+
+        match self.registry.execute(line) {
+            Ok(Action::Send(event)) => return Ok(Some(self.track(event))),
+            Ok(Action::File(command)) => {
+                let outcome = self.transfers.execute(command);
+                return Ok(self.carry_out(outcome));
+            }
+            Ok(Action::Direct(command)) => {
+                let outcome = self.direct.execute(command);
+                return Ok(self.carry_out(outcome));
+            }
+            Ok(Action::Print(text)) => {
+                for line in text.lines() {
+                    self.push_line(line.to_string());
+                }
+            }
+            Ok(Action::Clear) => self.clear(),
+            Ok(Action::Export(path)) => {
+                let line = match self.transcript.export(&path) {
+                    Ok(count) => format!("*** exported {} events to {}", count, path.display()),
+                    Err(err) => format!("*** couldn't export to {}: {}", path.display(), err),
+                };
+                self.push_line(line);
+            }
+            Ok(Action::Mentions) => {
+                self.mentions_only = !self.mentions_only;
+                self.scroll = 0;
+            }
+            Ok(Action::Quit) => return Err(()),
+            Err(err) => self.push_line(format!("*** {}", err)),
+        }
+
+        Ok(None)
+    }
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let first = &candidates[0];
+    let len = candidates[1..].iter().fold(first.len(), |len, c| {
+        first
+            .char_indices()
+            .zip(c.chars())
+            .take_while(|((i, a), b)| *i < len && a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+    });
+
+    first[..len].to_string()
+}
+
+// Puts the terminal back the way we found it, including when we bail out
+// early with an error.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+pub async fn run(
+    mut client: Client,
+    user_name: String,
+    direct: Direct,
+    highlights: Highlights,
+    transcript: Transcript,
+) -> Result<(), Box<dyn Error>> {
+    let _guard = TerminalGuard::new()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let mut keys = EventStream::new();
+    let mut app = App::new(user_name, direct, highlights, transcript);
+    let mut ticks = time::interval(TICK);
+
+    loop {
+        terminal.draw(|f| draw(f, &app))?;
+
+        // stop polling the network once the server has gone away but keep
+        // the UI up so that the scrollback can still be read
+        let connected = app.connected;
+        let uploading = connected && app.transfers.uploading();
+
+        select! {
+            event = async {
+                if connected {
+                    client.next().await
+                } else {
+                    future::pending().await
+                }
+            }.fuse() => {
+                match event {
+                    Some(Ok(event)) => {
+                        for reply in app.on_event(event) {
+                            client.send_event(reply).await?;
+                        }
+                    }
+                    _ => {
+                        app.connected = false;
+                        app.push_line("*** connection to server lost".to_string());
+                    }
+                }
+            },
+            key = keys.next().fuse() => {
+                match key {
+                    Some(Ok(TermEvent::Key(key))) => match app.on_key(key) {
+                        KeyAction::None => {}
+                        KeyAction::Typing if app.connected => client.send_event(Event::Typing()).await?,
+                        KeyAction::Typing => {}
+                        KeyAction::Submit(line) => match app.execute(&line) {
+                            Ok(Some(event)) if app.connected => client.send_event(event).await?,
+                            Ok(Some(_)) => app.push_line("*** not connected".to_string()),
+                            Ok(None) => {}
+                            Err(()) => break,
+                        },
+                        KeyAction::Quit => break,
+                    },
+                    Some(Ok(_)) => {}
+                    _ => break,
+                }
+            },
+
+            // files go out a chunk at a time in between everything else
+            _ = async {
+                if uploading {
+                    task::yield_now().await
+                } else {
+                    future::pending().await
+                }
+            }.fuse() => {
+                if let Some(event) = app.upload() {
+                    client.send_event(event).await?;
+                }
+            },
+            _ = ticks.tick().fuse() => {
+                if app.connected {
+                    for event in app.retry() {
+                        client.send_event(event).await?;
+                    }
+                }
+            },
+        }
+    }
+
+    if app.connected {
+        client.leave().await?;
+    }
+
+    Ok(())
+}
+
+fn draw(f: &mut Frame, app: &App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(10), Constraint::Length(MEMBERS_WIDTH)])
+        .split(rows[0]);
+
+    draw_scrollback(f, app, top[0]);
+    draw_members(f, app, top[1]);
+    draw_input(f, app, rows[1]);
+    draw_status(f, app, rows[2]);
+}
+
+fn draw_scrollback(f: &mut Frame, app: &App, area: Rect) {
+    let entries = app
+        .scrollback
+        .iter()
+        .filter(|entry| !app.mentions_only || app.flagged(entry))
+        .collect::<Vec<_>>();
+
+    let height = area.height.saturating_sub(2) as usize;
+    let end = entries.len() - app.scroll.min(entries.len());
+    let start = end.saturating_sub(height);
+
+    let lines = entries[start..end]
+        .iter()
+        .map(|entry| match entry {
+            Entry::Text(line) => Line::from(line.as_str()),
+            Entry::Message(id) => match app.state.message(*id) {
+                Some(message) if message.flagged => {
+                    Line::styled(message.render(*id), Style::default().fg(Color::Yellow))
+                }
+                Some(message) => Line::from(message.render(*id)),
+                None => Line::from(format!("#{} (forgotten)", id)),
+            },
+            Entry::Outgoing(seq) => match app.outgoing.get(seq) {
+                Some((text, Delivery::Failed(reason))) => Line::styled(
+                    format!("{}:> {} (not sent: {})", app.state.user_name, text, reason),
+                    Style::default().fg(Color::Red),
+                ),
+                Some((text, _)) => Line::styled(
+                    format!("{}:> {} (sending)", app.state.user_name, text),
+                    Style::default().fg(Color::DarkGray),
+                ),
+                None => Line::default(),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    let view = if app.mentions_only {
+        "rtalk mentions"
+    } else {
+        "rtalk"
+    };
+    let title = if app.scroll > 0 {
+        format!(" {} (scrolled up {} lines) ", view, app.scroll)
+    } else {
+        format!(" {} ", view)
+    };
+
+    let view = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(view, area);
+}
+
+fn draw_members(f: &mut Frame, app: &App, area: Rect) {
+    let items = app
+        .state
+        .members
+        .iter()
+        .map(|member| {
+            let (marker, color) = match app.state.presence_of(member) {
+                Some((Presence::Away, _)) => ("~ ", Color::Yellow),
+                Some((Presence::Busy, _)) => ("- ", Color::Red),
+                _ => ("+ ", Color::Green),
+            };
+
+            let mut spans = vec![
+                Span::styled(marker, Style::default().fg(color)),
+                Span::raw(bare_name(member)),
+            ];
+            if let Some((_, Some(status))) = app.state.presence_of(member) {
+                spans.push(Span::styled(
+                    format!(" ({})", status),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+
+            ListItem::new(Line::from(spans))
+        })
+        .collect::<Vec<_>>();
+
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" members ({}) ", app.state.members.len())),
+    );
+    f.render_widget(list, area);
+}
+
+fn draw_input(f: &mut Frame, app: &App, area: Rect) {
+    let text = app.input.text();
+    let width = area.width.saturating_sub(2) as usize;
+
+    // scroll the input horizontally so that the cursor stays visible
+    let cursor = app.input.cursor();
+    let offset = (cursor + 1).saturating_sub(width);
+    let visible = text.chars().skip(offset).collect::<String>();
+
+    let input = Paragraph::new(visible).block(Block::default().borders(Borders::ALL));
+    f.render_widget(input, area);
+    f.set_cursor(area.x + 1 + (cursor - offset) as u16, area.y + 1);
+}
+
+fn draw_status(f: &mut Frame, app: &App, area: Rect) {
+    let state = if app.connected {
+        "connected"
+    } else {
+        "disconnected"
+    };
+
+    let mut status = format!(
+        " {} as {} | {} online | PgUp/PgDn scroll | Ctrl-C quit",
+        state,
+        app.state.user_name,
+        app.state.members.len()
+    );
+
+    let typing = app.state.typing();
+    match typing.len() {
+        0 => {}
+        1 => status.push_str(&format!(" | {} is typing...", typing[0])),
+        _ => status.push_str(&format!(" | {} are typing...", typing.join(", "))),
+    }
+
+    let bar = Paragraph::new(status).style(Style::default().fg(Color::Black).bg(Color::Cyan));
+    f.render_widget(bar, area);
+}
@@ -1,18 +1,161 @@
 use std::io::{Error, ErrorKind};
 
 use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
 
 const MAGIC_COOKIE: u32 = 0xDEAD_BEEF;
 
-#[derive(Debug)]
+// The most an event can take up on the wire. The biggest ones are user lists
+// and file chunks, which come nowhere near this. Neither end sends anything
+// longer, since the other end would only turn it away.
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Online,
+    Away,
+    Busy,
+}
+
+impl Presence {
+    fn to_u8(self) -> u8 {
+        match self {
+            Presence::Online => 0,
+            Presence::Away => 1,
+            Presence::Busy => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Presence::Online),
+            1 => Some(Presence::Away),
+            2 => Some(Presence::Busy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub name: String,
+    pub connected_secs: u64,
+    pub idle_secs: u64,
+    pub presence: Presence,
+    pub status: Option<String>,
+}
+
+// How many people have reacted to a message with one emoji.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: u64,
+}
+
+// Besides the binary framing below, events can be carried as JSON for
+// clients that find that easier, e.g. {"MessageSend": "hi"} or
+// {"Leave": []}.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Event {
     RequestJoin(String),
     Joined(String),
     Leave(),
     Left(String),
     MessageSend(String),
-    MessageReceived(String, String),
+    // message ID, sender, text
+    MessageReceived(u64, String, String),
+    ListUsers(),
+    UserList(Vec<UserInfo>),
+    SetPresence(Presence, Option<String>),
+    PresenceChanged(String, Presence, Option<String>),
+    Typing(),
+    UserTyping(String),
+    Warning(String),
+    Disconnect(String),
+    Notice(String),
+    Op(String),
+    Kick(String, String),
+    Ban(String, u64, String),
+    Unban(String),
+    Mute(String, u64),
+    Edit(u64, String),
+    Delete(u64),
+    // reacting again with the same emoji takes the reaction back
+    React(u64, String),
+    MessageEdited(u64, String),
+    MessageDeleted(u64),
+    // every reaction to a message so far
+    Reactions(u64, Vec<Reaction>),
+
+    // File transfers. The sender picks the transfer ID, at random so that it
+    // doesn't clash with anyone else's, and the server passes each of these
+    // on to the other side of the transfer.
+    //
+    // transfer ID, recipient, file name, size, SHA-256 as hex
+    FileOffer(u64, String, String, u64, String),
+    // transfer ID, sender, file name, size, SHA-256 as hex
+    FileOffered(u64, String, String, u64, String),
+    // transfer ID, offset to start from when resuming
+    FileAccept(u64, u64),
+    // transfer ID, reason; either side can give up at any time
+    FileReject(u64, String),
+    // transfer ID, offset, data
+    FileChunk(u64, u64, Vec<u8>),
+    FileComplete(u64),
+
+    // Direct messages, encrypted end to end. The server keeps a directory of
+    // public keys for them but never sees what they say.
+    //
+    // our public key
+    PublishKey(Vec<u8>),
+    // asks for someone's public key
+    KeyRequest(String),
+    // name, public key
+    PublicKey(String, Vec<u8>),
+    // recipient, nonce, ciphertext
+    EncryptedSend(String, Vec<u8>, Vec<u8>),
+    // sender, sender's public key, nonce, ciphertext
+    EncryptedReceived(String, Vec<u8>, Vec<u8>, Vec<u8>),
+
+    // Receipts for messages to people who weren't around, which the server
+    // keeps until they come back.
+    //
+    // mailbox ID, recipient
+    Queued(u64, String),
+    // mailbox ID, recipient
+    Delivered(u64, String),
+
+    // Chat messages the sender wants to hear back about. The client numbers
+    // them and sends them again if no answer comes, so the server only passes
+    // each number on once but answers every time.
+    //
+    // sequence number, text
+    TrackedSend(u64, String),
+    // sequence number, ID the message went out with
+    MessageAck(u64, u64),
+    // sequence number, reason
+    MessageFailed(u64, String),
+    // A number the client picks at random and keeps when it connects again,
+    // so that tracked messages it sends again after losing the connection
+    // still only go out once.
+    //
+    // client ID
+    ClientId(u64),
+
+    // Sent on top of a message to the people it mentions as "@name", or
+    // kept for them if they're not around.
+    //
+    // message ID, sender, text
+    Mentioned(u64, String, String),
+
+    // Proof that whoever published a key holds its secret half, which is what
+    // their mailbox and remembered key belong to.
+    //
+    // a public key made for the occasion
+    KeyChallenge(Vec<u8>),
+    // what the shared secret of that and our key hashes to
+    KeyProof(Vec<u8>),
 }
 
 impl Event {
@@ -23,7 +166,47 @@ impl Event {
             Event::Leave() => 2,
             Event::Left(_) => 3,
             Event::MessageSend(_) => 4,
-            Event::MessageReceived(_, _) => 5,
+            Event::MessageReceived(_, _, _) => 5,
+            Event::ListUsers() => 6,
+            Event::UserList(_) => 7,
+            Event::SetPresence(_, _) => 8,
+            Event::PresenceChanged(_, _, _) => 9,
+            Event::Typing() => 10,
+            Event::UserTyping(_) => 11,
+            Event::Warning(_) => 12,
+            Event::Disconnect(_) => 13,
+            Event::Notice(_) => 14,
+            Event::Op(_) => 15,
+            Event::Kick(_, _) => 16,
+            Event::Ban(_, _, _) => 17,
+            Event::Unban(_) => 18,
+            Event::Mute(_, _) => 19,
+            Event::Edit(_, _) => 20,
+            Event::Delete(_) => 21,
+            Event::React(_, _) => 22,
+            Event::MessageEdited(_, _) => 23,
+            Event::MessageDeleted(_) => 24,
+            Event::Reactions(_, _) => 25,
+            Event::FileOffer(_, _, _, _, _) => 26,
+            Event::FileOffered(_, _, _, _, _) => 27,
+            Event::FileAccept(_, _) => 28,
+            Event::FileReject(_, _) => 29,
+            Event::FileChunk(_, _, _) => 30,
+            Event::FileComplete(_) => 31,
+            Event::PublishKey(_) => 32,
+            Event::KeyRequest(_) => 33,
+            Event::PublicKey(_, _) => 34,
+            Event::EncryptedSend(_, _, _) => 35,
+            Event::EncryptedReceived(_, _, _, _) => 36,
+            Event::Queued(_, _) => 37,
+            Event::Delivered(_, _) => 38,
+            Event::TrackedSend(_, _) => 39,
+            Event::MessageAck(_, _) => 40,
+            Event::MessageFailed(_, _) => 41,
+            Event::Mentioned(_, _, _) => 42,
+            Event::KeyChallenge(_) => 43,
+            Event::KeyProof(_) => 44,
+            Event::ClientId(_) => 45,
         }
     }
 }
@@ -36,161 +219,534 @@ fn put_string(dst: &mut BytesMut, string: &str) {
     dst.put_slice(buf);
 }
 
-impl Encoder for EventCodec {
-    type Item = Event;
+fn put_bytes(dst: &mut BytesMut, bytes: &[u8]) {
+    dst.put_u64(bytes.len() as u64);
+    dst.put_slice(bytes);
+}
+
+fn put_opt_string(dst: &mut BytesMut, string: &Option<String>) {
+    match string {
+        Some(string) => {
+            dst.put_u8(1);
+            put_string(dst, string);
+        }
+        None => dst.put_u8(0),
+    }
+}
+
+impl Encoder<Event> for EventCodec {
     type Error = Error;
 
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, item: Event, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
         dst.put_u32(MAGIC_COOKIE);
         dst.put_u8(item.discriminant());
 
         match &item {
-            Event::RequestJoin(user) | Event::Joined(user) | Event::Left(user) => {
+            Event::RequestJoin(user)
+            | Event::Joined(user)
+            | Event::Left(user)
+            | Event::UserTyping(user) => {
                 put_string(dst, user);
             }
 
-            Event::MessageSend(msg) => {
+            Event::MessageSend(msg)
+            | Event::Warning(msg)
+            | Event::Disconnect(msg)
+            | Event::Notice(msg) => {
                 put_string(dst, msg);
             }
 
-            Event::Leave() => {}
+            Event::Op(target) | Event::Unban(target) => {
+                put_string(dst, target);
+            }
+
+            Event::Kick(target, reason) => {
+                put_string(dst, target);
+                put_string(dst, reason);
+            }
+
+            Event::Ban(target, secs, reason) => {
+                put_string(dst, target);
+                dst.put_u64(*secs);
+                put_string(dst, reason);
+            }
+
+            Event::Mute(target, secs) => {
+                put_string(dst, target);
+                dst.put_u64(*secs);
+            }
+
+            Event::Leave() | Event::ListUsers() | Event::Typing() => {}
 
-            Event::MessageReceived(who, msg) => {
+            Event::MessageReceived(id, who, msg) | Event::Mentioned(id, who, msg) => {
+                dst.put_u64(*id);
                 put_string(dst, who);
                 put_string(dst, msg);
             }
+
+            Event::Edit(id, text)
+            | Event::React(id, text)
+            | Event::MessageEdited(id, text)
+            | Event::TrackedSend(id, text) => {
+                dst.put_u64(*id);
+                put_string(dst, text);
+            }
+
+            Event::Delete(id)
+            | Event::MessageDeleted(id)
+            | Event::FileComplete(id)
+            | Event::ClientId(id) => {
+                dst.put_u64(*id);
+            }
+
+            Event::FileOffer(id, who, name, size, sha256)
+            | Event::FileOffered(id, who, name, size, sha256) => {
+                dst.put_u64(*id);
+                put_string(dst, who);
+                put_string(dst, name);
+                dst.put_u64(*size);
+                put_string(dst, sha256);
+            }
+
+            Event::FileAccept(id, offset) | Event::MessageAck(id, offset) => {
+                dst.put_u64(*id);
+                dst.put_u64(*offset);
+            }
+
+            Event::FileReject(id, reason)
+            | Event::Queued(id, reason)
+            | Event::Delivered(id, reason)
+            | Event::MessageFailed(id, reason) => {
+                dst.put_u64(*id);
+                put_string(dst, reason);
+            }
+
+            Event::PublishKey(key) | Event::KeyChallenge(key) | Event::KeyProof(key) => {
+                put_bytes(dst, key)
+            }
+
+            Event::KeyRequest(name) => put_string(dst, name),
+
+            Event::PublicKey(name, key) => {
+                put_string(dst, name);
+                put_bytes(dst, key);
+            }
+
+            Event::EncryptedSend(to, nonce, ciphertext) => {
+                put_string(dst, to);
+                put_bytes(dst, nonce);
+                put_bytes(dst, ciphertext);
+            }
+
+            Event::EncryptedReceived(from, key, nonce, ciphertext) => {
+                put_string(dst, from);
+                put_bytes(dst, key);
+                put_bytes(dst, nonce);
+                put_bytes(dst, ciphertext);
+            }
+
+            Event::FileChunk(id, offset, data) => {
+                dst.put_u64(*id);
+                dst.put_u64(*offset);
+                put_bytes(dst, data);
+            }
+
+            Event::Reactions(id, reactions) => {
+                dst.put_u64(*id);
+                dst.put_u64(reactions.len() as u64);
+                for reaction in reactions {
+                    put_string(dst, &reaction.emoji);
+                    dst.put_u64(reaction.count);
+                }
+            }
+
+            Event::UserList(users) => {
+                dst.put_u64(users.len() as u64);
+                for user in users {
+                    put_string(dst, &user.name);
+                    dst.put_u64(user.connected_secs);
+                    dst.put_u64(user.idle_secs);
+                    dst.put_u8(user.presence.to_u8());
+                    put_opt_string(dst, &user.status);
+                }
+            }
+
+            Event::SetPresence(presence, status) => {
+                dst.put_u8(presence.to_u8());
+                put_opt_string(dst, status);
+            }
+
+            Event::PresenceChanged(who, presence, status) => {
+                put_string(dst, who);
+                dst.put_u8(presence.to_u8());
+                put_opt_string(dst, status);
+            }
         }
 
+        // whatever was already waiting to go out is left as it was
+        if dst.len() - start > MAX_FRAME {
+            dst.truncate(start);
+            return Err(Error::new(ErrorKind::InvalidInput, "Frame too long"));
+        }
         Ok(())
     }
 }
 
-impl Decoder for EventCodec {
-    type Item = Event;
-    type Error = Error;
+enum DecodeError {
+    // not enough bytes have arrived yet to decode the whole event
+    Incomplete,
+    Invalid(Error),
+}
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
-            src.reserve(5);
-            return Ok(None);
-        }
+// Reads fields off the front of a frame without consuming the source buffer
+// so that nothing is lost when the frame turns out to be incomplete.
+struct Reader<'a> {
+    buf: &'a [u8],
+    consumed: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, consumed: 0 }
+    }
 
-        let cookie = src.get_u32();
-        if cookie != MAGIC_COOKIE {
-            return Err(Error::from(ErrorKind::InvalidInput));
+    // Lengths come from the other end, so they are checked against the limit
+    // before waiting for that many bytes to arrive.
+    fn need(&self, len: usize) -> Result<(), DecodeError> {
+        if self.consumed.saturating_add(len) > MAX_FRAME {
+            Err(DecodeError::Invalid(Error::new(
+                ErrorKind::InvalidData,
+                "Frame too long",
+            )))
+        } else if self.buf.len() < len {
+            Err(DecodeError::Incomplete)
+        } else {
+            Ok(())
         }
+    }
 
-        let discriminant = src.get_u8();
-        let evt = match discriminant {
-            0 => {
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
+    fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        self.need(1)?;
+        self.consumed += 1;
+        Ok(self.buf.get_u8())
+    }
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+    fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        self.need(4)?;
+        self.consumed += 4;
+        Ok(self.buf.get_u32())
+    }
 
-                let user = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+    fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        self.need(8)?;
+        self.consumed += 8;
+        Ok(self.buf.get_u64())
+    }
 
-                Event::RequestJoin(user)
-            }
-            1 => {
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
+    fn get_string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.get_bytes()?).map_err(|_| bad_bytes())
+    }
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+    fn get_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.get_u64()? as usize;
+        self.need(len)?;
 
-                let user = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+        let bytes = self.buf[0..len].to_vec();
+        self.buf.advance(len);
+        self.consumed += len;
 
-                Event::Joined(user)
-            }
-            2 => Event::Leave(),
-            3 => {
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
+        Ok(bytes)
+    }
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+    fn get_opt_string(&mut self) -> Result<Option<String>, DecodeError> {
+        match self.get_u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.get_string()?)),
+            _ => Err(bad_bytes()),
+        }
+    }
+
+    fn get_presence(&mut self) -> Result<Presence, DecodeError> {
+        Presence::from_u8(self.get_u8()?).ok_or_else(bad_bytes)
+    }
+}
+
+fn bad_bytes() -> DecodeError {
+    DecodeError::Invalid(Error::new(ErrorKind::InvalidData, "Bad bytes"))
+}
 
-                let user = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+fn decode_event(src: &mut Reader) -> Result<Event, DecodeError> {
+    let cookie = src.get_u32()?;
+    if cookie != MAGIC_COOKIE {
+        return Err(DecodeError::Invalid(Error::from(ErrorKind::InvalidInput)));
+    }
 
-                Event::Left(user)
+    let evt = match src.get_u8()? {
+        0 => Event::RequestJoin(src.get_string()?),
+        1 => Event::Joined(src.get_string()?),
+        2 => Event::Leave(),
+        3 => Event::Left(src.get_string()?),
+        4 => Event::MessageSend(src.get_string()?),
+        5 => Event::MessageReceived(src.get_u64()?, src.get_string()?, src.get_string()?),
+        6 => Event::ListUsers(),
+        7 => {
+            let count = src.get_u64()?;
+            let mut users = Vec::new();
+            for _ in 0..count {
+                users.push(UserInfo {
+                    name: src.get_string()?,
+                    connected_secs: src.get_u64()?,
+                    idle_secs: src.get_u64()?,
+                    presence: src.get_presence()?,
+                    status: src.get_opt_string()?,
+                });
             }
-            4 => {
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+            Event::UserList(users)
+        }
+        8 => Event::SetPresence(src.get_presence()?, src.get_opt_string()?),
+        9 => Event::PresenceChanged(
+            src.get_string()?,
+            src.get_presence()?,
+            src.get_opt_string()?,
+        ),
+        10 => Event::Typing(),
+        11 => Event::UserTyping(src.get_string()?),
+        12 => Event::Warning(src.get_string()?),
+        13 => Event::Disconnect(src.get_string()?),
+        14 => Event::Notice(src.get_string()?),
+        15 => Event::Op(src.get_string()?),
+        16 => Event::Kick(src.get_string()?, src.get_string()?),
+        17 => Event::Ban(src.get_string()?, src.get_u64()?, src.get_string()?),
+        18 => Event::Unban(src.get_string()?),
+        19 => Event::Mute(src.get_string()?, src.get_u64()?),
+        20 => Event::Edit(src.get_u64()?, src.get_string()?),
+        21 => Event::Delete(src.get_u64()?),
+        22 => Event::React(src.get_u64()?, src.get_string()?),
+        23 => Event::MessageEdited(src.get_u64()?, src.get_string()?),
+        24 => Event::MessageDeleted(src.get_u64()?),
+        25 => {
+            let id = src.get_u64()?;
+            let count = src.get_u64()?;
+            let mut reactions = Vec::new();
+            for _ in 0..count {
+                reactions.push(Reaction {
+                    emoji: src.get_string()?,
+                    count: src.get_u64()?,
+                });
+            }
+
+            Event::Reactions(id, reactions)
+        }
+        26 => Event::FileOffer(
+            src.get_u64()?,
+            src.get_string()?,
+            src.get_string()?,
+            src.get_u64()?,
+            src.get_string()?,
+        ),
+        27 => Event::FileOffered(
+            src.get_u64()?,
+            src.get_string()?,
+            src.get_string()?,
+            src.get_u64()?,
+            src.get_string()?,
+        ),
+        28 => Event::FileAccept(src.get_u64()?, src.get_u64()?),
+        29 => Event::FileReject(src.get_u64()?, src.get_string()?),
+        30 => Event::FileChunk(src.get_u64()?, src.get_u64()?, src.get_bytes()?),
+        31 => Event::FileComplete(src.get_u64()?),
+        32 => Event::PublishKey(src.get_bytes()?),
+        33 => Event::KeyRequest(src.get_string()?),
+        34 => Event::PublicKey(src.get_string()?, src.get_bytes()?),
+        35 => Event::EncryptedSend(src.get_string()?, src.get_bytes()?, src.get_bytes()?),
+        36 => Event::EncryptedReceived(
+            src.get_string()?,
+            src.get_bytes()?,
+            src.get_bytes()?,
+            src.get_bytes()?,
+        ),
+        37 => Event::Queued(src.get_u64()?, src.get_string()?),
+        38 => Event::Delivered(src.get_u64()?, src.get_string()?),
+        39 => Event::TrackedSend(src.get_u64()?, src.get_string()?),
+        40 => Event::MessageAck(src.get_u64()?, src.get_u64()?),
+        41 => Event::MessageFailed(src.get_u64()?, src.get_string()?),
+        42 => Event::Mentioned(src.get_u64()?, src.get_string()?, src.get_string()?),
+        43 => Event::KeyChallenge(src.get_bytes()?),
+        44 => Event::KeyProof(src.get_bytes()?),
+        45 => Event::ClientId(src.get_u64()?),
+        _ => return Err(bad_bytes()),
+    };
+
+    Ok(evt)
+}
 
-                let msg = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+impl Decoder for EventCodec {
+    type Item = Event;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            src.reserve(5);
+            return Ok(None);
+        }
 
-                Event::MessageSend(msg)
+        let mut reader = Reader::new(&src[..]);
+        match decode_event(&mut reader) {
+            Ok(evt) => {
+                let consumed = reader.consumed;
+                src.advance(consumed);
+                Ok(Some(evt))
             }
-            5 => {
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
+            Err(DecodeError::Incomplete) => Ok(None),
+            Err(DecodeError::Invalid(err)) => Err(err),
+        }
+    }
+}
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let user = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+    fn round_trip(evt: Event) {
+        let mut buf = BytesMut::new();
+        EventCodec.encode(evt.clone(), &mut buf).unwrap();
+        assert_eq!(EventCodec.decode(&mut buf).unwrap(), Some(evt));
+        assert!(buf.is_empty());
+    }
 
-                if src.len() < 8 {
-                    src.reserve(8);
-                    return Ok(None);
-                }
+    #[test]
+    fn round_trips_events() {
+        round_trip(Event::RequestJoin("alice".to_string()));
+        round_trip(Event::Leave());
+        round_trip(Event::MessageReceived(
+            7,
+            "bob".to_string(),
+            "hi".to_string(),
+        ));
+        round_trip(Event::ListUsers());
+        round_trip(Event::UserList(vec![
+            UserInfo {
+                name: "alice".to_string(),
+                connected_secs: 120,
+                idle_secs: 5,
+                presence: Presence::Away,
+                status: Some("lunch".to_string()),
+            },
+            UserInfo {
+                name: "bob".to_string(),
+                connected_secs: 3,
+                idle_secs: 3,
+                presence: Presence::Online,
+                status: None,
+            },
+        ]));
+        round_trip(Event::SetPresence(Presence::Busy, None));
+        round_trip(Event::PresenceChanged(
+            "carol".to_string(),
+            Presence::Away,
+            Some("brb".to_string()),
+        ));
+        round_trip(Event::UserTyping("dave".to_string()));
+        round_trip(Event::Disconnect("flooding".to_string()));
+        round_trip(Event::Ban("10.0.0.1".to_string(), 3600, "spam".to_string()));
+        round_trip(Event::Mute("eve".to_string(), 0));
+        round_trip(Event::Edit(7, "hello".to_string()));
+        round_trip(Event::MessageDeleted(7));
+        round_trip(Event::Reactions(
+            7,
+            vec![Reaction {
+                emoji: "+1".to_string(),
+                count: 2,
+            }],
+        ));
+        round_trip(Event::FileOffer(
+            1,
+            "bob".to_string(),
+            "cat.png".to_string(),
+            1024,
+            "ab".repeat(32),
+        ));
+        round_trip(Event::FileAccept(9, 512));
+        round_trip(Event::FileChunk(1, 512, vec![0, 159, 146, 150, 255]));
+        round_trip(Event::FileComplete(1));
+        round_trip(Event::PublicKey("bob".to_string(), vec![7; 32]));
+        round_trip(Event::EncryptedReceived(
+            "alice".to_string(),
+            vec![7; 32],
+            vec![1; 12],
+            vec![2, 3, 5, 8],
+        ));
+        round_trip(Event::Queued(3, "bob".to_string()));
+        round_trip(Event::Delivered(3, "bob".to_string()));
+        round_trip(Event::TrackedSend(7, "hello".to_string()));
+        round_trip(Event::MessageAck(7, 42));
+        round_trip(Event::MessageFailed(7, "Not today.".to_string()));
+        round_trip(Event::Mentioned(
+            42,
+            "alice".to_string(),
+            "@bob lunch?".to_string(),
+        ));
+        round_trip(Event::KeyChallenge(vec![9; 32]));
+        round_trip(Event::KeyProof(vec![8; 32]));
+        round_trip(Event::ClientId(u64::MAX));
+    }
 
-                let len = src.get_u64() as usize;
-                if src.len() < len {
-                    src.reserve(len);
-                    return Ok(None);
-                }
+    #[test]
+    fn waits_for_the_rest_of_a_partial_frame() {
+        let mut full = BytesMut::new();
+        EventCodec
+            .encode(Event::MessageSend("hello".to_string()), &mut full)
+            .unwrap();
+
+        let mut buf = BytesMut::new();
+        for byte in &full[..full.len() - 1] {
+            buf.put_u8(*byte);
+            assert_eq!(EventCodec.decode(&mut buf).unwrap(), None);
+        }
+
+        buf.put_u8(full[full.len() - 1]);
+        assert_eq!(
+            EventCodec.decode(&mut buf).unwrap(),
+            Some(Event::MessageSend("hello".to_string()))
+        );
+    }
 
-                let msg = String::from_utf8(src[0..len].to_vec())
-                    .map_err(|_| Error::new(ErrorKind::InvalidData, "Bad bytes"))?;
-                src.advance(len);
+    #[test]
+    fn rejects_bad_cookie() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(0xCAFE_F00D);
+        buf.put_u8(2);
+        assert!(EventCodec.decode(&mut buf).is_err());
+    }
 
-                Event::MessageReceived(user, msg)
-            }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "Bad bytes")),
-        };
+    #[test]
+    fn rejects_frames_that_are_too_long() {
+        // only the header has arrived, but there's no point waiting for 4 GiB
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAGIC_COOKIE);
+        buf.put_u8(4);
+        buf.put_u64(4 * 1024 * 1024 * 1024);
+        buf.put_slice(b"hello");
+        let err = EventCodec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // a little under the limit is fine
+        round_trip(Event::MessageSend("x".repeat(MAX_FRAME - 100)));
+    }
 
-        Ok(Some(evt))
+    #[test]
+    fn refuses_to_encode_frames_that_are_too_long() {
+        let mut buf = BytesMut::new();
+        EventCodec
+            .encode(Event::MessageSend("queued".to_string()), &mut buf)
+            .unwrap();
+        let queued = buf.clone();
+
+        let too_long = Event::MessageReceived(1, "bob".to_string(), "x".repeat(MAX_FRAME));
+        let err = EventCodec.encode(too_long, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(buf, queued);
     }
 }
@@ -0,0 +1,427 @@
+// A small JSON over HTTP API for managing a running server. It is meant to be
+// reached from the same machine only, and every request has to carry the
+// admin token from the config as `Authorization: Bearer <token>`.
+//
+//   GET  /users       everyone connected, with addresses and timings
+//   POST /kick        {"name": "bob", "reason": "..."} or {"id": 3, ...}
+//   POST /announce    {"text": "..."}
+//   POST /unpin       {"name": "bob"} lets the next key proven as bob take
+//                     the name, throwing away what was waiting for it
+//   POST /reload      reads the config file again
+//
+// A reload takes effect straight away for everything but the addresses the
+// server listens on, the ban file and federation, which need a restart.
+
+use std::io;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::net::{TcpListener, TcpStream};
+
+use rtalk_codec::Event;
+
+use crate::config::Config;
+use crate::http::{self, Request};
+use crate::session::Session;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UserDetails {
+    pub(crate) id: u64,
+    // `None` until the connection has joined
+    pub(crate) name: Option<String>,
+    pub(crate) ip: SocketAddr,
+    pub(crate) connected_secs: u64,
+    pub(crate) idle_secs: u64,
+    pub(crate) operator: bool,
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    name: Option<String>,
+    id: Option<u64>,
+    #[serde(default = "default_reason")]
+    reason: String,
+}
+
+fn default_reason() -> String {
+    "no reason given".to_string()
+}
+
+#[derive(Deserialize)]
+struct AnnounceRequest {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct UnpinRequest {
+    name: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct Admin {
+    pub(crate) session: Session,
+    // where to reload the config from
+    pub(crate) config_path: Option<String>,
+}
+
+struct Response {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Response {
+    fn ok(body: serde_json::Value) -> Self {
+        Response {
+            status: "200 OK",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Response {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+pub(crate) async fn serve(listener: TcpListener, admin: Admin) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admin.respond(socket).await {
+                tracing::debug!(error = %err, "admin request failed");
+            }
+        });
+    }
+}
+
+impl Admin {
+    async fn respond(&self, mut socket: TcpStream) -> io::Result<()> {
+        let request = match http::read_request(&mut socket).await? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        let response = if self.authorized(&request) {
+            self.handle(&request).await
+        } else {
+            Response::error("401 Unauthorized", "missing or wrong admin token")
+        };
+
+        let body = response.body.to_string();
+        http::respond(&mut socket, response.status, "application/json", &body).await
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let token = request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // the token is looked up each time so that a reload can change it
+        match (token, self.session.admin_token()) {
+            (Some(token), Some(expected)) => same(token.as_bytes(), expected.as_bytes()),
+            _ => false,
+        }
+    }
+
+    async fn handle(&self, request: &Request) -> Response {
+        tracing::info!(method = %request.method, path = %request.path, "admin request");
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/users") => Response::ok(json!(self.session.user_details())),
+            ("POST", "/kick") => match serde_json::from_slice(&request.body) {
+                Ok(kick) => self.kick(kick).await,
+                Err(err) => Response::error("400 Bad Request", &err.to_string()),
+            },
+            ("POST", "/announce") => match serde_json::from_slice(&request.body) {
+                Ok(AnnounceRequest { text }) => {
+                    let notice = format!("Announcement: {}", text);
+                    self.session
+                        .broadcast(|| Event::Notice(notice.clone()))
+                        .await;
+                    Response::ok(json!({}))
+                }
+                Err(err) => Response::error("400 Bad Request", &err.to_string()),
+            },
+            ("POST", "/unpin") => match serde_json::from_slice(&request.body) {
+                Ok(UnpinRequest { name }) => {
+                    if self
+                        .session
+                        .mailboxes(|mailboxes, _| mailboxes.forget(&name))
+                    {
+                        tracing::info!(name = %name, "mailbox unpinned");
+                        Response::ok(json!({}))
+                    } else {
+                        Response::error("404 Not Found", "no key is pinned to that name")
+                    }
+                }
+                Err(err) => Response::error("400 Bad Request", &err.to_string()),
+            },
+            ("POST", "/reload") => self.reload(),
+            _ => Response::error("404 Not Found", "no such endpoint"),
+        }
+    }
+
+    async fn kick(&self, kick: KickRequest) -> Response {
+        let (target, ids) = match (kick.name, kick.id) {
+            (Some(name), _) => {
+                let ids = self.session.users_named(&name);
+                (name, ids)
+            }
+            (None, Some(id)) => {
+                let connected = self.session.user_details().iter().any(|user| user.id == id);
+                let ids = if connected { vec![id] } else { Vec::new() };
+                (format!("user {}", id), ids)
+            }
+            (None, None) => return Response::error("400 Bad Request", "give a name or an id"),
+        };
+
+        if ids.is_empty() {
+            return Response::error("404 Not Found", "no such user");
+        }
+
+        let notice = format!("{} was kicked by the admin: {}", target, kick.reason);
+        self.session
+            .broadcast(|| Event::Notice(notice.clone()))
+            .await;
+        for id in &ids {
+            let reason = format!("You were kicked by the admin: {}", kick.reason);
+            self.session.disconnect(*id, reason).await;
+        }
+
+        Response::ok(json!({ "kicked": ids.len() }))
+    }
+
+    fn reload(&self) -> Response {
+        let path = match &self.config_path {
+            Some(path) => path,
+            None => return Response::error("409 Conflict", "the server has no config file"),
+        };
+
+        match Config::load(path) {
+            // taking the token away would lock the admin API out for good
+            Ok(config) if !has_token(&config) => {
+                Response::error("400 Bad Request", "the new config has no admin_token")
+            }
+            Ok(config) => {
+                self.session.set_config(config);
+                tracing::info!(path = %path, "config reloaded");
+                Response::ok(json!({}))
+            }
+            Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+        }
+    }
+}
+
+pub(crate) fn has_token(config: &Config) -> bool {
+    config
+        .admin_token
+        .as_ref()
+        .is_some_and(|token| !token.is_empty())
+}
+
+// Compares without bailing out at the first difference, so that how long a
+// guess takes to reject says nothing about how close it was.
+pub(crate) fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::testing::{self, TestClient};
+    use crate::Server;
+
+    const TOKEN: &str = "sesame";
+
+    async fn start(config_path: Option<String>) -> (Server, SocketAddr) {
+        let config = Config {
+            admin_token: Some(TOKEN.to_string()),
+            ..testing::config()
+        };
+        let mut builder = Server::builder().config(config);
+        if let Some(path) = config_path {
+            builder = builder.config_path(&path);
+        }
+        let server = builder.build().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let admin = server.clone();
+        tokio::spawn(async move { admin.serve_admin(listener).await });
+
+        (server, addr)
+    }
+
+    async fn call(
+        addr: SocketAddr,
+        token: &str,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, Value) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        let (_server, addr) = start(None).await;
+
+        let (status, _) = call(addr, "guess", "GET", "/users", "").await;
+        assert_eq!(status, 401);
+
+        let (status, users) = call(addr, TOKEN, "GET", "/users", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(users, json!([]));
+    }
+
+    #[tokio::test]
+    async fn users_can_be_listed_told_and_kicked() {
+        let (server, addr) = start(None).await;
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        let (_, users) = call(addr, TOKEN, "GET", "/users", "").await;
+        assert_eq!(users[0]["name"], "alice");
+        assert_eq!(users[0]["ip"], alice.peer.to_string());
+
+        let (status, _) = call(
+            addr,
+            TOKEN,
+            "POST",
+            "/announce",
+            r#"{"text":"restart at noon"}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        alice
+            .recv_until(|e| matches!(e, Event::Notice(text) if text.contains("restart at noon")))
+            .await;
+
+        let (status, _) = call(addr, TOKEN, "POST", "/kick", r#"{"name":"nobody"}"#).await;
+        assert_eq!(status, 404);
+
+        let (status, kicked) = call(
+            addr,
+            TOKEN,
+            "POST",
+            "/kick",
+            r#"{"name":"alice","reason":"bye"}"#,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(kicked["kicked"], 1);
+        match alice.recv_disconnect().await {
+            Some(Event::Disconnect(reason)) => assert!(reason.contains("bye")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn names_can_be_unpinned() {
+        let (server, addr) = start(None).await;
+        let mut alice = TestClient::join(&server, "alice").await;
+        alice.prove_key([1; 32]).await;
+        alice.send(Event::Leave()).await;
+        alice.recv_disconnect().await;
+
+        // someone who has lost their key can't take their name back...
+        let mut again = TestClient::join(&server, "alice").await;
+        again.prove_key([2; 32]).await;
+        again
+            .recv_until(|e| matches!(e, Event::Warning(w) if w.starts_with("Someone else has used the name alice")))
+            .await;
+
+        // ...until the admin lets it go
+        let body = r#"{"name":"alice"}"#;
+        let (status, _) = call(addr, TOKEN, "POST", "/unpin", body).await;
+        assert_eq!(status, 200);
+        let (status, _) = call(addr, TOKEN, "POST", "/unpin", body).await;
+        assert_eq!(status, 404);
+
+        let key = again.prove_key([2; 32]).await;
+        again.send(Event::Leave()).await;
+        again.recv_disconnect().await;
+
+        // the new key is the one handed out for mail now
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.send(Event::KeyRequest("alice".to_string())).await;
+        let pinned = Event::PublicKey("alice".to_string(), key);
+        bob.recv_until(|e| *e == pinned).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_requests_are_cut_off() {
+        let (_server, addr) = start(None).await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"GET /users HTTP/1.1\r\n").await.unwrap();
+
+        // the rest of the request never comes, so the server hangs up
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn config_can_be_reloaded() {
+        let path = std::env::temp_dir().join(format!("rtalk-admin-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "operators = {}\n").unwrap();
+
+        // a config that would lock the admin API out is turned down
+        let (server, addr) = start(Some(path.clone())).await;
+        let (status, _) = call(addr, TOKEN, "POST", "/reload", "").await;
+        assert_eq!(status, 400);
+
+        let carols_key = &testing::operator_config("carol").operators["carol"];
+        let config = format!(
+            "admin_token = \"open sesame\"\noperators = {{ carol = \"{}\" }}\n",
+            carols_key
+        );
+        fs::write(&path, config).unwrap();
+        let (status, _) = call(addr, TOKEN, "POST", "/reload", "").await;
+        assert_eq!(status, 200);
+
+        // the new token is the one that works now
+        let (status, _) = call(addr, TOKEN, "GET", "/users", "").await;
+        assert_eq!(status, 401);
+        let (status, _) = call(addr, "open sesame", "GET", "/users", "").await;
+        assert_eq!(status, 200);
+
+        // carol is an operator now, so kicking someone who isn't there is
+        // answered with that rather than a telling off
+        let mut carol = TestClient::join(&server, "carol").await;
+        carol.prove_key(testing::OPERATOR_KEY).await;
+        carol
+            .send(Event::Kick("nobody".to_string(), "test".to_string()))
+            .await;
+        match carol.recv_until(|e| matches!(e, Event::Warning(_))).await {
+            Event::Warning(warning) => assert!(warning.contains("no one called nobody")),
+            _ => unreachable!(),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
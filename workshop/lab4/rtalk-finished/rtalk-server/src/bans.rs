@@ -0,0 +1,188 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    // either a user name or an IP address
+    pub target: String,
+    // seconds since the Unix epoch; `None` means the ban never runs out
+    pub until: Option<u64>,
+    pub reason: String,
+    pub by: String,
+}
+
+impl Ban {
+    pub fn new(target: String, secs: u64, reason: String, by: String) -> Self {
+        let until = if secs == 0 {
+            None
+        } else {
+            Some(now_secs().saturating_add(secs))
+        };
+
+        Ban {
+            target,
+            until,
+            reason,
+            by,
+        }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.target.parse().ok()
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.until.is_some_and(|until| until <= now)
+    }
+
+    pub fn describe(&self) -> String {
+        let how_long = match self.until {
+            None => "permanently".to_string(),
+            Some(until) => {
+                let mins = until.saturating_sub(now_secs()).div_ceil(60);
+                format!("for another {} minute(s)", mins)
+            }
+        };
+
+        format!("banned {} by {}: {}", how_long, self.by, self.reason)
+    }
+}
+
+// The list of bans, written back to disk whenever it changes so that it
+// survives a restart.
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    pub fn load(path: Option<&str>) -> io::Result<Self> {
+        let path = path.map(PathBuf::from);
+        let bans = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text)
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?,
+                Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err),
+            },
+            None => Vec::new(),
+        };
+
+        Ok(BanList { path, bans })
+    }
+
+    // Adds a ban, replacing any earlier one for the same target.
+    pub fn add(&mut self, ban: Ban) -> io::Result<()> {
+        self.bans.retain(|other| other.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    pub fn remove(&mut self, target: &str) -> io::Result<bool> {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.target != target);
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn find_ip(&mut self, ip: IpAddr) -> Option<&Ban> {
+        self.prune();
+        self.bans.iter().find(|ban| ban.ip() == Some(ip))
+    }
+
+    pub fn find_name(&mut self, name: &str) -> Option<&Ban> {
+        self.prune();
+        self.bans.iter().find(|ban| ban.target == name)
+    }
+
+    fn prune(&mut self) {
+        let now = now_secs();
+        self.bans.retain(|ban| !ban.expired(now));
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let text = serde_json::to_string_pretty(&self.bans)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        fs::write(path, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_persist_across_loads() {
+        let path = std::env::temp_dir().join(format!("rtalk-bans-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut bans = BanList::load(Some(path)).unwrap();
+        bans.add(Ban::new(
+            "10.1.2.3".to_string(),
+            0,
+            "spam".to_string(),
+            "op".to_string(),
+        ))
+        .unwrap();
+        bans.add(Ban::new(
+            "mallory".to_string(),
+            600,
+            "rude".to_string(),
+            "op".to_string(),
+        ))
+        .unwrap();
+
+        let mut bans = BanList::load(Some(path)).unwrap();
+        assert!(bans.find_ip("10.1.2.3".parse().unwrap()).is_some());
+        assert!(bans.find_ip("10.1.2.4".parse().unwrap()).is_none());
+        assert!(bans.find_name("mallory").is_some());
+
+        assert!(bans.remove("mallory").unwrap());
+        let mut bans = BanList::load(Some(path)).unwrap();
+        assert!(bans.find_name("mallory").is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn expired_bans_are_ignored() {
+        let mut bans = BanList::load(None).unwrap();
+        let mut ban = Ban::new("10.0.0.1".to_string(), 60, String::new(), String::new());
+        ban.until = Some(now_secs() - 1);
+        bans.add(ban).unwrap();
+
+        assert!(bans.find_ip("10.0.0.1".parse().unwrap()).is_none());
+
+        // as long as anyone could ask for is still a ban
+        let ban = Ban::new(
+            "10.0.0.2".to_string(),
+            u64::MAX,
+            String::new(),
+            String::new(),
+        );
+        assert_eq!(ban.until, Some(u64::MAX));
+        bans.add(ban).unwrap();
+        assert!(bans.find_ip("10.0.0.2".parse().unwrap()).is_some());
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen: String,
+    // where to accept WebSocket connections, if at all
+    pub ws_listen: Option<String>,
+    // where to accept QUIC connections, if at all. QUIC needs a certificate
+    // for the server: `quic_cert` and `quic_key` are PEM files with the
+    // certificate chain and its private key.
+    pub quic_listen: Option<String>,
+    pub quic_cert: Option<String>,
+    pub quic_key: Option<String>,
+    // the path of a Unix domain socket to accept connections on as well, if
+    // any. Users connecting there can leave their name for their account's.
+    pub unix_listen: Option<String>,
+    // where to serve metrics for Prometheus, if at all
+    pub metrics_listen: Option<String>,
+    // where to serve the admin API, if at all; this has to be a loopback
+    // address and needs `admin_token` set as well
+    pub admin_listen: Option<String>,
+    pub admin_token: Option<String>,
+    // users joining under one of these names are made operators once they
+    // have proven they hold the key with the fingerprint it maps to
+    pub operators: HashMap<String, String>,
+    // the longest a chat message can be, in bytes. Messages go out with who
+    // sent them attached, so nothing much over 1 MiB less 64 KiB can be sent
+    // whatever this says.
+    pub max_message_len: usize,
+    // where bans are kept between runs; bans only last until the server
+    // stops when this isn't set
+    pub ban_file: Option<String>,
+    pub log_format: LogFormat,
+    pub rate_limit: RateLimitConfig,
+    pub files: FileConfig,
+    pub mailbox: MailboxConfig,
+    pub federation: FederationConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "127.0.0.1:3215".to_string(),
+            ws_listen: None,
+            quic_listen: None,
+            quic_cert: None,
+            quic_key: None,
+            unix_listen: None,
+            metrics_listen: None,
+            admin_listen: None,
+            admin_token: None,
+            operators: HashMap::new(),
+            max_message_len: 16 * 1024,
+            ban_file: Some("rtalk-bans.json".to_string()),
+            log_format: LogFormat::Pretty,
+            rate_limit: RateLimitConfig::default(),
+            files: FileConfig::default(),
+            mailbox: MailboxConfig::default(),
+            federation: FederationConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // human readable, for a terminal
+    Pretty,
+    // one JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ViolationAction {
+    Mute,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // events a single connection may send in a burst, and how quickly that
+    // allowance comes back
+    pub burst: u32,
+    pub refill_per_sec: f64,
+
+    // the same, shared by every connection from one IP address
+    pub ip_burst: u32,
+    pub ip_refill_per_sec: f64,
+
+    // how many times a connection can hit the limit within
+    // `violation_window_secs` before `action` is taken against it
+    pub max_violations: u32,
+    pub violation_window_secs: u64,
+    pub action: ViolationAction,
+    pub mute_secs: u64,
+
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            burst: 10,
+            refill_per_sec: 2.0,
+            ip_burst: 30,
+            ip_refill_per_sec: 6.0,
+            max_violations: 5,
+            violation_window_secs: 60,
+            action: ViolationAction::Mute,
+            mute_secs: 60,
+            max_connections_per_ip: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FileConfig {
+    // the biggest file anyone can send
+    pub max_file_size: u64,
+    // how many bytes of files a connection can send in all
+    pub quota: u64,
+    // how many transfers a user can have under way at once, sending or
+    // receiving
+    pub max_transfers: usize,
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        FileConfig {
+            max_file_size: 100 * 1024 * 1024,
+            quota: 1024 * 1024 * 1024,
+            max_transfers: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MailboxConfig {
+    // how long messages for people who aren't around are kept for them, and
+    // how long a name stays pinned to its key after its owner was last seen
+    pub retention_secs: u64,
+    // how many messages can be waiting for one person
+    pub max_messages: usize,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig {
+            retention_secs: 7 * 24 * 60 * 60,
+            max_messages: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    // what other servers know this one as; `listen` is used when it isn't
+    // set, and no two linked servers can have the same one
+    pub node_id: Option<String>,
+    // where to accept links from other servers, if at all
+    pub listen: Option<String>,
+    // servers to link to, which are dialled again whenever a link drops
+    pub peers: Vec<String>,
+    // every server in the federation has to be given the same secret
+    pub secret: Option<String>,
+}
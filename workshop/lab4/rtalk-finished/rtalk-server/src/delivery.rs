@@ -0,0 +1,170 @@
+use tracing::debug;
+
+use rtalk_codec::Event;
+
+use crate::session::{Session, Tracked};
+
+// Passes on a tracked message from user `id` and lets them know how it went.
+// Clients send a message again when they don't hear back in time, so one that
+// has already gone out is only acknowledged again. Clients that give an ID
+// get the same once they have connected again.
+pub(crate) async fn handle(session: &Session, id: u64, event: Event) {
+    let (seq, msg) = match event {
+        Event::TrackedSend(seq, msg) => (seq, msg),
+        Event::ClientId(client) => {
+            session.set_client(id, client);
+            return;
+        }
+        _ => unreachable!(),
+    };
+
+    match session.track(id, seq) {
+        Tracked::New => {}
+        // the answer goes to whichever connection sent it first; if that
+        // has gone, the client asks again
+        Tracked::Sending => return,
+        Tracked::Sent(message_id) => {
+            debug!(seq, "message sent again");
+            session
+                .send_event(id, Event::MessageAck(seq, message_id))
+                .await;
+            return;
+        }
+    }
+
+    let answer = match session.chat(id, msg).await {
+        Ok(message_id) => {
+            session.remember_ack(id, seq, Some(message_id));
+            Event::MessageAck(seq, message_id)
+        }
+        Err(reason) => {
+            session.remember_ack(id, seq, None);
+            let reason = reason.unwrap_or_else(|| "Your message was not sent.".to_string());
+            Event::MessageFailed(seq, reason)
+        }
+    };
+    session.send_event(id, answer).await;
+}
+
+pub(crate) fn is_tracked(event: &Event) -> bool {
+    matches!(event, Event::TrackedSend(_, _) | Event::ClientId(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{self, TestClient};
+    use crate::Server;
+    use crate::{async_trait, ChatUser, PluginContext, PluginResult, ServerPlugin, Verdict};
+
+    struct NoShouting;
+
+    #[async_trait]
+    impl ServerPlugin for NoShouting {
+        fn name(&self) -> &str {
+            "no-shouting"
+        }
+
+        async fn on_message(
+            &self,
+            _ctx: &PluginContext,
+            _from: &ChatUser,
+            message: String,
+        ) -> PluginResult<Verdict> {
+            if message.chars().any(char::is_lowercase) {
+                Ok(Verdict::Allow(message))
+            } else {
+                Ok(Verdict::Veto(None))
+            }
+        }
+    }
+
+    fn server() -> Server {
+        Server::builder()
+            .config(testing::config())
+            .plugin(NoShouting)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_sent_again_go_out_once() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        // the first acknowledgement got lost, so alice tries again
+        alice.send(Event::TrackedSend(1, "hi".to_string())).await;
+        alice.send(Event::TrackedSend(1, "hi".to_string())).await;
+        let first = alice
+            .recv_until(|e| matches!(e, Event::MessageAck(..)))
+            .await;
+        let second = alice
+            .recv_until(|e| matches!(e, Event::MessageAck(..)))
+            .await;
+        assert_eq!(first, second);
+
+        alice
+            .send(Event::TrackedSend(2, "anyone?".to_string()))
+            .await;
+        let said = |e: &Event| matches!(e, Event::MessageReceived(..));
+        match (bob.recv_until(said).await, bob.recv_until(said).await) {
+            (Event::MessageReceived(id, _, hi), Event::MessageReceived(_, _, next)) => {
+                assert_eq!(first, Event::MessageAck(1, id));
+                assert_eq!(hi, "hi");
+                assert_eq!(next, "anyone?");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_sent_again_after_reconnecting_go_out_once() {
+        let server = server();
+        let mut bob = TestClient::join(&server, "bob").await;
+        let said = |e: &Event| matches!(e, Event::MessageReceived(..));
+
+        // alice's connection drops before the acknowledgement gets to them
+        let mut alice = TestClient::join(&server, "alice").await;
+        alice.send(Event::ClientId(7)).await;
+        alice.send(Event::TrackedSend(1, "hi".to_string())).await;
+        let id = match bob.recv_until(said).await {
+            Event::MessageReceived(id, _, _) => id,
+            _ => unreachable!(),
+        };
+        drop(alice);
+
+        // so they send it again once they're back
+        let mut alice = TestClient::join(&server, "alice").await;
+        alice.send(Event::ClientId(7)).await;
+        alice.send(Event::TrackedSend(1, "hi".to_string())).await;
+        alice.recv_until(|e| *e == Event::MessageAck(1, id)).await;
+
+        // someone else's client doesn't get to use alice's numbers
+        let mut carol = TestClient::join(&server, "carol").await;
+        carol.send(Event::TrackedSend(1, "hello".to_string())).await;
+        match bob.recv_until(said).await {
+            Event::MessageReceived(_, _, text) => assert_eq!(text, "hello"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn turned_down_messages_fail() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        alice.send(Event::TrackedSend(1, "HELLO".to_string())).await;
+        match alice
+            .recv_until(|e| matches!(e, Event::MessageFailed(..) | Event::MessageAck(..)))
+            .await
+        {
+            Event::MessageFailed(seq, reason) => {
+                assert_eq!(seq, 1);
+                assert!(reason.contains("not sent"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
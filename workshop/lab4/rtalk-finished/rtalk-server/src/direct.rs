@@ -0,0 +1,234 @@
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use rtalk_codec::Event;
+
+use crate::mailbox;
+use crate::session::Session;
+
+// X25519 public keys are always this long.
+const KEY_LEN: usize = 32;
+
+// Mixed into key proofs so that they can't be mistaken for anything else
+// made from the same shared secret.
+const PROOF_CONTEXT: &[u8] = b"rtalk key proof v1";
+
+// Direct messages are for chat, not for getting files past the quotas.
+const MAX_CIPHERTEXT: usize = 16 * 1024;
+
+// Looks after the directory of public keys and passes encrypted direct
+// messages on. Only the two ends of a conversation have the keys to read it,
+// so plugins never get to see these either. Messages for someone who isn't
+// connected wait in their mailbox, sealed with the last key proven under
+// their name.
+pub async fn handle(session: &Session, id: u64, event: Event) {
+    match event {
+        Event::PublishKey(key) => {
+            if key.len() != KEY_LEN {
+                let warning = "That is not a public key.".to_string();
+                session.send_event(id, Event::Warning(warning)).await;
+                return;
+            }
+
+            // the key is handed out straight away, but only someone who can
+            // show they hold its secret half gets what was left for them
+            let challenge = StaticSecret::random_from_rng(rand::rngs::OsRng);
+            let public = PublicKey::from(&challenge);
+            session.set_public_key(id, key, challenge);
+            let challenge = Event::KeyChallenge(public.as_bytes().to_vec());
+            session.send_event(id, challenge).await;
+        }
+
+        Event::KeyProof(proof) => {
+            if session.prove_key(id, &proof) {
+                mailbox::claim(session, id, true).await;
+            } else {
+                let warning = "That key could not be proven.".to_string();
+                session.send_event(id, Event::Warning(warning)).await;
+            }
+        }
+
+        Event::KeyRequest(name) => {
+            if let Some((_, who, key)) = session.public_key_of(&name) {
+                session.send_event(id, Event::PublicKey(who, key)).await;
+            } else if let Some(key) = remembered_key(session, &name) {
+                session.send_event(id, Event::PublicKey(name, key)).await;
+            } else {
+                no_key(session, id, &name).await;
+            }
+        }
+
+        Event::EncryptedSend(to, nonce, ciphertext) => {
+            if ciphertext.len() > MAX_CIPHERTEXT {
+                let warning = "That message is too long.".to_string();
+                session.send_event(id, Event::Warning(warning)).await;
+                return;
+            }
+
+            let key = match session.public_key(id) {
+                Some(key) => key,
+                None => {
+                    let warning = "Publish your key before sending direct messages.".to_string();
+                    session.send_event(id, Event::Warning(warning)).await;
+                    return;
+                }
+            };
+            let len = ciphertext.len();
+            let from = session.get_name(id);
+            let message = Event::EncryptedReceived(from, key, nonce, ciphertext);
+
+            match session.public_key_of(&to) {
+                Some((recipient, _, _)) => {
+                    tracing::debug!(len, "direct message passed on");
+                    session.send_event(recipient, message).await;
+                }
+                None => leave_message(session, id, &to, message).await,
+            }
+        }
+
+        _ => unreachable!(),
+    }
+}
+
+pub fn is_direct(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::PublishKey(_) | Event::KeyProof(_) | Event::KeyRequest(_) | Event::EncryptedSend(..)
+    )
+}
+
+// What proves that whoever holds one of these keys has the secret half of the
+// other; the same from either end. `None` for keys that make a proof
+// meaningless.
+pub(crate) fn key_proof(secret: &StaticSecret, key: &[u8]) -> Option<Vec<u8>> {
+    let key: [u8; KEY_LEN] = key.try_into().ok()?;
+    let shared = secret.diffie_hellman(&PublicKey::from(key));
+    if !shared.was_contributory() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(PROOF_CONTEXT);
+    hasher.update(shared.as_bytes());
+    Some(hasher.finalize().to_vec())
+}
+
+// A short form of `key` for people to compare, the same as clients show.
+pub(crate) fn fingerprint(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The key someone published before they went. Anyone called `name` who is
+// still here just hasn't published one.
+fn remembered_key(session: &Session, name: &str) -> Option<Vec<u8>> {
+    if !session.users_named(name).is_empty() {
+        return None;
+    }
+    session.mailboxes(|mailboxes, config| mailboxes.key_of(config, name))
+}
+
+// Keeps a message for someone who has gone, as long as they proved a key for
+// it to be sealed with while they were here.
+async fn leave_message(session: &Session, id: u64, to: &str, message: Event) {
+    if remembered_key(session, to).is_none() {
+        no_key(session, id, to).await;
+        return;
+    }
+
+    let sender = session.bare_name(id);
+    let posted = session.mailboxes(|mailboxes, config| mailboxes.post(config, to, sender, message));
+    let reply = match posted {
+        Some(letter) => Event::Queued(letter, to.to_string()),
+        None => Event::Warning(format!("{}'s mailbox is full.", to)),
+    };
+    session.send_event(id, reply).await;
+}
+
+async fn no_key(session: &Session, id: u64, name: &str) {
+    let warning = format!("No one called {} has published a key.", name);
+    session.send_event(id, Event::Warning(warning)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{server, TestClient};
+
+    async fn warning(client: &mut TestClient) -> String {
+        match client.recv_until(|e| matches!(e, Event::Warning(_))).await {
+            Event::Warning(warning) => warning,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_passed_on_untouched() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        alice.send(Event::PublishKey(vec![1; 32])).await;
+        bob.send(Event::PublishKey(vec![2; 32])).await;
+        // bob's key is only there to ask for once the server has taken it
+        bob.recv_until(|e| matches!(e, Event::KeyChallenge(_)))
+            .await;
+
+        alice.send(Event::KeyRequest("bob".to_string())).await;
+        match alice
+            .recv_until(|e| matches!(e, Event::PublicKey(..)))
+            .await
+        {
+            Event::PublicKey(who, key) => {
+                assert!(who.starts_with("bob "));
+                assert_eq!(key, vec![2; 32]);
+            }
+            _ => unreachable!(),
+        }
+
+        alice
+            .send(Event::EncryptedSend(
+                "bob".to_string(),
+                vec![3; 12],
+                vec![4, 5, 6],
+            ))
+            .await;
+        match bob
+            .recv_until(|e| matches!(e, Event::EncryptedReceived(..)))
+            .await
+        {
+            Event::EncryptedReceived(from, key, nonce, ciphertext) => {
+                assert!(from.starts_with("alice "));
+                assert_eq!(key, vec![1; 32]);
+                assert_eq!(nonce, vec![3; 12]);
+                assert_eq!(ciphertext, vec![4, 5, 6]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_messages_need_keys_at_both_ends() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let _bob = TestClient::join(&server, "bob").await;
+
+        alice.send(Event::PublishKey(vec![1; 5])).await;
+        assert!(warning(&mut alice).await.contains("not a public key"));
+
+        let message = Event::EncryptedSend("bob".to_string(), vec![3; 12], vec![4]);
+        alice.send(message.clone()).await;
+        assert!(warning(&mut alice).await.contains("Publish your key"));
+
+        alice.send(Event::PublishKey(vec![1; 32])).await;
+        alice.send(message).await;
+        assert!(warning(&mut alice).await.contains("No one called bob"));
+    }
+}
@@ -0,0 +1,805 @@
+// Links between servers, so that one chat can be spread over several of
+// them. Each server tells the others who is on it and passes on what happens
+// there; users only ever talk to their own server.
+//
+// Links speak JSON, one message per line. Anything a server hears over one
+// link it passes on over all of its others, so servers don't all have to be
+// linked to each other. That means the same message can turn up more than
+// once, which is what the origin IDs are for.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::{select, Sink, Stream};
+use futures_util::{future::FutureExt, sink::SinkExt};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, LinesCodec, LinesCodecError};
+use tracing::{debug, info};
+
+use rtalk_codec::{Event, Presence, UserInfo};
+
+use crate::admin;
+use crate::mentions;
+use crate::session::Session;
+
+// Far more than any one message needs; member lists are the biggest.
+const MAX_LINE: usize = 1024 * 1024;
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CHALLENGE_SIZE: usize = 32;
+
+// How many origin IDs are remembered for spotting repeats. Copies of a
+// message arrive moments apart, so this only has to cover a short while.
+const SEEN_LIMIT: usize = 10_000;
+
+// Identifies one message from one server. `boot` is picked afresh every time
+// a server starts so that its sequence numbers starting over again aren't
+// taken for repeats.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct OriginId {
+    node: String,
+    boot: u64,
+    seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Envelope {
+    origin: OriginId,
+    body: Relayed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Relayed {
+    // everyone on each server the sender knows of, its own included
+    Members(Vec<(String, Vec<UserInfo>)>),
+    // servers the sender has lost touch with
+    Gone(Vec<String>),
+    // the rest happened on the origin server
+    Joined(String),
+    Left(String),
+    // who, text
+    Message(String, String),
+    Presence(String, Presence, Option<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+enum LinkMessage {
+    // the first thing both ends send: who they are, and random bytes for the
+    // other end to prove it knows the secret with
+    Hello { node: String, challenge: Vec<u8> },
+    // the answer to both challenges
+    Proof(Vec<u8>),
+    Relay(Envelope),
+}
+
+// Which end of a link a server is at. The server that dialled the other one
+// has to prove itself first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Role {
+    Initiator,
+    Responder,
+}
+
+#[derive(Default)]
+struct Links {
+    seq: u64,
+    counter: u64,
+    // the server at the other end of each link, and what to send down it
+    links: HashMap<u64, (String, UnboundedSender<Envelope>)>,
+    seen: HashSet<OriginId>,
+    seen_order: VecDeque<OriginId>,
+    // the users on every other server we know of
+    members: BTreeMap<String, Vec<UserInfo>>,
+    // which link we hear from each server over
+    via: HashMap<String, u64>,
+}
+
+impl Links {
+    fn remember(&mut self, origin: OriginId) {
+        if self.seen_order.len() >= SEEN_LIMIT {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(origin.clone());
+        self.seen_order.push_back(origin);
+    }
+
+    // A link of our own straight to `node`, if there is one.
+    fn direct(&self, node: &str) -> Option<u64> {
+        self.links
+            .iter()
+            .find(|(_, (peer, _))| peer == node)
+            .map(|(id, _)| *id)
+    }
+}
+
+pub(crate) struct Federation {
+    node: String,
+    boot: u64,
+    state: Mutex<Links>,
+}
+
+impl Federation {
+    pub(crate) fn new(node: String) -> Self {
+        Federation {
+            node,
+            boot: RandomState::new().build_hasher().finish(),
+            state: Mutex::new(Links::default()),
+        }
+    }
+
+    // Everyone on the other servers.
+    pub(crate) fn users(&self) -> Vec<UserInfo> {
+        let links = self.state.lock().unwrap();
+        links.members.values().flatten().cloned().collect()
+    }
+
+    // Gives something that happened here an origin ID.
+    fn stamp(&self, body: Relayed) -> Envelope {
+        let mut links = self.state.lock().unwrap();
+        links.seq += 1;
+        let origin = OriginId {
+            node: self.node.clone(),
+            boot: self.boot,
+            seq: links.seq,
+        };
+        links.remember(origin.clone());
+        Envelope { origin, body }
+    }
+
+    // Sends down every link but the one the envelope came in on.
+    fn send(&self, envelope: &Envelope, except: Option<u64>) {
+        let links = self.state.lock().unwrap();
+        for (id, (_, sender)) in &links.links {
+            if Some(*id) != except {
+                let _ = sender.send(envelope.clone());
+            }
+        }
+    }
+
+    fn add_link(&self, node: &str) -> (u64, UnboundedReceiver<Envelope>) {
+        let mut links = self.state.lock().unwrap();
+        links.counter += 1;
+        let id = links.counter;
+
+        let (sender, rx) = mpsc::unbounded_channel();
+        links.links.insert(id, (node.to_string(), sender));
+        links.via.insert(node.to_string(), id);
+        (id, rx)
+    }
+
+    // Closes a link and forgets the servers we heard from over it, returning
+    // them along with their users. Servers we have another link to are kept.
+    fn remove_link(&self, id: u64) -> Vec<(String, Vec<UserInfo>)> {
+        let mut links = self.state.lock().unwrap();
+        links.links.remove(&id);
+
+        let nodes = links
+            .via
+            .iter()
+            .filter(|(_, via)| **via == id)
+            .map(|(node, _)| node.clone())
+            .collect::<Vec<_>>();
+
+        let mut gone = Vec::new();
+        for node in nodes {
+            match links.direct(&node) {
+                Some(other) => {
+                    links.via.insert(node, other);
+                }
+                None => {
+                    links.via.remove(&node);
+                    let users = links.members.remove(&node).unwrap_or_default();
+                    gone.push((node, users));
+                }
+            }
+        }
+        gone
+    }
+
+    // Whether this is the first copy of a message to reach us.
+    fn first_sight(&self, link: u64, origin: &OriginId) -> bool {
+        let mut links = self.state.lock().unwrap();
+        if origin.node == self.node || links.seen.contains(origin) {
+            return false;
+        }
+
+        links.remember(origin.clone());
+        links.via.entry(origin.node.clone()).or_insert(link);
+        true
+    }
+
+    // Replaces what we know of the users on `node`, returning the names of
+    // those who have arrived and those who have gone.
+    fn set_members(
+        &self,
+        link: u64,
+        node: String,
+        users: Vec<UserInfo>,
+    ) -> (Vec<String>, Vec<String>) {
+        if node == self.node {
+            return (Vec::new(), Vec::new());
+        }
+
+        let mut links = self.state.lock().unwrap();
+        links.via.entry(node.clone()).or_insert(link);
+        let old = links
+            .members
+            .insert(node, users.clone())
+            .unwrap_or_default();
+
+        let joined = users
+            .iter()
+            .filter(|user| !old.iter().any(|known| known.name == user.name))
+            .map(|user| user.name.clone())
+            .collect();
+        let left = old
+            .iter()
+            .filter(|known| !users.iter().any(|user| user.name == known.name))
+            .map(|known| known.name.clone())
+            .collect();
+        (joined, left)
+    }
+
+    // Forgets servers that another server has lost touch with, unless we can
+    // still reach them ourselves. Returns the names of the users that went
+    // with them, and whether any of the servers were kept.
+    fn forget(&self, nodes: &[String]) -> (Vec<String>, bool) {
+        let mut links = self.state.lock().unwrap();
+        let mut left = Vec::new();
+        let mut kept = false;
+
+        for node in nodes {
+            if *node == self.node || links.direct(node).is_some() {
+                kept = true;
+                continue;
+            }
+
+            links.via.remove(node);
+            let users = links.members.remove(node).unwrap_or_default();
+            left.extend(users.into_iter().map(|user| user.name));
+        }
+        (left, kept)
+    }
+
+    // Returns false if we already knew of them.
+    fn add_member(&self, node: &str, name: &str) -> bool {
+        let mut links = self.state.lock().unwrap();
+        let users = links.members.entry(node.to_string()).or_default();
+        if users.iter().any(|user| user.name == name) {
+            return false;
+        }
+
+        users.push(UserInfo {
+            name: name.to_string(),
+            connected_secs: 0,
+            idle_secs: 0,
+            presence: Presence::Online,
+            status: None,
+        });
+        true
+    }
+
+    // Returns false if we didn't know of them.
+    fn remove_member(&self, node: &str, name: &str) -> bool {
+        let mut links = self.state.lock().unwrap();
+        match links.members.get_mut(node) {
+            Some(users) => {
+                let before = users.len();
+                users.retain(|user| user.name != name);
+                users.len() != before
+            }
+            None => false,
+        }
+    }
+
+    fn set_presence(&self, node: &str, name: &str, presence: Presence, status: Option<String>) {
+        let mut links = self.state.lock().unwrap();
+        let user = links
+            .members
+            .get_mut(node)
+            .and_then(|users| users.iter_mut().find(|user| user.name == name));
+        if let Some(user) = user {
+            user.presence = presence;
+            user.status = status;
+        }
+    }
+}
+
+// Passes on something that happened here to every server we're linked to.
+pub(crate) fn relay(session: &Session, body: Relayed) {
+    let federation = session.federation();
+    let envelope = federation.stamp(body);
+    federation.send(&envelope, None);
+}
+
+// Runs a link to another server over `transport` until either end hangs up.
+// Both ends have to have been given the same secret.
+pub(crate) async fn link<T>(
+    session: Session,
+    secret: String,
+    role: Role,
+    transport: T,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let federation = session.federation();
+    let framed = LinesCodec::new_with_max_length(MAX_LINE).framed(transport);
+    let (mut sink, mut stream) = futures::StreamExt::split(framed);
+
+    let proved = handshake(&mut sink, &mut stream, role, &federation.node, &secret);
+    let peer = match timeout(HELLO_TIMEOUT, proved).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the other server didn't say hello in time",
+            ))
+        }
+    };
+
+    let (id, mut outgoing) = federation.add_link(&peer);
+    info!(peer = %peer, "federation link up");
+
+    let result: io::Result<()> = async {
+        // bring the other server up to date with everyone we know of
+        let members = federation.stamp(members(&session));
+        send(&mut sink, &LinkMessage::Relay(members)).await?;
+
+        loop {
+            select! {
+                envelope = outgoing.recv().fuse() => match envelope {
+                    Some(envelope) => send(&mut sink, &LinkMessage::Relay(envelope)).await?,
+                    None => return Ok(()),
+                },
+                message = recv(&mut stream).fuse() => match message? {
+                    Some(LinkMessage::Relay(envelope)) => deliver(&session, id, envelope).await,
+                    Some(_) => debug!(peer = %peer, "ignoring a second handshake"),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+
+    let gone = federation.remove_link(id);
+    info!(peer = %peer, "federation link down");
+    if !gone.is_empty() {
+        let nodes = gone.iter().map(|(node, _)| node.clone()).collect();
+        let left = gone
+            .into_iter()
+            .flat_map(|(_, users)| users)
+            .map(|user| user.name)
+            .collect();
+        announce(&session, Vec::new(), left).await;
+
+        // the servers further along may only have been reachable through us
+        relay(&session, Relayed::Gone(nodes));
+    }
+
+    result
+}
+
+// Makes sure the server at the other end has the same secret as us, without
+// either end ever sending it, and returns its node ID. Each end challenges the
+// other, and each proof covers both challenges and both node IDs, so a proof
+// given on one link can't be passed off on another. The responder only
+// proves itself once the initiator has, so connecting to a server doesn't
+// get anyone an answer to a challenge of their choosing.
+async fn handshake<Si, St>(
+    sink: &mut Si,
+    stream: &mut St,
+    role: Role,
+    node: &str,
+    secret: &str,
+) -> io::Result<String>
+where
+    Si: Sink<String, Error = LinesCodecError> + Unpin,
+    St: Stream<Item = Result<String, LinesCodecError>> + Unpin,
+{
+    let mut challenge = vec![0; CHALLENGE_SIZE];
+    OsRng.fill_bytes(&mut challenge);
+    let hello = LinkMessage::Hello {
+        node: node.to_string(),
+        challenge: challenge.clone(),
+    };
+
+    if role == Role::Initiator {
+        send(sink, &hello).await?;
+    }
+    let (peer, theirs) = match recv(stream).await? {
+        Some(LinkMessage::Hello { node, challenge }) => (node, challenge),
+        _ => return Err(invalid("the other server didn't say hello")),
+    };
+    if peer == node {
+        return Err(invalid("the other server has our node ID"));
+    }
+    if role == Role::Responder {
+        send(sink, &hello).await?;
+    }
+
+    let ours = (node, challenge.as_slice());
+    let (initiator, responder) = match role {
+        Role::Initiator => (ours, (peer.as_str(), theirs.as_slice())),
+        Role::Responder => ((peer.as_str(), theirs.as_slice()), ours),
+    };
+    let ours = answer(secret, role, initiator, responder);
+    let expected = match role {
+        Role::Initiator => answer(secret, Role::Responder, initiator, responder),
+        Role::Responder => answer(secret, Role::Initiator, initiator, responder),
+    };
+
+    if role == Role::Initiator {
+        send(sink, &LinkMessage::Proof(ours.clone())).await?;
+    }
+    match recv(stream).await? {
+        Some(LinkMessage::Proof(proof)) if admin::same(&proof, &expected) => {}
+        Some(LinkMessage::Proof(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the other server has a different secret",
+            ))
+        }
+        _ => return Err(invalid("the other server didn't answer our challenge")),
+    }
+    if role == Role::Responder {
+        send(sink, &LinkMessage::Proof(ours)).await?;
+    }
+    Ok(peer)
+}
+
+// What the server at `role`'s end of a link proves itself with: a MAC of
+// who is at each end and their challenges, keyed with the secret.
+fn answer(secret: &str, role: Role, initiator: (&str, &[u8]), responder: (&str, &[u8])) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(match role {
+        Role::Initiator => b"initiator",
+        Role::Responder => b"responder",
+    });
+    for part in &[
+        initiator.0.as_bytes(),
+        initiator.1,
+        responder.0.as_bytes(),
+        responder.1,
+    ] {
+        // lengths first, so that no two transcripts run together the same
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn members(session: &Session) -> Relayed {
+    let federation = session.federation();
+    let mut nodes = vec![(federation.node.clone(), session.local_users())];
+    let links = federation.state.lock().unwrap();
+    nodes.extend(
+        links
+            .members
+            .iter()
+            .map(|(node, users)| (node.clone(), users.clone())),
+    );
+    Relayed::Members(nodes)
+}
+
+// Acts on a message from another server and passes it on, unless it has been
+// through here before.
+async fn deliver(session: &Session, link: u64, envelope: Envelope) {
+    let federation = session.federation();
+    if !federation.first_sight(link, &envelope.origin) {
+        return;
+    }
+    federation.send(&envelope, Some(link));
+
+    let node = envelope.origin.node;
+    match envelope.body {
+        Relayed::Members(nodes) => {
+            for (node, users) in nodes {
+                let (joined, left) = federation.set_members(link, node, users);
+                announce(session, joined, left).await;
+            }
+        }
+        Relayed::Gone(nodes) => {
+            let (left, kept) = federation.forget(&nodes);
+            announce(session, Vec::new(), left).await;
+
+            // the sender can find the servers it lost through us instead
+            if kept {
+                relay(session, members(session));
+            }
+        }
+        Relayed::Joined(name) => {
+            if federation.add_member(&node, &name) {
+                session.broadcast(|| Event::Joined(name.clone())).await;
+            }
+        }
+        Relayed::Left(name) => {
+            if federation.remove_member(&node, &name) {
+                session.broadcast(|| Event::Left(name.clone())).await;
+            }
+        }
+        Relayed::Message(who, text) => {
+            // the message gets an ID here like any other, so reactions to it
+            // stay on this server
+            let everyone = session.user_ids();
+            if let Some(message_id) = session.post_message(everyone, None, &who, &text).await {
+                mentions::notify(session, None, message_id, &who, &text).await;
+            }
+        }
+        Relayed::Presence(who, presence, status) => {
+            federation.set_presence(&node, &who, presence, status.clone());
+            session
+                .broadcast(|| Event::PresenceChanged(who.clone(), presence, status.clone()))
+                .await;
+        }
+    }
+}
+
+async fn announce(session: &Session, joined: Vec<String>, left: Vec<String>) {
+    for name in left {
+        session.broadcast(|| Event::Left(name.clone())).await;
+    }
+    for name in joined {
+        session.broadcast(|| Event::Joined(name.clone())).await;
+    }
+}
+
+async fn send<S>(sink: &mut S, message: &LinkMessage) -> io::Result<()>
+where
+    S: Sink<String, Error = LinesCodecError> + Unpin,
+{
+    let line = serde_json::to_string(message)?;
+    sink.send(line).await.map_err(link_error)
+}
+
+async fn recv<S>(stream: &mut S) -> io::Result<Option<LinkMessage>>
+where
+    S: Stream<Item = Result<String, LinesCodecError>> + Unpin,
+{
+    match stream.next().await {
+        Some(Ok(line)) => Ok(Some(serde_json::from_str(&line)?)),
+        Some(Err(err)) => Err(link_error(err)),
+        None => Ok(None),
+    }
+}
+
+fn link_error(err: LinesCodecError) -> io::Error {
+    match err {
+        LinesCodecError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    use crate::testing::{self, TestClient};
+    use crate::Server;
+
+    fn server(node: &str, secret: &str) -> Server {
+        let mut config = testing::config();
+        config.federation.node_id = Some(node.to_string());
+        config.federation.secret = Some(secret.to_string());
+        testing::server_with(config)
+    }
+
+    // Links two servers over an in-memory pipe, `a` getting in touch with
+    // `b`, returning the tasks running each end.
+    fn connect(a: &Server, b: &Server) -> (JoinHandle<io::Result<()>>, JoinHandle<io::Result<()>>) {
+        let (a_end, b_end) = duplex(64 * 1024);
+        let (a, b) = (a.clone(), b.clone());
+        (
+            tokio::spawn(async move { a.link(a_end).await }),
+            tokio::spawn(async move { b.accept_link(b_end).await }),
+        )
+    }
+
+    fn joined(event: &Event, name: &str) -> bool {
+        matches!(event, Event::Joined(who) if who.starts_with(&format!("{} ", name)))
+    }
+
+    fn said(event: &Event, text: &str) -> bool {
+        matches!(event, Event::MessageReceived(_, _, msg) if msg == text)
+    }
+
+    #[tokio::test]
+    async fn users_on_linked_servers_chat_together() {
+        let north = server("north", "s3cret");
+        let south = server("south", "s3cret");
+        let _link = connect(&north, &south);
+
+        // alice may already be on north by the time the link comes up, in
+        // which case bob finds her in the user list rather than joining
+        let mut alice = TestClient::join(&north, "alice").await;
+        let mut bob = TestClient::join(&south, "bob").await;
+        alice.recv_until(|e| joined(e, "bob")).await;
+        assert_eq!(north.users().len(), 2);
+
+        alice
+            .send(Event::MessageSend("hi from the north".to_string()))
+            .await;
+        match bob.recv_until(|e| said(e, "hi from the north")).await {
+            Event::MessageReceived(_, who, _) => assert!(who.starts_with("alice ")),
+            _ => unreachable!(),
+        }
+        assert_eq!(south.users().len(), 2);
+
+        bob.send(Event::MessageSend("hi from the south".to_string()))
+            .await;
+        match alice.recv_until(|e| said(e, "hi from the south")).await {
+            Event::MessageReceived(_, who, _) => assert!(who.starts_with("bob ")),
+            _ => unreachable!(),
+        }
+
+        bob.send(Event::SetPresence(
+            Presence::Away,
+            Some("lunch".to_string()),
+        ))
+        .await;
+        alice
+            .recv_until(|e| matches!(e, Event::PresenceChanged(who, Presence::Away, _) if who.starts_with("bob ")))
+            .await;
+
+        drop(bob);
+        alice
+            .recv_until(|e| matches!(e, Event::Left(who) if who.starts_with("bob ")))
+            .await;
+        assert_eq!(north.users().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_going_round_a_loop_arrive_once() {
+        let a = server("a", "s3cret");
+        let b = server("b", "s3cret");
+        let c = server("c", "s3cret");
+        let _links = (connect(&a, &b), connect(&b, &c), connect(&c, &a));
+
+        let mut alice = TestClient::join(&a, "alice").await;
+        let mut carol = TestClient::join(&c, "carol").await;
+        alice.recv_until(|e| joined(e, "carol")).await;
+
+        carol.send(Event::MessageSend("one".to_string())).await;
+        carol.send(Event::MessageSend("two".to_string())).await;
+
+        let is_message = |e: &Event| matches!(e, Event::MessageReceived(..));
+        assert!(said(&alice.recv_until(is_message).await, "one"));
+        assert!(said(&alice.recv_until(is_message).await, "two"));
+    }
+
+    #[tokio::test]
+    async fn servers_that_drop_out_take_their_users_with_them() {
+        let a = server("a", "s3cret");
+        let b = server("b", "s3cret");
+        let c = server("c", "s3cret");
+        let _ab = connect(&a, &b);
+        let (_, c_end) = connect(&b, &c);
+
+        // carol's server is only linked to alice's through the one in the
+        // middle
+        let mut alice = TestClient::join(&a, "alice").await;
+        let _carol = TestClient::join(&c, "carol").await;
+        alice.recv_until(|e| joined(e, "carol")).await;
+
+        c_end.abort();
+        alice
+            .recv_until(|e| matches!(e, Event::Left(who) if who.starts_with("carol ")))
+            .await;
+        assert_eq!(a.users().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn servers_need_the_same_secret() {
+        let north = server("north", "s3cret");
+        let south = server("south", "guess");
+        let (a, b) = connect(&north, &south);
+
+        // south is the one to check north's proof, and never gives its own
+        let err = b.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(a.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn challenges_cant_be_passed_on_to_another_server() {
+        let north = server("north", "s3cret");
+        let south = server("south", "s3cret");
+
+        // mallory gets in touch with north, claiming to be south
+        let (north_end, mallory_end) = duplex(64 * 1024);
+        let accepting = north.clone();
+        let north_link = tokio::spawn(async move { accepting.accept_link(north_end).await });
+        let mut to_north = LinesCodec::new().framed(mallory_end);
+        let hello = LinkMessage::Hello {
+            node: "south".to_string(),
+            challenge: vec![7; CHALLENGE_SIZE],
+        };
+        send(&mut to_north, &hello).await.unwrap();
+        let challenge = match recv(&mut to_north).await.unwrap() {
+            Some(LinkMessage::Hello { challenge, .. }) => challenge,
+            _ => panic!("north didn't say hello"),
+        };
+
+        // and passes north's challenge on to the real south, hoping it
+        // answers it
+        let (south_end, mallory_end) = duplex(64 * 1024);
+        let accepting = south.clone();
+        let south_link = tokio::spawn(async move { accepting.accept_link(south_end).await });
+        let mut to_south = LinesCodec::new().framed(mallory_end);
+        let hello = LinkMessage::Hello {
+            node: "mallory".to_string(),
+            challenge,
+        };
+        send(&mut to_south, &hello).await.unwrap();
+        assert!(matches!(
+            recv(&mut to_south).await.unwrap(),
+            Some(LinkMessage::Hello { .. })
+        ));
+
+        // south wants a proof from mallory first, so there is nothing to
+        // pass back to north but a guess
+        send(&mut to_south, &LinkMessage::Proof(vec![0; 32]))
+            .await
+            .unwrap();
+        assert!(recv(&mut to_south).await.unwrap().is_none());
+        assert!(south_link.await.unwrap().is_err());
+
+        send(&mut to_north, &LinkMessage::Proof(vec![0; 32]))
+            .await
+            .unwrap();
+        let err = north_link.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(north.users().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_secret_never_goes_over_the_wire() {
+        let north = server("north", "s3cret");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = north.clone();
+        tokio::spawn(async move { accepting.serve_federation(listener).await });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"{\"Hello\":{\"node\":\"mallory\",\"challenge\":[1,2,3]}}\n")
+            .await
+            .unwrap();
+        socket.write_all(b"{\"Proof\":[1,2,3]}\n").await.unwrap();
+
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(2), socket.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.contains("Hello"));
+        assert!(!received.contains("s3cret"));
+        // and nothing else until the other end has proved itself
+        assert!(!received.contains("Relay"));
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+
+use rtalk_codec::Event;
+
+use crate::config::FileConfig;
+use crate::session::Session;
+
+// Chunks are passed on one at a time in between everything else, so keeping
+// them small keeps chat moving while a big file goes through.
+const MAX_CHUNK: usize = 64 * 1024;
+
+const MAX_NAME_LEN: usize = 255;
+
+struct Transfer {
+    sender: u64,
+    recipient: u64,
+    size: u64,
+    // where the next chunk has to start; `None` until the recipient accepts
+    next_offset: Option<u64>,
+}
+
+// Every transfer under way. Files are never stored on the server, it only
+// keeps track of how far along each transfer is so that it can check the
+// chunks going through.
+#[derive(Default)]
+pub(crate) struct Transfers {
+    active: HashMap<u64, Transfer>,
+    // bytes each user has sent so far, counted against their quota
+    sent: HashMap<u64, u64>,
+}
+
+impl Transfers {
+    fn under_way(&self, id: u64) -> usize {
+        self.active
+            .values()
+            .filter(|transfer| transfer.sender == id || transfer.recipient == id)
+            .count()
+    }
+
+    fn offer(
+        &mut self,
+        limits: &FileConfig,
+        id: u64,
+        transfer_id: u64,
+        recipient: u64,
+        size: u64,
+    ) -> Result<(), String> {
+        if self.active.contains_key(&transfer_id) {
+            return Err("That transfer ID is already in use, pick another.".to_string());
+        }
+        if size > limits.max_file_size {
+            return Err(format!(
+                "Files can be at most {} bytes.",
+                limits.max_file_size
+            ));
+        }
+
+        let sent = self.sent.get(&id).copied().unwrap_or(0);
+        if sent + size > limits.quota {
+            return Err("That would take you over your file quota.".to_string());
+        }
+        if self.under_way(id) >= limits.max_transfers
+            || self.under_way(recipient) >= limits.max_transfers
+        {
+            return Err("There are too many transfers under way, try again later.".to_string());
+        }
+
+        self.active.insert(
+            transfer_id,
+            Transfer {
+                sender: id,
+                recipient,
+                size,
+                next_offset: None,
+            },
+        );
+        Ok(())
+    }
+
+    // Returns the sender, who needs to be told where to start from.
+    fn accept(&mut self, id: u64, transfer_id: u64, offset: u64) -> Result<u64, String> {
+        let transfer = match self.active.get_mut(&transfer_id) {
+            Some(transfer) if transfer.recipient == id && transfer.next_offset.is_none() => {
+                transfer
+            }
+            _ => return Err(no_such_transfer(transfer_id)),
+        };
+
+        if offset > transfer.size {
+            return Err("You can't resume past the end of the file.".to_string());
+        }
+
+        transfer.next_offset = Some(offset);
+        Ok(transfer.sender)
+    }
+
+    // Whether user `id` is sending `transfer_id` and the recipient has
+    // accepted it.
+    pub(crate) fn is_sending(&self, id: u64, transfer_id: u64) -> bool {
+        self.active
+            .get(&transfer_id)
+            .is_some_and(|transfer| transfer.sender == id && transfer.next_offset.is_some())
+    }
+
+    // Checks that a chunk carries on from where the last one left off and
+    // returns who it is for.
+    fn chunk(
+        &mut self,
+        limits: &FileConfig,
+        id: u64,
+        transfer_id: u64,
+        offset: u64,
+        len: usize,
+    ) -> Result<u64, String> {
+        let transfer = match self.active.get_mut(&transfer_id) {
+            Some(transfer) if transfer.sender == id => transfer,
+            _ => return Err(no_such_transfer(transfer_id)),
+        };
+
+        if transfer.next_offset != Some(offset) {
+            return Err("That chunk is out of order.".to_string());
+        }
+        // empty chunks take nothing off the quota, so they would be a free
+        // way of flooding the recipient
+        if len == 0 {
+            return Err("Chunks can't be empty.".to_string());
+        }
+        if len > MAX_CHUNK {
+            return Err(format!("Chunks can be at most {} bytes.", MAX_CHUNK));
+        }
+        if offset + len as u64 > transfer.size {
+            return Err("That is more than the size of the file.".to_string());
+        }
+
+        let sent = self.sent.entry(id).or_insert(0);
+        if *sent + len as u64 > limits.quota {
+            return Err("You are over your file quota.".to_string());
+        }
+
+        *sent += len as u64;
+        transfer.next_offset = Some(offset + len as u64);
+        Ok(transfer.recipient)
+    }
+
+    // Returns the recipient if the whole file has been sent.
+    fn complete(&mut self, id: u64, transfer_id: u64) -> Result<u64, String> {
+        match self.active.get(&transfer_id) {
+            Some(transfer) if transfer.sender == id => {
+                if transfer.next_offset != Some(transfer.size) {
+                    return Err("The file hasn't all been sent yet.".to_string());
+                }
+            }
+            _ => return Err(no_such_transfer(transfer_id)),
+        }
+
+        let transfer = self.active.remove(&transfer_id).unwrap();
+        Ok(transfer.recipient)
+    }
+
+    // Calls off a transfer that user `id` is part of, returning whoever is
+    // on the other end.
+    fn cancel(&mut self, id: u64, transfer_id: u64) -> Option<u64> {
+        let transfer = self.active.get(&transfer_id)?;
+        let other = if transfer.sender == id {
+            transfer.recipient
+        } else if transfer.recipient == id {
+            transfer.sender
+        } else {
+            return None;
+        };
+
+        self.active.remove(&transfer_id);
+        Some(other)
+    }
+
+    // Calls off everything user `id` is part of, returning the transfers
+    // along with whoever was on the other end of each.
+    fn abandon(&mut self, id: u64) -> Vec<(u64, u64)> {
+        self.sent.remove(&id);
+
+        let transfer_ids = self
+            .active
+            .iter()
+            .filter(|(_, transfer)| transfer.sender == id || transfer.recipient == id)
+            .map(|(transfer_id, _)| *transfer_id)
+            .collect::<Vec<_>>();
+
+        transfer_ids
+            .into_iter()
+            .filter_map(|transfer_id| Some((transfer_id, self.cancel(id, transfer_id)?)))
+            .collect()
+    }
+}
+
+// Passes file transfer events from user `id` on to the other side of the
+// transfer, as long as they stay within the limits.
+pub async fn handle(session: &Session, id: u64, event: Event) {
+    match event {
+        Event::FileOffer(transfer_id, to, name, size, sha256) => {
+            let recipient = match offer(session, id, transfer_id, &to, &name, size, &sha256) {
+                Ok(recipient) => recipient,
+                Err(reason) => {
+                    session
+                        .send_event(id, Event::FileReject(transfer_id, reason))
+                        .await;
+                    return;
+                }
+            };
+
+            let from = session.get_name(id);
+            session
+                .send_event(
+                    recipient,
+                    Event::FileOffered(transfer_id, from, name, size, sha256),
+                )
+                .await;
+        }
+
+        Event::FileAccept(transfer_id, offset) => {
+            match session.transfers(|transfers, _| transfers.accept(id, transfer_id, offset)) {
+                Ok(sender) => {
+                    session
+                        .send_event(sender, Event::FileAccept(transfer_id, offset))
+                        .await;
+                }
+                Err(warning) => session.send_event(id, Event::Warning(warning)).await,
+            }
+        }
+
+        Event::FileReject(transfer_id, reason) => {
+            if let Some(other) = session.transfers(|transfers, _| transfers.cancel(id, transfer_id))
+            {
+                session
+                    .send_event(other, Event::FileReject(transfer_id, reason))
+                    .await;
+            }
+        }
+
+        Event::FileChunk(transfer_id, offset, data) => {
+            let checked = session.transfers(|transfers, limits| {
+                transfers.chunk(limits, id, transfer_id, offset, data.len())
+            });
+            match checked {
+                Ok(recipient) => {
+                    session
+                        .send_event(recipient, Event::FileChunk(transfer_id, offset, data))
+                        .await;
+                }
+                Err(reason) => fail(session, id, transfer_id, reason).await,
+            }
+        }
+
+        Event::FileComplete(transfer_id) => {
+            match session.transfers(|transfers, _| transfers.complete(id, transfer_id)) {
+                Ok(recipient) => {
+                    session
+                        .send_event(recipient, Event::FileComplete(transfer_id))
+                        .await;
+                }
+                Err(reason) => fail(session, id, transfer_id, reason).await,
+            }
+        }
+
+        _ => unreachable!(),
+    }
+}
+
+pub fn is_file_transfer(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::FileOffer(..)
+            | Event::FileAccept(..)
+            | Event::FileReject(..)
+            | Event::FileChunk(..)
+            | Event::FileComplete(..)
+    )
+}
+
+// Calls off every transfer a user who is leaving was part of.
+pub(crate) async fn abandon(session: &Session, id: u64) {
+    let abandoned = session.transfers(|transfers, _| transfers.abandon(id));
+    for (transfer_id, other) in abandoned {
+        let reason = "The other side of the transfer has gone away.".to_string();
+        session
+            .send_event(other, Event::FileReject(transfer_id, reason))
+            .await;
+    }
+}
+
+// Starts a transfer if everything about the offer checks out, returning who
+// it is for.
+fn offer(
+    session: &Session,
+    id: u64,
+    transfer_id: u64,
+    to: &str,
+    name: &str,
+    size: u64,
+    sha256: &str,
+) -> Result<u64, String> {
+    if !valid_name(name) {
+        return Err(format!("{} is not a file name that can be sent.", name));
+    }
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("The checksum has to be a SHA-256 in hex.".to_string());
+    }
+
+    let recipient = match session.users_named(to).first() {
+        Some(recipient) if *recipient != id => *recipient,
+        Some(_) => return Err("You can't send files to yourself.".to_string()),
+        None => return Err(format!("There is no one called {}.", to)),
+    };
+
+    session
+        .transfers(|transfers, limits| transfers.offer(limits, id, transfer_id, recipient, size))?;
+    Ok(recipient)
+}
+
+// Recipients save files under the name they were offered with, so it has to
+// be a plain name that can't point anywhere else.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+}
+
+// Gives up on a transfer that went wrong, telling both sides.
+async fn fail(session: &Session, id: u64, transfer_id: u64, reason: String) {
+    if let Some(other) = session.transfers(|transfers, _| transfers.cancel(id, transfer_id)) {
+        session
+            .send_event(other, Event::FileReject(transfer_id, reason.clone()))
+            .await;
+    }
+    session
+        .send_event(id, Event::FileReject(transfer_id, reason))
+        .await;
+}
+
+fn no_such_transfer(transfer_id: u64) -> String {
+    format!("There is no transfer {} of yours.", transfer_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{self, TestClient};
+    use crate::{Config, Server};
+
+    fn server(files: FileConfig) -> Server {
+        testing::server_with(Config {
+            files,
+            ..testing::config()
+        })
+    }
+
+    fn sha256() -> String {
+        "0f".repeat(32)
+    }
+
+    // Offers bob a file from alice and waits for the offer to arrive.
+    async fn offer(alice: &mut TestClient, bob: &mut TestClient, transfer_id: u64, size: u64) {
+        alice
+            .send(Event::FileOffer(
+                transfer_id,
+                "bob".to_string(),
+                "notes.txt".to_string(),
+                size,
+                sha256(),
+            ))
+            .await;
+        match bob
+            .recv_until(|e| matches!(e, Event::FileOffered(..)))
+            .await
+        {
+            Event::FileOffered(offered_id, from, name, offered_size, _) => {
+                assert_eq!(offered_id, transfer_id);
+                assert!(from.starts_with("alice "));
+                assert_eq!(name, "notes.txt");
+                assert_eq!(offered_size, size);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    async fn rejected(client: &mut TestClient, transfer_id: u64) -> String {
+        match client
+            .recv_until(|e| matches!(e, Event::FileReject(id, _) if *id == transfer_id))
+            .await
+        {
+            Event::FileReject(_, reason) => reason,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn files_are_passed_on_in_between_chat() {
+        let server = server(FileConfig::default());
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        offer(&mut alice, &mut bob, 7, 6).await;
+        bob.send(Event::FileAccept(7, 0)).await;
+        alice.recv_until(|e| *e == Event::FileAccept(7, 0)).await;
+
+        alice.send(Event::FileChunk(7, 0, b"hel".to_vec())).await;
+        alice
+            .send(Event::MessageSend("notes coming up".to_string()))
+            .await;
+        alice.send(Event::FileChunk(7, 3, b"lo\n".to_vec())).await;
+        alice.send(Event::FileComplete(7)).await;
+
+        bob.recv_until(|e| *e == Event::FileChunk(7, 0, b"hel".to_vec()))
+            .await;
+        bob.recv_until(|e| matches!(e, Event::MessageReceived(..)))
+            .await;
+        bob.recv_until(|e| *e == Event::FileChunk(7, 3, b"lo\n".to_vec()))
+            .await;
+        bob.recv_until(|e| *e == Event::FileComplete(7)).await;
+    }
+
+    #[tokio::test]
+    async fn resumed_transfers_have_to_carry_on_where_they_left_off() {
+        let server = server(FileConfig::default());
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        offer(&mut alice, &mut bob, 7, 6).await;
+        bob.send(Event::FileAccept(7, 3)).await;
+        alice.recv_until(|e| *e == Event::FileAccept(7, 3)).await;
+
+        alice.send(Event::FileChunk(7, 0, b"hel".to_vec())).await;
+        assert!(rejected(&mut alice, 7).await.contains("out of order"));
+        assert!(rejected(&mut bob, 7).await.contains("out of order"));
+
+        // a transfer is called off when either side leaves
+        offer(&mut alice, &mut bob, 8, 6).await;
+        alice.send(Event::Leave()).await;
+        assert!(rejected(&mut bob, 8).await.contains("gone away"));
+    }
+
+    #[tokio::test]
+    async fn empty_chunks_are_refused() {
+        let server = server(FileConfig::default());
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        offer(&mut alice, &mut bob, 7, 6).await;
+        bob.send(Event::FileAccept(7, 0)).await;
+        alice.recv_until(|e| *e == Event::FileAccept(7, 0)).await;
+
+        for _ in 0..50 {
+            alice.send(Event::FileChunk(7, 0, Vec::new())).await;
+        }
+        assert!(rejected(&mut alice, 7).await.contains("empty"));
+        let event = bob
+            .recv_until(|e| matches!(e, Event::FileChunk(..) | Event::FileReject(..)))
+            .await;
+        assert!(matches!(event, Event::FileReject(7, reason) if reason.contains("empty")));
+
+        // the rest are for a transfer that is over, so they count against
+        // the rate limit like anything else
+        alice
+            .recv_until(|e| matches!(e, Event::Warning(text) if text.contains("slow down")))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn files_are_limited_in_size() {
+        let server = server(FileConfig {
+            max_file_size: 10,
+            quota: 15,
+            max_transfers: 4,
+        });
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        alice
+            .send(Event::FileOffer(
+                1,
+                "bob".to_string(),
+                "big.bin".to_string(),
+                11,
+                sha256(),
+            ))
+            .await;
+        assert!(rejected(&mut alice, 1).await.contains("at most 10 bytes"));
+
+        offer(&mut alice, &mut bob, 2, 10).await;
+        bob.send(Event::FileAccept(2, 0)).await;
+        alice.recv_until(|e| *e == Event::FileAccept(2, 0)).await;
+        alice.send(Event::FileChunk(2, 0, vec![0; 10])).await;
+        alice.send(Event::FileComplete(2)).await;
+        bob.recv_until(|e| *e == Event::FileComplete(2)).await;
+
+        alice
+            .send(Event::FileOffer(
+                3,
+                "bob".to_string(),
+                "more.bin".to_string(),
+                10,
+                sha256(),
+            ))
+            .await;
+        assert!(rejected(&mut alice, 3).await.contains("quota"));
+    }
+
+    #[test]
+    fn file_names_cannot_be_paths() {
+        assert!(valid_name("notes.txt"));
+        assert!(!valid_name("../notes.txt"));
+        assert!(!valid_name("C:\\notes.txt"));
+        assert!(!valid_name(".."));
+        assert!(!valid_name(""));
+    }
+}
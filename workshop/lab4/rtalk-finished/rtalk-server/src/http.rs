@@ -0,0 +1,118 @@
+// Just enough HTTP/1.1 for the metrics and admin endpoints: one request per
+// connection, no chunked bodies and no keep-alive.
+
+use std::io;
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// Requests bigger than this are not what we're expecting and get cut off.
+const MAX_REQUEST: usize = 64 * 1024;
+
+// Clients that take longer than this to send their request get hung up on,
+// so that they can't hold connections open.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Reads a request, or returns `None` if the client hung up, sent something
+// that isn't one or took too long about it.
+pub(crate) async fn read_request(socket: &mut TcpStream) -> io::Result<Option<Request>> {
+    timeout(READ_TIMEOUT, read(socket))
+        .await
+        .unwrap_or(Ok(None))
+}
+
+async fn read(socket: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_len = loop {
+        if let Some(at) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break at + 4;
+        }
+
+        let n = socket.read(&mut buf).await?;
+        if n == 0 || data.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_len]).into_owned();
+    let mut lines = head.lines();
+    let mut words = lines.next().unwrap_or("").split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(None),
+    };
+
+    let headers = lines
+        .filter_map(|line| {
+            let at = line.find(':')?;
+            Some((
+                line[..at].trim().to_string(),
+                line[at + 1..].trim().to_string(),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: data.split_off(head_len),
+    };
+
+    let length = match request.header("Content-Length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) if length <= MAX_REQUEST => length,
+            _ => return Ok(None),
+        },
+        None => 0,
+    };
+    while request.body.len() < length {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        request.body.extend_from_slice(&buf[..n]);
+    }
+    request.body.truncate(length);
+
+    Ok(Some(request))
+}
+
+pub(crate) async fn respond(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
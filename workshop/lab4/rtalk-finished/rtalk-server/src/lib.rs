@@ -0,0 +1,41 @@
+#![recursion_limit = "512"]
+
+mod admin;
+mod bans;
+mod config;
+mod delivery;
+mod direct;
+mod federation;
+mod files;
+mod http;
+mod logging;
+mod mailbox;
+mod mentions;
+mod messages;
+mod metrics;
+mod moderation;
+mod plugin;
+mod quic;
+mod rate_limit;
+mod server;
+mod session;
+// helpers for tests, here and in crates built on the server
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(unix)]
+mod unix;
+mod ws;
+
+pub use config::{
+    Config, FederationConfig, FileConfig, LogFormat, MailboxConfig, RateLimitConfig,
+    ViolationAction,
+};
+pub use logging::init_logging;
+pub use plugin::{ChatUser, PluginContext, PluginError, PluginResult, ServerPlugin, Verdict};
+pub use quic::bind_quic;
+pub use server::{Server, ServerBuilder};
+#[cfg(unix)]
+pub use unix::{bind_unix, SocketFile};
+
+// plugins need this to implement `ServerPlugin`
+pub use async_trait::async_trait;
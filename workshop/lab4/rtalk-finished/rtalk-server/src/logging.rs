@@ -0,0 +1,136 @@
+use std::io;
+
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogFormat;
+
+// Sends the server's logs to stdout in the given format. Which events make it
+// through is picked with RUST_LOG, defaulting to everything at info and above.
+pub fn init_logging(format: LogFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing::subscriber::set_global_default(subscriber(format, filter, io::stdout))?;
+    Ok(())
+}
+
+fn subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use rtalk_codec::Event;
+
+    use crate::testing::{server, TestClient};
+
+    // Collects everything logged so that tests can look through it.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Capture {
+        type Writer = Capture;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    impl Capture {
+        fn lines(&self) -> Vec<Value> {
+            let logs = self.0.lock().unwrap();
+            String::from_utf8_lossy(&logs)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+
+        // The first event with the given message, if there is one.
+        fn find(&self, message: &str) -> Option<Value> {
+            self.lines()
+                .into_iter()
+                .find(|line| line["message"] == message)
+        }
+    }
+
+    fn capture(logs: &Capture) -> tracing::subscriber::DefaultGuard {
+        let filter = EnvFilter::new("debug");
+        tracing::subscriber::set_default(subscriber(LogFormat::Json, filter, logs.clone()))
+    }
+
+    #[tokio::test]
+    async fn connection_events_are_logged_in_their_span() {
+        let logs = Capture::default();
+        let _guard = capture(&logs);
+
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        alice.send(Event::MessageSend("hi".to_string())).await;
+        alice
+            .recv_until(|e| matches!(e, Event::MessageReceived(..)))
+            .await;
+        alice.send(Event::Leave()).await;
+        alice.recv_disconnect().await;
+
+        let joined = logs.find("joined").expect("no join logged");
+        assert!(joined["name"].as_str().unwrap().starts_with("alice "));
+        assert_eq!(joined["span"]["name"], "connection");
+        assert_eq!(joined["span"]["peer"], alice.peer.to_string());
+        assert!(joined["span"]["user_id"].is_u64());
+
+        assert!(logs.find("message sent").is_some());
+        assert!(logs.find("left").is_some());
+    }
+
+    #[tokio::test]
+    async fn codec_errors_are_logged() {
+        let logs = Capture::default();
+        let _guard = capture(&logs);
+
+        let server = server();
+        let (mut client, server_end) = duplex(1024);
+        server.connect(server_end, SocketAddr::from(([127, 0, 0, 1], 9)));
+
+        // not even the magic cookie is right; wait for the server to hang up
+        client.write_all(&[0u8; 16]).await.unwrap();
+        let mut buf = [0u8; 16];
+        let _ = client.read(&mut buf).await;
+
+        let error = logs.find("codec error").expect("no codec error logged");
+        assert_eq!(error["level"], "WARN");
+        assert_eq!(error["kind"], "InvalidInput");
+    }
+}
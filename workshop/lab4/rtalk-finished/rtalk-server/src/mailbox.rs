@@ -0,0 +1,380 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use rtalk_codec::Event;
+
+use crate::config::MailboxConfig;
+use crate::session::Session;
+
+// Something waiting for someone to come back.
+struct Letter {
+    id: u64,
+    // who gets told once it has been handed over
+    sender: Option<String>,
+    posted: Instant,
+    event: Event,
+}
+
+// A key pinned to a name, and when its owner was last around.
+struct Pin {
+    key: Vec<u8>,
+    seen: Instant,
+}
+
+// Messages for people who aren't connected, by name. Names are only what
+// people choose to call themselves, so each mailbox belongs to the key first
+// proven under its name, and only whoever proves that key again can empty it.
+// A pin lasts as long as messages are kept after its owner was last seen, or
+// until the admin resets it, and each key only holds one name at a time.
+// They are only kept in memory, so a restart loses them.
+#[derive(Default)]
+pub(crate) struct Mailboxes {
+    counter: u64,
+    letters: HashMap<String, VecDeque<Letter>>,
+    // the key proven under each name, so that direct messages can be
+    // encrypted for people who have gone. Someone else taking the name can't
+    // put their own key in its place.
+    keys: HashMap<String, Pin>,
+}
+
+impl Mailboxes {
+    pub(crate) fn key_of(&mut self, config: &MailboxConfig, name: &str) -> Option<Vec<u8>> {
+        self.expire(config);
+        self.keys.get(name).map(|pin| pin.key.clone())
+    }
+
+    // Whether there is anyone to keep messages for `name` for.
+    pub(crate) fn knows(&mut self, config: &MailboxConfig, name: &str) -> bool {
+        self.expire(config);
+        self.keys.contains_key(name)
+    }
+
+    // Keeps `event` for `to`, returning its ID, or `None` if their mailbox
+    // is full.
+    pub(crate) fn post(
+        &mut self,
+        config: &MailboxConfig,
+        to: &str,
+        sender: Option<String>,
+        event: Event,
+    ) -> Option<u64> {
+        self.expire(config);
+
+        let letters = self.letters.entry(to.to_string()).or_default();
+        if letters.len() >= config.max_messages {
+            return None;
+        }
+
+        self.counter += 1;
+        letters.push_back(Letter {
+            id: self.counter,
+            sender,
+            posted: Instant::now(),
+            event,
+        });
+        Some(self.counter)
+    }
+
+    // Hands over what was left for `name` to whoever has proven `key`, as
+    // long as it is the key that goes with the name. Returns `None` if it
+    // isn't. A name no one holds is only pinned to `key` if `pin` is set,
+    // which moves the key off any other name it held.
+    fn claim(
+        &mut self,
+        config: &MailboxConfig,
+        name: &str,
+        key: &[u8],
+        pin: bool,
+    ) -> Option<Vec<Letter>> {
+        self.expire(config);
+
+        match self.keys.get_mut(name) {
+            Some(known) if known.key != key => return None,
+            Some(known) => known.seen = Instant::now(),
+            None if pin => {
+                let letters = &mut self.letters;
+                self.keys.retain(|name, known| {
+                    let other = known.key == key;
+                    if other {
+                        letters.remove(name);
+                    }
+                    !other
+                });
+                self.keys.insert(
+                    name.to_string(),
+                    Pin {
+                        key: key.to_vec(),
+                        seen: Instant::now(),
+                    },
+                );
+            }
+            None => {}
+        }
+
+        Some(self.letters.remove(name).map(Vec::from).unwrap_or_default())
+    }
+
+    // Keeps the pin on `name` for another `retention_secs` if `key` holds it.
+    fn seen(&mut self, name: &str, key: &[u8]) {
+        if let Some(known) = self.keys.get_mut(name) {
+            if known.key == key {
+                known.seen = Instant::now();
+            }
+        }
+    }
+
+    // Unpins `name` and throws away what was waiting for it, so that the
+    // next key proven under it takes it. Returns whether it was pinned.
+    pub(crate) fn forget(&mut self, name: &str) -> bool {
+        self.letters.remove(name);
+        self.keys.remove(name).is_some()
+    }
+
+    fn expire(&mut self, config: &MailboxConfig) {
+        let retention = Duration::from_secs(config.retention_secs);
+        self.keys.retain(|_, pin| pin.seen.elapsed() < retention);
+        for letters in self.letters.values_mut() {
+            letters.retain(|letter| letter.posted.elapsed() < retention);
+        }
+        let keys = &self.keys;
+        self.letters
+            .retain(|name, letters| !letters.is_empty() && keys.contains_key(name));
+    }
+}
+
+// Hands user `id` everything that was left under their name, and lets the
+// senders know, once they have proven their key. Their name is pinned to the
+// key if `pin` is set and no one holds it yet.
+pub(crate) async fn claim(session: &Session, id: u64, pin: bool) {
+    let (name, key) = match session.proven_key(id) {
+        Some(proven) => proven,
+        None => return,
+    };
+    let letters = session.mailboxes(|mailboxes, config| mailboxes.claim(config, &name, &key, pin));
+    let letters = match letters {
+        Some(letters) => letters,
+        None => {
+            let warning = format!(
+                "Someone else has used the name {} before, so what was left for them stays waiting.",
+                name
+            );
+            session.send_event(id, Event::Warning(warning)).await;
+            return;
+        }
+    };
+    if !letters.is_empty() {
+        tracing::debug!(count = letters.len(), "mailbox delivered");
+    }
+
+    for letter in letters {
+        session.send_event(id, letter.event).await;
+        if let Some(sender) = letter.sender {
+            let receipt = Event::Delivered(letter.id, name.clone());
+            send_or_post(session, &sender, receipt).await;
+        }
+    }
+}
+
+// Keeps the pin on user `id`'s name for as long as it would last from now,
+// as they are going.
+pub(crate) fn seen(session: &Session, id: u64) {
+    if let Some((name, key)) = session.proven_key(id) {
+        session.mailboxes(|mailboxes, _| mailboxes.seen(&name, &key));
+    }
+}
+
+// Sends `event` to everyone called `name`, or leaves it in their mailbox if
+// no one is and the name is pinned to someone.
+pub(crate) async fn send_or_post(session: &Session, name: &str, event: Event) {
+    let ids = session.users_named(name);
+    if ids.is_empty() {
+        session.mailboxes(|mailboxes, config| {
+            if mailboxes.knows(config, name) {
+                mailboxes.post(config, name, None, event);
+            }
+        });
+        return;
+    }
+
+    session.broadcast_to(ids, || event.clone()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use crate::testing::{config, server, server_with, TestClient};
+    use crate::Server;
+
+    const ALICE: [u8; 32] = [1; 32];
+    const BOB: [u8; 32] = [2; 32];
+    const MALLORY: [u8; 32] = [3; 32];
+
+    fn key(secret: [u8; 32]) -> Vec<u8> {
+        PublicKey::from(&StaticSecret::from(secret))
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn left(event: &Event, name: &str) -> bool {
+        matches!(event, Event::Left(who) if who.starts_with(&format!("{} ", name)))
+    }
+
+    // Has bob publish a key and go, then alice send bob a direct message.
+    // Returns alice and the message's mailbox ID.
+    async fn leave_a_message(server: &Server) -> (TestClient, u64) {
+        let mut alice = TestClient::join(server, "alice").await;
+        let mut bob = TestClient::join(server, "bob").await;
+        bob.prove_key(BOB).await;
+        bob.send(Event::Leave()).await;
+        alice.recv_until(|e| left(e, "bob")).await;
+
+        // bob's key is still handed out so that messages can be sealed for
+        // when they come back
+        alice.prove_key(ALICE).await;
+        alice.send(Event::KeyRequest("bob".to_string())).await;
+        let bobs_key = Event::PublicKey("bob".to_string(), key(BOB));
+        alice.recv_until(|e| *e == bobs_key).await;
+
+        alice
+            .send(Event::EncryptedSend(
+                "bob".to_string(),
+                vec![3; 12],
+                vec![4, 5, 6],
+            ))
+            .await;
+        match alice.recv_until(|e| matches!(e, Event::Queued(..))).await {
+            Event::Queued(id, to) => {
+                assert_eq!(to, "bob");
+                (alice, id)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_wait_for_people_to_come_back() {
+        let server = server();
+        let (mut alice, id) = leave_a_message(&server).await;
+
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key(BOB).await;
+        match bob
+            .recv_until(|e| matches!(e, Event::EncryptedReceived(..)))
+            .await
+        {
+            Event::EncryptedReceived(from, sender_key, _, ciphertext) => {
+                assert!(from.starts_with("alice "));
+                assert_eq!(sender_key, key(ALICE));
+                assert_eq!(ciphertext, vec![4, 5, 6]);
+            }
+            _ => unreachable!(),
+        }
+
+        let receipt = Event::Delivered(id, "bob".to_string());
+        alice.recv_until(|e| *e == receipt).await;
+    }
+
+    #[tokio::test]
+    async fn receipts_wait_for_senders_that_have_gone() {
+        let server = server();
+        let (mut alice, id) = leave_a_message(&server).await;
+        let mut carol = TestClient::join(&server, "carol").await;
+        alice.send(Event::Leave()).await;
+        carol.recv_until(|e| left(e, "alice")).await;
+
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key(BOB).await;
+        bob.recv_until(|e| matches!(e, Event::EncryptedReceived(..)))
+            .await;
+
+        let mut alice = TestClient::join(&server, "alice").await;
+        alice.prove_key(ALICE).await;
+        let receipt = Event::Delivered(id, "bob".to_string());
+        alice.recv_until(|e| *e == receipt).await;
+    }
+
+    #[tokio::test]
+    async fn old_messages_are_thrown_away() {
+        let mut config = config();
+        config.mailbox.retention_secs = 1;
+        let server = server_with(config);
+        let (mut alice, _) = leave_a_message(&server).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key(BOB).await;
+        alice.send(Event::MessageSend("anyone?".to_string())).await;
+        let next = bob
+            .recv_until(|e| matches!(e, Event::EncryptedReceived(..) | Event::MessageReceived(..)))
+            .await;
+        assert!(matches!(next, Event::MessageReceived(..)));
+    }
+
+    #[tokio::test]
+    async fn taking_someones_name_doesnt_get_you_their_mail() {
+        let server = server();
+        let (mut alice, _) = leave_a_message(&server).await;
+
+        // mallory can change their name to bob's...
+        let mut mallory = TestClient::join(&server, "mallory").await;
+        mallory.prove_key(MALLORY).await;
+        mallory.send(Event::RequestJoin("bob".to_string())).await;
+        mallory
+            .recv_until(|e| matches!(e, Event::Warning(w) if w.starts_with("Someone else has used the name bob")))
+            .await;
+
+        // ...and publish bob's key, but can't prove it's theirs
+        mallory.send(Event::PublishKey(key(BOB))).await;
+        mallory
+            .recv_until(|e| matches!(e, Event::KeyChallenge(_)))
+            .await;
+        mallory.send(Event::KeyProof(vec![0; 32])).await;
+        mallory
+            .recv_until(|e| matches!(e, Event::Warning(w) if w.contains("could not be proven")))
+            .await;
+        mallory.send(Event::Leave()).await;
+        alice.recv_until(|e| left(e, "bob")).await;
+
+        // bob's key is still the one handed out, and the mail is still there
+        alice.send(Event::KeyRequest("bob".to_string())).await;
+        let bobs_key = Event::PublicKey("bob".to_string(), key(BOB));
+        alice.recv_until(|e| *e == bobs_key).await;
+
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key(BOB).await;
+        bob.recv_until(|e| matches!(e, Event::EncryptedReceived(..)))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn only_proving_a_key_pins_a_name() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut mallory = TestClient::join(&server, "mallory").await;
+        mallory.prove_key(MALLORY).await;
+        for name in ["carol", "dave"] {
+            mallory.send(Event::RequestJoin(name.to_string())).await;
+            alice
+                .recv_until(|e| matches!(e, Event::Joined(who) if who.starts_with(name)))
+                .await;
+        }
+        // proving the key again as dave moves it off mallory
+        mallory.prove_key(MALLORY).await;
+        mallory.send(Event::Leave()).await;
+        alice.recv_until(|e| left(e, "dave")).await;
+
+        for name in ["mallory", "carol"] {
+            alice.send(Event::KeyRequest(name.to_string())).await;
+            let warning = format!("No one called {} has published a key.", name);
+            alice
+                .recv_until(|e| *e == Event::Warning(warning.clone()))
+                .await;
+        }
+        alice.send(Event::KeyRequest("dave".to_string())).await;
+        let daves_key = Event::PublicKey("dave".to_string(), key(MALLORY));
+        alice.recv_until(|e| *e == daves_key).await;
+    }
+}
@@ -0,0 +1,120 @@
+use rtalk_codec::Event;
+
+use crate::session::Session;
+
+// Punctuation that can follow a mention without being part of the name, as
+// in "thanks @bob!".
+const TRAILING: &[char] = &['.', ',', ':', ';', '!', '?', ')', '\'', '"'];
+
+// The names `text` mentions as "@name", each only once.
+pub(crate) fn mentioned(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let name = match word.strip_prefix('@') {
+            Some(name) => name.trim_end_matches(TRAILING),
+            None => continue,
+        };
+
+        if !name.is_empty() && !names.iter().any(|seen| seen == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+// Lets everyone message `message_id` mentions know about it, apart from
+// whoever wrote it. People who have been here before with a key but aren't
+// now find it in their mailbox when they come back and prove it again.
+pub(crate) async fn notify(
+    session: &Session,
+    author: Option<u64>,
+    message_id: u64,
+    who: &str,
+    text: &str,
+) {
+    for name in mentioned(text) {
+        let event = Event::Mentioned(message_id, who.to_string(), text.to_string());
+
+        let ids = session.users_named(&name);
+        if ids.is_empty() {
+            session.mailboxes(|mailboxes, config| {
+                if mailboxes.knows(config, &name) {
+                    mailboxes.post(config, &name, None, event);
+                }
+            });
+            continue;
+        }
+
+        let ids = ids.into_iter().filter(|id| Some(*id) != author).collect();
+        session.broadcast_to(ids, || event.clone()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{server, TestClient};
+
+    #[test]
+    fn finds_mentions() {
+        assert_eq!(
+            mentioned("@bob, @carol: thanks @bob! mail me@example.com @ @"),
+            vec!["bob", "carol"]
+        );
+    }
+
+    #[tokio::test]
+    async fn mentions_are_flagged_for_whoever_is_mentioned() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+        let mut carol = TestClient::join(&server, "carol").await;
+
+        alice
+            .send(Event::MessageSend("@bob lunch?".to_string()))
+            .await;
+        alice.send(Event::MessageSend("@carol?".to_string())).await;
+
+        match bob.recv_until(|e| matches!(e, Event::Mentioned(..))).await {
+            Event::Mentioned(_, who, text) => {
+                assert!(who.starts_with("alice "));
+                assert_eq!(text, "@bob lunch?");
+            }
+            _ => unreachable!(),
+        }
+        match carol
+            .recv_until(|e| matches!(e, Event::Mentioned(..)))
+            .await
+        {
+            Event::Mentioned(_, _, text) => assert_eq!(text, "@carol?"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mentions_wait_for_people_to_come_back() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key([2; 32]).await;
+        bob.send(Event::Leave()).await;
+        alice
+            .recv_until(|e| matches!(e, Event::Left(who) if who.starts_with("bob ")))
+            .await;
+
+        alice
+            .send(Event::MessageSend("where is @bob?".to_string()))
+            .await;
+        // by the time the user list comes back the message has been handled
+        alice.send(Event::ListUsers()).await;
+        alice.recv_until(|e| matches!(e, Event::UserList(_))).await;
+
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.prove_key([2; 32]).await;
+        match bob.recv_until(|e| matches!(e, Event::Mentioned(..))).await {
+            Event::Mentioned(_, _, text) => assert_eq!(text, "where is @bob?"),
+            _ => unreachable!(),
+        }
+    }
+}
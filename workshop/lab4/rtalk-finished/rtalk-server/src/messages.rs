@@ -0,0 +1,211 @@
+use rtalk_codec::Event;
+
+use crate::plugin::ChatUser;
+use crate::session::{self, Session};
+
+// Reactions are meant to be a single emoji or a short word like "+1".
+const MAX_REACTION_LEN: usize = 32;
+
+// Carries out an edit, delete or reaction from user `id`. Changes go to
+// everyone who was sent the original message.
+pub async fn handle(session: &Session, id: u64, event: Event) {
+    match event {
+        Event::Edit(message_id, text) => {
+            if session.too_long(&text) {
+                too_long(session, id).await;
+                return;
+            }
+            let recipients = match changeable(session, id, message_id).await {
+                Some(recipients) => recipients,
+                None => return,
+            };
+
+            // edits get the same scrutiny from plugins as new messages
+            let user = ChatUser {
+                id,
+                name: session.get_name(id),
+            };
+            let text = match session.plugins().message(session, &user, text).await {
+                Ok(text) => text,
+                Err(reason) => {
+                    if let Some(reason) = reason {
+                        session.send_event(id, Event::Warning(reason)).await;
+                    }
+                    return;
+                }
+            };
+            if session.too_long(&text) {
+                too_long(session, id).await;
+                return;
+            }
+
+            session
+                .broadcast_to(recipients, || {
+                    Event::MessageEdited(message_id, text.clone())
+                })
+                .await;
+        }
+
+        Event::Delete(message_id) => {
+            let recipients = match changeable(session, id, message_id).await {
+                Some(recipients) => recipients,
+                None => return,
+            };
+
+            session.forget_message(message_id);
+            session
+                .broadcast_to(recipients, || Event::MessageDeleted(message_id))
+                .await;
+        }
+
+        Event::React(message_id, emoji) => {
+            let emoji = emoji.trim();
+            if emoji.is_empty() || emoji.len() > MAX_REACTION_LEN {
+                let warning = "That is not something you can react with.".to_string();
+                session.send_event(id, Event::Warning(warning)).await;
+                return;
+            }
+
+            // only people who saw a message get to react to it
+            let recipients = match session.message(message_id) {
+                Some((_, recipients)) if recipients.contains(&id) => recipients,
+                _ => {
+                    no_such_message(session, id, message_id).await;
+                    return;
+                }
+            };
+
+            let reactions = session.toggle_reaction(message_id, id, emoji);
+            session
+                .broadcast_to(recipients, || {
+                    Event::Reactions(message_id, reactions.clone())
+                })
+                .await;
+        }
+
+        _ => unreachable!(),
+    }
+}
+
+pub fn is_message_change(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Edit(_, _) | Event::Delete(_) | Event::React(_, _)
+    )
+}
+
+// Looks up who was sent a message, as long as user `id` is allowed to change
+// it: only its author and operators are.
+async fn changeable(session: &Session, id: u64, message_id: u64) -> Option<Vec<u64>> {
+    let (author, recipients) = match session.message(message_id) {
+        Some(message) => message,
+        None => {
+            no_such_message(session, id, message_id).await;
+            return None;
+        }
+    };
+
+    if author != Some(id) && !session.is_operator(id) {
+        let warning = "You can only change your own messages.".to_string();
+        session.send_event(id, Event::Warning(warning)).await;
+        return None;
+    }
+
+    Some(recipients)
+}
+
+async fn too_long(session: &Session, id: u64) {
+    let warning = session::TOO_LONG.to_string();
+    session.send_event(id, Event::Warning(warning)).await;
+}
+
+async fn no_such_message(session: &Session, id: u64, message_id: u64) {
+    let warning = format!("There is no message #{}.", message_id);
+    session.send_event(id, Event::Warning(warning)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rtalk_codec::Reaction;
+
+    use crate::testing::{self, TestClient, OPERATOR_KEY};
+    use crate::Server;
+
+    fn server() -> Server {
+        testing::server_with(testing::operator_config("op"))
+    }
+
+    // Sends a message and waits for it to come back, returning its ID.
+    async fn say(client: &mut TestClient, text: &str) -> u64 {
+        client.send(Event::MessageSend(text.to_string())).await;
+        match client
+            .recv_until(|e| matches!(e, Event::MessageReceived(_, _, msg) if msg == text))
+            .await
+        {
+            Event::MessageReceived(id, _, _) => id,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_authors_and_operators_change_messages() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+        let mut op = TestClient::join(&server, "op").await;
+        op.prove_key(OPERATOR_KEY).await;
+
+        let id = say(&mut alice, "helo").await;
+
+        bob.send(Event::Edit(id, "bob was here".to_string())).await;
+        match bob.recv_until(|e| matches!(e, Event::Warning(_))).await {
+            Event::Warning(warning) => assert!(warning.contains("your own")),
+            _ => unreachable!(),
+        }
+
+        alice.send(Event::Edit(id, "hello".to_string())).await;
+        bob.recv_until(|e| *e == Event::MessageEdited(id, "hello".to_string()))
+            .await;
+
+        op.send(Event::Delete(id)).await;
+        alice.recv_until(|e| *e == Event::MessageDeleted(id)).await;
+
+        // a deleted message is gone for good
+        alice.send(Event::Edit(id, "hello?".to_string())).await;
+        match alice.recv_until(|e| matches!(e, Event::Warning(_))).await {
+            Event::Warning(warning) => assert!(warning.contains("no message")),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_are_counted_and_can_be_taken_back() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        let id = say(&mut alice, "lunch?").await;
+        bob.recv_until(|e| matches!(e, Event::MessageReceived(..)))
+            .await;
+
+        let yes = |count| {
+            vec![Reaction {
+                emoji: "+1".to_string(),
+                count,
+            }]
+        };
+
+        alice.send(Event::React(id, "+1".to_string())).await;
+        bob.send(Event::React(id, "+1".to_string())).await;
+        alice
+            .recv_until(|e| *e == Event::Reactions(id, yes(2)))
+            .await;
+
+        bob.send(Event::React(id, "+1".to_string())).await;
+        alice
+            .recv_until(|e| *e == Event::Reactions(id, yes(1)))
+            .await;
+    }
+}
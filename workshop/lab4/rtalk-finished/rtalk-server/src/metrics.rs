@@ -0,0 +1,249 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::http;
+
+// Everything the server counts about itself. Each server has its own
+// registry so that several can live in one process.
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) connected_users: IntGauge,
+    pub(crate) joins: IntCounter,
+    pub(crate) leaves: IntCounter,
+    pub(crate) messages: IntCounter,
+    pub(crate) bytes_in: IntCounter,
+    pub(crate) bytes_out: IntCounter,
+    pub(crate) broadcast_seconds: Histogram,
+    pub(crate) queue_depth: IntGaugeVec,
+    pub(crate) codec_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let connected_users =
+            IntGauge::new("rtalk_connected_users", "Connections currently open").unwrap();
+        let joins = IntCounter::new("rtalk_joins_total", "Users who have joined").unwrap();
+        let leaves = IntCounter::new("rtalk_leaves_total", "Users who have left").unwrap();
+        let messages =
+            IntCounter::new("rtalk_messages_total", "Chat messages sent on to everyone").unwrap();
+        let bytes_in =
+            IntCounter::new("rtalk_bytes_received_total", "Bytes read from clients").unwrap();
+        let bytes_out =
+            IntCounter::new("rtalk_bytes_sent_total", "Bytes written to clients").unwrap();
+        let broadcast_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "rtalk_broadcast_seconds",
+                "Time taken to hand an event to every recipient",
+            )
+            .buckets(vec![
+                0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+            ]),
+        )
+        .unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "rtalk_user_queue_depth",
+                "Events waiting to be written to each user",
+            ),
+            &["user_id"],
+        )
+        .unwrap();
+        let codec_errors = IntCounterVec::new(
+            Opts::new("rtalk_codec_errors_total", "Undecodable input from clients"),
+            &["kind"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(connected_users.clone()))
+            .unwrap();
+        registry.register(Box::new(joins.clone())).unwrap();
+        registry.register(Box::new(leaves.clone())).unwrap();
+        registry.register(Box::new(messages.clone())).unwrap();
+        registry.register(Box::new(bytes_in.clone())).unwrap();
+        registry.register(Box::new(bytes_out.clone())).unwrap();
+        registry
+            .register(Box::new(broadcast_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(codec_errors.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_users,
+            joins,
+            leaves,
+            messages,
+            bytes_in,
+            bytes_out,
+            broadcast_seconds,
+            queue_depth,
+            codec_errors,
+        }
+    }
+
+    // The metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+        encoder.encode(&self.registry.gather(), &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+// Answers `GET /metrics` with whatever `render` returns at the time. This is
+// only meant for a scraper so it handles one request per connection and
+// nothing else.
+pub(crate) async fn serve<F>(listener: TcpListener, render: F) -> io::Result<()>
+where
+    F: Fn() -> String + Clone + Send + 'static,
+{
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let render = render.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(socket, render).await {
+                tracing::debug!(error = %err, "metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond<F: Fn() -> String>(mut socket: TcpStream, render: F) -> io::Result<()> {
+    let request = match http::read_request(&mut socket).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    if request.method == "GET" && request.path == "/metrics" {
+        let body = render();
+        http::respond(&mut socket, "200 OK", "text/plain; version=0.0.4", &body).await
+    } else {
+        http::respond(&mut socket, "404 Not Found", "text/plain", "").await
+    }
+}
+
+// Wraps a connection, counting the bytes that pass through it.
+pub(crate) struct Counted<T> {
+    inner: Pin<Box<T>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T, metrics: Arc<Metrics>) -> Self {
+        Counted {
+            inner: Box::pin(inner),
+            metrics,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Counted<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = self.inner.as_mut().poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - before;
+            self.metrics.bytes_in.inc_by(read as u64);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Counted<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.inner.as_mut().poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.metrics.bytes_out.inc_by(written as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use rtalk_codec::Event;
+
+    use crate::testing::{self, TestClient};
+
+    // The value of an unlabelled metric in the text output.
+    fn value(text: &str, name: &str) -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(&format!("{} ", name)))
+            .unwrap_or_else(|| panic!("{} missing from\n{}", name, text))
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn chat_shows_up_in_the_metrics() {
+        let server = testing::server();
+
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+        bob.send(Event::MessageSend("hi".to_string())).await;
+        alice
+            .recv_until(|e| matches!(e, Event::MessageReceived(..)))
+            .await;
+        bob.send(Event::Leave()).await;
+        alice.recv_until(|e| matches!(e, Event::Left(_))).await;
+
+        let text = server.metrics();
+        assert_eq!(value(&text, "rtalk_connected_users"), 1.0);
+        assert_eq!(value(&text, "rtalk_joins_total"), 2.0);
+        assert_eq!(value(&text, "rtalk_leaves_total"), 1.0);
+        assert_eq!(value(&text, "rtalk_messages_total"), 1.0);
+        assert!(value(&text, "rtalk_bytes_received_total") > 0.0);
+        assert!(value(&text, "rtalk_bytes_sent_total") > 0.0);
+        assert!(value(&text, "rtalk_broadcast_seconds_count") > 0.0);
+        assert!(text.contains("rtalk_user_queue_depth{user_id="));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, || "rtalk_joins_total 3\n".to_string()));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nrtalk_joins_total 3\n"));
+    }
+}
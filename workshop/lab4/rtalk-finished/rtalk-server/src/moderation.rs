@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use rtalk_codec::Event;
+
+use crate::bans::Ban;
+use crate::session::Session;
+
+// Carries out a moderation request from user `id`. Only operators are allowed
+// to moderate; everyone else just gets told off.
+pub async fn handle(session: &Session, id: u64, event: Event) {
+    if !session.is_operator(id) {
+        let warning = "You need to be an operator to do that.".to_string();
+        session.send_event(id, Event::Warning(warning)).await;
+        return;
+    }
+
+    let by = session.get_name(id);
+    match event {
+        Event::Op(target) => {
+            let ids = match find_users(session, id, &target).await {
+                Some(ids) => ids,
+                None => return,
+            };
+
+            for target_id in ids {
+                session.set_operator(target_id, true);
+            }
+            announce(
+                session,
+                format!("{} was made an operator by {}.", target, by),
+            )
+            .await;
+        }
+
+        Event::Kick(target, reason) => {
+            let ids = match find_users(session, id, &target).await {
+                Some(ids) => ids,
+                None => return,
+            };
+
+            announce(
+                session,
+                format!("{} was kicked by {}: {}", target, by, reason),
+            )
+            .await;
+            for target_id in ids {
+                let reason = format!("You were kicked by {}: {}", by, reason);
+                session.disconnect(target_id, reason).await;
+            }
+        }
+
+        Event::Ban(target, secs, reason) => {
+            let ban = Ban::new(target.clone(), secs, reason, by.clone());
+            let description = ban.describe();
+            let ip = ban.ip();
+
+            if let Err(err) = session.add_ban(ban) {
+                let warning = format!("The ban is in place but could not be saved: {}", err);
+                session.send_event(id, Event::Warning(warning)).await;
+            }
+
+            let ids = match ip {
+                Some(ip) => session.users_from(ip),
+                None => session.users_named(&target),
+            };
+
+            announce(session, format!("{} was {}", target, description)).await;
+            for target_id in ids {
+                let reason = format!("You were {}", description);
+                session.disconnect(target_id, reason).await;
+            }
+        }
+
+        Event::Unban(target) => match session.remove_ban(&target) {
+            Ok(true) => announce(session, format!("{} was unbanned by {}.", target, by)).await,
+            Ok(false) => {
+                let warning = format!("{} is not banned.", target);
+                session.send_event(id, Event::Warning(warning)).await;
+            }
+            Err(err) => {
+                let warning = format!("The ban was lifted but could not be saved: {}", err);
+                session.send_event(id, Event::Warning(warning)).await;
+            }
+        },
+
+        Event::Mute(target, secs) => {
+            let ids = match find_users(session, id, &target).await {
+                Some(ids) => ids,
+                None => return,
+            };
+
+            // muting for no time at all lifts an earlier mute
+            let duration = if secs == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(secs))
+            };
+
+            for target_id in &ids {
+                session.mute(*target_id, duration);
+            }
+
+            let (notice, warning) = match duration {
+                Some(_) => (
+                    format!("{} was muted by {} for {} seconds.", target, by, secs),
+                    format!("You were muted by {} for {} seconds.", by, secs),
+                ),
+                None => (
+                    format!("{} was unmuted by {}.", target, by),
+                    format!("You were unmuted by {}.", by),
+                ),
+            };
+
+            announce(session, notice).await;
+            for target_id in ids {
+                session
+                    .send_event(target_id, Event::Warning(warning.clone()))
+                    .await;
+            }
+        }
+
+        _ => unreachable!(),
+    }
+}
+
+pub fn is_moderation(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Op(_)
+            | Event::Kick(_, _)
+            | Event::Ban(_, _, _)
+            | Event::Unban(_)
+            | Event::Mute(_, _)
+    )
+}
+
+// Looks up the users going by `name`, letting the operator know if there
+// aren't any.
+async fn find_users(session: &Session, id: u64, name: &str) -> Option<Vec<u64>> {
+    let ids = session.users_named(name);
+    if ids.is_empty() {
+        let warning = format!("There is no one called {} here.", name);
+        session.send_event(id, Event::Warning(warning)).await;
+        return None;
+    }
+
+    Some(ids)
+}
+
+async fn announce(session: &Session, notice: String) {
+    session.broadcast(|| Event::Notice(notice.clone())).await;
+}
+
+pub fn ban_reason(ban: &Ban) -> String {
+    format!("You are {}", ban.describe())
+}
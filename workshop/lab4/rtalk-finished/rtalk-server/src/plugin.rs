@@ -0,0 +1,305 @@
+use std::error::Error;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+use async_trait::async_trait;
+use futures_util::future::FutureExt;
+
+use rtalk_codec::{Event, UserInfo};
+
+use crate::session::Session;
+
+pub type PluginError = Box<dyn Error + Send + Sync>;
+pub type PluginResult<T> = Result<T, PluginError>;
+
+// Someone in the chat, as seen by a plugin. `name` is the name everyone else
+// sees, address and all.
+#[derive(Debug, Clone)]
+pub struct ChatUser {
+    pub id: u64,
+    pub name: String,
+}
+
+// What should become of a message once a plugin has seen it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    // send this on, either the message as it was or a rewritten version
+    Allow(String),
+
+    // drop the message, optionally telling the sender why
+    Veto(Option<String>),
+}
+
+// A bot living inside the server. Every hook has a default that does nothing
+// so a plugin only needs to implement the ones it cares about.
+//
+// Messages that start with "!" are commands: once they have been through the
+// message hooks and sent on, `on_command` is called with the command name and
+// the rest of the line.
+#[async_trait]
+pub trait ServerPlugin: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn on_join(&self, _ctx: &PluginContext, _user: &ChatUser) -> PluginResult<()> {
+        Ok(())
+    }
+
+    async fn on_leave(&self, _ctx: &PluginContext, _name: &str) -> PluginResult<()> {
+        Ok(())
+    }
+
+    async fn on_message(
+        &self,
+        _ctx: &PluginContext,
+        _from: &ChatUser,
+        message: String,
+    ) -> PluginResult<Verdict> {
+        Ok(Verdict::Allow(message))
+    }
+
+    async fn on_command(
+        &self,
+        _ctx: &PluginContext,
+        _from: &ChatUser,
+        _command: &str,
+        _args: &str,
+    ) -> PluginResult<()> {
+        Ok(())
+    }
+}
+
+// What a plugin can do to the chat from inside one of its hooks.
+pub struct PluginContext {
+    session: Session,
+    name: String,
+}
+
+impl PluginContext {
+    // Says something to everyone, under the plugin's name. Returns the ID of
+    // the message, or `None` if it was too long to send.
+    pub async fn say(&self, text: &str) -> Option<u64> {
+        let everyone = self.session.user_ids();
+        self.session
+            .post_message(everyone, None, &self.name, text)
+            .await
+    }
+
+    // Says something to just one user.
+    pub async fn tell(&self, user: &ChatUser, text: &str) -> Option<u64> {
+        self.session
+            .post_message(vec![user.id], None, &self.name, text)
+            .await
+    }
+
+    pub async fn announce(&self, notice: &str) {
+        self.session
+            .broadcast(|| Event::Notice(notice.to_string()))
+            .await;
+    }
+
+    pub fn users(&self) -> Vec<UserInfo> {
+        self.session.list_users()
+    }
+}
+
+// The plugins registered on a server. Each hook is run against every plugin
+// in turn, and a plugin that fails or panics is logged and skipped rather than
+// being allowed to take the connection down with it.
+pub(crate) struct Plugins {
+    plugins: Vec<Box<dyn ServerPlugin>>,
+}
+
+impl Plugins {
+    pub(crate) fn new(plugins: Vec<Box<dyn ServerPlugin>>) -> Self {
+        Plugins { plugins }
+    }
+
+    pub(crate) async fn joined(&self, session: &Session, user: &ChatUser) {
+        for plugin in &self.plugins {
+            let ctx = context(session, plugin.as_ref());
+            guarded(plugin.as_ref(), "join", plugin.on_join(&ctx, user)).await;
+        }
+    }
+
+    pub(crate) async fn left(&self, session: &Session, name: &str) {
+        for plugin in &self.plugins {
+            let ctx = context(session, plugin.as_ref());
+            guarded(plugin.as_ref(), "leave", plugin.on_leave(&ctx, name)).await;
+        }
+    }
+
+    // Passes a message through every plugin, returning what should be sent
+    // on, or why it shouldn't be.
+    pub(crate) async fn message(
+        &self,
+        session: &Session,
+        from: &ChatUser,
+        mut message: String,
+    ) -> Result<String, Option<String>> {
+        for plugin in &self.plugins {
+            let ctx = context(session, plugin.as_ref());
+            let verdict = plugin.on_message(&ctx, from, message.clone());
+            match guarded(plugin.as_ref(), "message", verdict).await {
+                Some(Verdict::Allow(rewritten)) => message = rewritten,
+                Some(Verdict::Veto(reason)) => return Err(reason),
+
+                // a broken plugin doesn't get a say
+                None => {}
+            }
+        }
+
+        Ok(message)
+    }
+
+    pub(crate) async fn command(&self, session: &Session, from: &ChatUser, message: &str) {
+        let line = match message.strip_prefix('!') {
+            Some(line) => line,
+            None => return,
+        };
+
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(at) => (&line[..at], line[at..].trim()),
+            None => (line, ""),
+        };
+        if command.is_empty() {
+            return;
+        }
+
+        for plugin in &self.plugins {
+            let ctx = context(session, plugin.as_ref());
+            let hook = plugin.on_command(&ctx, from, command, args);
+            guarded(plugin.as_ref(), "command", hook).await;
+        }
+    }
+}
+
+fn context(session: &Session, plugin: &dyn ServerPlugin) -> PluginContext {
+    PluginContext {
+        session: session.clone(),
+        name: plugin.name().to_string(),
+    }
+}
+
+async fn guarded<T, F>(plugin: &dyn ServerPlugin, hook: &str, fut: F) -> Option<T>
+where
+    F: Future<Output = PluginResult<T>>,
+{
+    match AssertUnwindSafe(fut).catch_unwind().await {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(err)) => {
+            tracing::warn!(plugin = plugin.name(), hook, error = %err, "plugin failed");
+            None
+        }
+        Err(_) => {
+            tracing::error!(plugin = plugin.name(), hook, "plugin panicked");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{self, TestClient};
+    use crate::Server;
+
+    struct Echo;
+
+    #[async_trait]
+    impl ServerPlugin for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        async fn on_command(
+            &self,
+            ctx: &PluginContext,
+            from: &ChatUser,
+            command: &str,
+            args: &str,
+        ) -> PluginResult<()> {
+            if command == "echo" {
+                ctx.tell(from, args).await;
+            }
+            Ok(())
+        }
+    }
+
+    struct Censor;
+
+    #[async_trait]
+    impl ServerPlugin for Censor {
+        fn name(&self) -> &str {
+            "censor"
+        }
+
+        async fn on_message(
+            &self,
+            _ctx: &PluginContext,
+            _from: &ChatUser,
+            message: String,
+        ) -> PluginResult<Verdict> {
+            if message.contains("spam") {
+                return Ok(Verdict::Veto(Some("No spam please.".to_string())));
+            }
+            Ok(Verdict::Allow(message.replace("darn", "d**n")))
+        }
+    }
+
+    struct Broken;
+
+    #[async_trait]
+    impl ServerPlugin for Broken {
+        fn name(&self) -> &str {
+            "broken"
+        }
+
+        async fn on_join(&self, _ctx: &PluginContext, _user: &ChatUser) -> PluginResult<()> {
+            panic!("oops")
+        }
+
+        async fn on_message(
+            &self,
+            _ctx: &PluginContext,
+            _from: &ChatUser,
+            _message: String,
+        ) -> PluginResult<Verdict> {
+            Err("no idea what to do".into())
+        }
+    }
+
+    fn server() -> Server {
+        Server::builder()
+            .config(testing::config())
+            .plugin(Broken)
+            .plugin(Censor)
+            .plugin(Echo)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn plugins_transform_veto_and_answer() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        alice.send(Event::MessageSend("darn it".to_string())).await;
+        alice
+            .recv_until(|e| matches!(e, Event::MessageReceived(_, _, msg) if msg == "d**n it"))
+            .await;
+
+        alice.send(Event::MessageSend("buy spam".to_string())).await;
+        match alice.recv().await {
+            Event::Warning(reason) => assert_eq!(reason, "No spam please."),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        alice
+            .send(Event::MessageSend("!echo hello".to_string()))
+            .await;
+        alice
+            .recv_until(|e| matches!(e, Event::MessageReceived(_, who, msg) if who == "echo" && msg == "hello"))
+            .await;
+    }
+}
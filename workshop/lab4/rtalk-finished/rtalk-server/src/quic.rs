@@ -0,0 +1,91 @@
+use std::fs;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::SocketAddr;
+
+use futures_util::sink::SinkExt;
+use quinn::{Connecting, Endpoint, ServerConfig};
+use rustls_pemfile::Item;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use rtalk_codec::{Event, EventCodec};
+
+use crate::session::Session;
+
+// Makes a QUIC endpoint on `addr` for `serve` to accept connections on. The
+// certificate chain and its private key are read from PEM files.
+pub fn bind_quic(addr: SocketAddr, cert_path: &str, key_path: &str) -> io::Result<Endpoint> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(cert_path)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(invalid(&format!("no certificates in {}", cert_path)));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(fs::File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(&format!("no private key in {}", key_path)))?;
+
+    let mut config = ServerConfig::with_single_cert(certs, rustls::PrivateKey(key))
+        .map_err(|err| invalid(&err.to_string()))?;
+
+    // let clients carry on when their address changes, e.g. on moving from
+    // wifi to mobile data
+    config.migration(true);
+
+    Endpoint::server(config, addr)
+}
+
+// Accepts QUIC connections and adds them to the same session as everyone
+// else. Each connection carries its events on one bidirectional stream that
+// the client opens, encoded with `EventCodec` just as they are over TCP.
+pub async fn serve(endpoint: Endpoint, session: Session) -> io::Result<()> {
+    while let Some(connecting) = endpoint.accept().await {
+        // the handshake needs a round trip, so don't hold up everyone else
+        // while it happens
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accept(connecting, session).await {
+                tracing::debug!(error = %err, "QUIC handshake failed");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn accept(connecting: Connecting, session: Session) -> io::Result<()> {
+    let connection = connecting.await.map_err(to_io_error)?;
+    let ip = connection.remote_address();
+
+    // the stream only shows up here once the client has sent something on
+    // it, which it does straight away by asking to join
+    let (send, recv) = connection.accept_bi().await.map_err(to_io_error)?;
+    let mut sink = FramedWrite::new(session.counted(send), EventCodec);
+    let stream = FramedRead::new(session.counted(recv), EventCodec);
+
+    if let Some(reason) = session.refusal(ip.ip()) {
+        tracing::info!(peer = %ip, reason = %reason, "connection refused");
+        let _ = sink.send(Event::Disconnect(reason)).await;
+
+        // closing waits for the client to have the reason before the
+        // connection goes
+        let _ = sink.close().await;
+        return Ok(());
+    }
+
+    // the streams keep the connection open for as long as the user is here
+    session.add_user(ip, Box::pin(sink), Box::pin(stream));
+    Ok(())
+}
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::other(err)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, message.to_string())
+}
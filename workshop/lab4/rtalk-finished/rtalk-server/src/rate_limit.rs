@@ -0,0 +1,105 @@
+use std::time::Instant;
+
+// A classic token bucket: it holds up to `capacity` tokens, each event takes
+// one and tokens trickle back in at `refill_per_sec`.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: u32, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: f64::from(burst),
+            tokens: f64::from(burst),
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Whether there is a token to take, without taking it.
+    pub fn ready(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens >= 1.0
+    }
+
+    // Changes the bucket's size and refill rate, keeping the tokens it holds
+    // as long as they fit.
+    pub fn set_limits(&mut self, now: Instant, burst: u32, refill_per_sec: f64) {
+        self.ready(now);
+        self.capacity = f64::from(burst);
+        self.tokens = self.tokens.min(self.capacity);
+        self.refill_per_sec = refill_per_sec;
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        if self.ready(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn allows_a_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, 2.0);
+
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // two tokens a second means one is back after half a second
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // and it never holds more than the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(much_later));
+        }
+        assert!(!bucket.try_take(much_later));
+    }
+
+    #[test]
+    fn looking_takes_nothing() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1, 0.0);
+
+        assert!(bucket.ready(start));
+        assert!(bucket.ready(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.ready(start));
+    }
+
+    #[test]
+    fn limits_can_change() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, 0.0);
+        assert!(bucket.try_take(start));
+
+        // what is left is cut down to the new size...
+        bucket.set_limits(start, 1, 1.0);
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // ...and refills at the new rate
+        assert!(bucket.try_take(start + Duration::from_secs(1)));
+    }
+}
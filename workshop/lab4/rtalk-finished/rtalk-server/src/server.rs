@@ -0,0 +1,489 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::sink::SinkExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_util::codec::Decoder;
+
+use rtalk_codec::{Event, EventCodec, UserInfo};
+
+use crate::admin::{self, Admin};
+use crate::bans::BanList;
+use crate::config::Config;
+use crate::federation::{self, Role};
+use crate::metrics;
+use crate::plugin::{Plugins, ServerPlugin};
+use crate::quic;
+use crate::session::Session;
+#[cfg(unix)]
+use crate::unix;
+use crate::ws;
+
+// How long to wait before dialling a peer server again after its link drops.
+const RELINK_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    config_path: Option<String>,
+    plugins: Vec<Box<dyn ServerPlugin>>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Where the config was loaded from, so that the admin API can reload it.
+    pub fn config_path(mut self, path: &str) -> Self {
+        self.config_path = Some(path.to_string());
+        self
+    }
+
+    // Plugins see events in the order they were added.
+    pub fn plugin<P: ServerPlugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    pub fn build(self) -> io::Result<Server> {
+        let bans = BanList::load(self.config.ban_file.as_deref())?;
+        let plugins = Plugins::new(self.plugins);
+        Ok(Server {
+            session: Session::new(self.config.clone(), bans, plugins),
+            config: self.config,
+            config_path: self.config_path,
+        })
+    }
+}
+
+// A chat server. Connections can be handed to it over any transport, or it
+// can accept them itself on the addresses in its config with `run`.
+#[derive(Clone)]
+pub struct Server {
+    session: Session,
+    config: Config,
+    config_path: Option<String>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // Adds a connection speaking the rtalk protocol to the chat. Returns the
+    // new user's ID, or `None` if the peer was turned away.
+    pub fn connect<T>(&self, transport: T, peer: SocketAddr) -> Option<u64>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.connect_as(transport, peer, None)
+    }
+
+    // Adds a connection from the Unix socket. Users on it who join without a
+    // name are given the name of the account at the other end.
+    #[cfg(unix)]
+    pub fn connect_unix(&self, stream: UnixStream) -> Option<u64> {
+        let account = match unix::peer_account(&stream) {
+            Ok(account) => account,
+            Err(err) => {
+                tracing::warn!(error = %err, "can't tell who is on the Unix socket");
+                return None;
+            }
+        };
+
+        // the session wants an address; a local connection's is loopback,
+        // but it is the account that bans and limits go by
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+        self.connect_as(stream, peer, Some(account))
+    }
+
+    fn connect_as<T>(&self, transport: T, peer: SocketAddr, account: Option<String>) -> Option<u64>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let codec = EventCodec;
+        let transport = self.session.counted(transport);
+        let (mut sink, stream) = futures::StreamExt::split(codec.framed(transport));
+
+        let refusal = match &account {
+            Some(account) => self.session.local_refusal(account),
+            None => self.session.refusal(peer.ip()),
+        };
+        if let Some(reason) = refusal {
+            tracing::info!(peer = %peer, reason = %reason, "connection refused");
+
+            // tell the client why before hanging up on it, without holding
+            // up the caller
+            tokio::spawn(async move {
+                let _ = sink.send(Event::Disconnect(reason)).await;
+            });
+            return None;
+        }
+
+        Some(
+            self.session
+                .add_user_as(peer, account, Box::pin(sink), Box::pin(stream)),
+        )
+    }
+
+    // Like `connect` but for a transport that starts with a WebSocket
+    // handshake.
+    pub async fn connect_ws<T>(&self, transport: T, peer: SocketAddr) -> Result<(), WsError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        ws::accept(transport, peer, self.session.clone()).await
+    }
+
+    pub async fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (socket, ip) = listener.accept().await?;
+            self.connect(socket, ip);
+        }
+    }
+
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.connect_unix(stream);
+        }
+    }
+
+    pub async fn serve_ws(&self, listener: TcpListener) -> io::Result<()> {
+        ws::serve(listener, self.session.clone()).await
+    }
+
+    // Accepts QUIC connections on an endpoint made with `bind_quic`.
+    pub async fn serve_quic(&self, endpoint: quinn::Endpoint) -> io::Result<()> {
+        quic::serve(endpoint, self.session.clone()).await
+    }
+
+    // Serves the metrics for Prometheus to scrape.
+    pub async fn serve_metrics(&self, listener: TcpListener) -> io::Result<()> {
+        let server = self.clone();
+        metrics::serve(listener, move || server.metrics()).await
+    }
+
+    // Serves the admin API. Fails straight away if there's no admin token in
+    // the config or the listener isn't on a loopback address.
+    pub async fn serve_admin(&self, listener: TcpListener) -> io::Result<()> {
+        if !admin::has_token(&self.config) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the admin API needs an admin_token",
+            ));
+        }
+        if !listener.local_addr()?.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the admin API only listens on loopback addresses",
+            ));
+        }
+
+        let admin = Admin {
+            session: self.session.clone(),
+            config_path: self.config_path.clone(),
+        };
+        admin::serve(listener, admin).await
+    }
+
+    // Runs a federation link to another server over `transport` until either
+    // end hangs up, as the server that got in touch. Fails straight away if
+    // there's no federation secret in the config.
+    pub async fn link<T>(&self, transport: T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let secret = self.federation_secret()?;
+        federation::link(self.session.clone(), secret, Role::Initiator, transport).await
+    }
+
+    // The same, for a link that the other server got in touch over.
+    pub async fn accept_link<T>(&self, transport: T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let secret = self.federation_secret()?;
+        federation::link(self.session.clone(), secret, Role::Responder, transport).await
+    }
+
+    // Accepts federation links from other servers.
+    pub async fn serve_federation(&self, listener: TcpListener) -> io::Result<()> {
+        self.federation_secret()?;
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.accept_link(socket).await {
+                    tracing::warn!(peer = %peer, error = %err, "federation link failed");
+                }
+            });
+        }
+    }
+
+    // Keeps a federation link to the server at `addr` going for as long as
+    // we're running.
+    async fn keep_linked(&self, addr: String) {
+        loop {
+            let result = match TcpStream::connect(&addr).await {
+                Ok(socket) => self.link(socket).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                tracing::warn!(peer = %addr, error = %err, "federation link failed");
+            }
+            tokio::time::sleep(RELINK_DELAY).await;
+        }
+    }
+
+    fn federation_secret(&self) -> io::Result<String> {
+        match &self.config.federation.secret {
+            Some(secret) if !secret.is_empty() => Ok(secret.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "federation needs a secret",
+            )),
+        }
+    }
+
+    // Listens on the addresses from the config until something goes wrong.
+    pub async fn run(&self) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.config.listen).await?;
+
+        if let Some(addr) = &self.config.ws_listen {
+            let ws_listener = TcpListener::bind(addr).await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.serve_ws(ws_listener).await {
+                    tracing::error!(error = %err, "WebSocket listener failed");
+                }
+            });
+        }
+
+        if let Some(addr) = &self.config.quic_listen {
+            let (cert, key) = match (&self.config.quic_cert, &self.config.quic_key) {
+                (Some(cert), Some(key)) => (cert, key),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "QUIC needs quic_cert and quic_key",
+                    ))
+                }
+            };
+            let addr = addr
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let endpoint = quic::bind_quic(addr, cert, key)?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.serve_quic(endpoint).await {
+                    tracing::error!(error = %err, "QUIC listener failed");
+                }
+            });
+        }
+
+        #[cfg(unix)]
+        {
+            if let Some(path) = &self.config.unix_listen {
+                let (unix_listener, file) = unix::bind_unix(path)?;
+                let server = self.clone();
+                tokio::spawn(async move {
+                    // the socket file goes when this task does, which is at
+                    // the latest when the runtime shuts down
+                    let _file = file;
+                    if let Err(err) = server.serve_unix(unix_listener).await {
+                        tracing::error!(error = %err, "Unix socket listener failed");
+                    }
+                });
+            }
+        }
+
+        if let Some(addr) = &self.config.metrics_listen {
+            let metrics_listener = TcpListener::bind(addr).await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.serve_metrics(metrics_listener).await {
+                    tracing::error!(error = %err, "metrics listener failed");
+                }
+            });
+        }
+
+        if let Some(addr) = &self.config.admin_listen {
+            let admin_listener = TcpListener::bind(addr).await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.serve_admin(admin_listener).await {
+                    tracing::error!(error = %err, "admin listener failed");
+                }
+            });
+        }
+
+        if let Some(addr) = &self.config.federation.listen {
+            let federation_listener = TcpListener::bind(addr).await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.serve_federation(federation_listener).await {
+                    tracing::error!(error = %err, "federation listener failed");
+                }
+            });
+        }
+
+        for addr in &self.config.federation.peers {
+            let server = self.clone();
+            let addr = addr.clone();
+            tokio::spawn(async move { server.keep_linked(addr).await });
+        }
+
+        self.serve_tcp(listener).await
+    }
+
+    // Everyone in the chat, including users on linked servers.
+    pub fn users(&self) -> Vec<UserInfo> {
+        self.session.list_users()
+    }
+
+    // Everything the server counts, in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.session.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::Stream;
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_util::codec::{Encoder, Framed};
+
+    use crate::testing;
+
+    async fn start_server() -> (SocketAddr, SocketAddr) {
+        let server = testing::server();
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = (tcp.local_addr().unwrap(), ws.local_addr().unwrap());
+
+        let ws_server = server.clone();
+        tokio::spawn(async move { ws_server.serve_ws(ws).await });
+        tokio::spawn(async move { server.serve_tcp(tcp).await });
+
+        addrs
+    }
+
+    // Reads events until one matches, failing the test if it takes too long.
+    async fn wait_for<S, F>(stream: &mut S, matches: F) -> Event
+    where
+        S: Stream<Item = io::Result<Event>> + Unpin,
+        F: Fn(&Event) -> bool,
+    {
+        let found = timeout(Duration::from_secs(5), async {
+            while let Some(event) = stream.next().await {
+                let event = event.unwrap();
+                if matches(&event) {
+                    return event;
+                }
+            }
+            panic!("connection closed");
+        });
+
+        found.await.expect("timed out waiting for event")
+    }
+
+    fn said(event: &Event, text: &str) -> bool {
+        matches!(event, Event::MessageReceived(_, _, msg) if msg == text)
+    }
+
+    fn json(event: &Event) -> Message {
+        Message::Text(serde_json::to_string(event).unwrap())
+    }
+
+    fn from_json(msg: Result<Message, tokio_tungstenite::tungstenite::Error>) -> io::Result<Event> {
+        match msg.unwrap() {
+            Message::Text(text) => Ok(serde_json::from_str(&text).unwrap()),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    fn from_binary(
+        msg: Result<Message, tokio_tungstenite::tungstenite::Error>,
+    ) -> io::Result<Event> {
+        match msg.unwrap() {
+            Message::Binary(data) => Ok(EventCodec
+                .decode(&mut bytes::BytesMut::from(&data[..]))?
+                .unwrap()),
+            other => panic!("expected a binary frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_and_tcp_users_chat_together() {
+        let (tcp_addr, ws_addr) = start_server().await;
+        let ws_url = format!("ws://{}", ws_addr);
+
+        let mut terminal: Framed<TcpStream, EventCodec> =
+            EventCodec.framed(TcpStream::connect(tcp_addr).await.unwrap());
+        terminal
+            .send(Event::RequestJoin("terminal".to_string()))
+            .await
+            .unwrap();
+        wait_for(&mut terminal, |e| matches!(e, Event::UserList(_))).await;
+
+        // a browser speaking JSON
+        let (browser, _) = tokio_tungstenite::connect_async(&ws_url).await.unwrap();
+        let (mut browser_tx, browser_rx) = futures::StreamExt::split(browser);
+        let mut browser_rx = browser_rx.map(from_json);
+        browser_tx
+            .send(json(&Event::RequestJoin("browser".to_string())))
+            .await
+            .unwrap();
+        wait_for(&mut browser_rx, |e| matches!(e, Event::UserList(_))).await;
+        wait_for(
+            &mut terminal,
+            |e| matches!(e, Event::Joined(who) if who.starts_with("browser ")),
+        )
+        .await;
+
+        // a WebSocket client speaking the binary protocol
+        let (tool, _) = tokio_tungstenite::connect_async(&ws_url).await.unwrap();
+        let (mut tool_tx, tool_rx) = futures::StreamExt::split(tool);
+        let mut tool_rx = tool_rx.map(from_binary);
+        let mut buf = bytes::BytesMut::new();
+        EventCodec
+            .encode(Event::RequestJoin("tool".to_string()), &mut buf)
+            .unwrap();
+        tool_tx.send(Message::Binary(buf.to_vec())).await.unwrap();
+        wait_for(&mut tool_rx, |e| matches!(e, Event::UserList(_))).await;
+
+        browser_tx
+            .send(json(&Event::MessageSend("hello from the web".to_string())))
+            .await
+            .unwrap();
+        match wait_for(&mut terminal, |e| said(e, "hello from the web")).await {
+            Event::MessageReceived(_, who, _) => assert!(who.starts_with("browser ")),
+            _ => unreachable!(),
+        }
+        wait_for(&mut tool_rx, |e| said(e, "hello from the web")).await;
+
+        terminal
+            .send(Event::MessageSend("hi from the terminal".to_string()))
+            .await
+            .unwrap();
+        wait_for(&mut browser_rx, |e| said(e, "hi from the terminal")).await;
+        wait_for(&mut tool_rx, |e| said(e, "hi from the terminal")).await;
+    }
+}
@@ -0,0 +1,1180 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{future, select, Sink, Stream};
+use futures_util::{future::FutureExt, sink::SinkExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Sender;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, info_span, warn, Instrument};
+use x25519_dalek::StaticSecret;
+
+use rtalk_codec::{Event, Presence, Reaction, UserInfo, MAX_FRAME};
+
+use crate::admin::{self, UserDetails};
+use crate::bans::{Ban, BanList};
+use crate::config::{Config, FileConfig, MailboxConfig, RateLimitConfig, ViolationAction};
+use crate::delivery;
+use crate::direct;
+use crate::federation::{self, Federation, Relayed};
+use crate::files::{self, Transfers};
+use crate::mailbox::{self, Mailboxes};
+use crate::mentions;
+use crate::messages;
+use crate::metrics::{Counted, Metrics};
+use crate::moderation;
+use crate::plugin::{ChatUser, Plugins};
+use crate::rate_limit::TokenBucket;
+
+// The two halves of a connection to a user. Users can reach us over more than
+// one kind of transport so the session only deals in events.
+pub type EventSink = Pin<Box<dyn Sink<Event, Error = io::Error> + Send>>;
+pub type EventStream = Pin<Box<dyn Stream<Item = io::Result<Event>> + Send>>;
+
+// Messages can be edited, deleted and reacted to until this many newer ones
+// have been sent.
+const MESSAGE_HISTORY: usize = 1000;
+
+// Events queued for a user beyond this hold up whoever is sending to them.
+const QUEUE_SIZE: usize = 100;
+
+// Typing notifications from a user are passed on at most this often.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+// Sequence numbers of tracked messages are remembered for this many of each
+// user's most recent ones, so that a message sent again isn't passed on twice.
+const ACK_HISTORY: usize = 256;
+
+// Those of clients that have given an ID are kept after they go, for this many
+// clients, so that what they send again once they're back goes out once too.
+const ACK_CLIENTS: usize = 1024;
+
+// Sequence numbers of tracked messages and the IDs they went out with, if
+// they have yet.
+type Acked = VecDeque<(u64, Option<u64>)>;
+
+// The longest a message can be, whatever the config says. It leaves room in
+// the frame for the message's ID and who sent it; recipients would turn away
+// any longer frame.
+const MAX_MESSAGE_LEN: usize = MAX_FRAME - 64 * 1024;
+
+pub(crate) const TOO_LONG: &str = "That message is too long.";
+
+// Mutes are cut down to this, which is as good as forever. Any longer and the
+// time they run out at might not fit in an `Instant`.
+const MAX_MUTE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+pub struct User {
+    name: Option<String>,
+    ip: SocketAddr,
+    sender: Sender<Event>,
+    connected_at: Instant,
+    last_active: Instant,
+    presence: Presence,
+    status: Option<String>,
+    last_typing: Option<Instant>,
+    bucket: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+    operator: bool,
+    // for encrypting direct messages to this user
+    public_key: Option<Vec<u8>>,
+    // the secret half of the challenge they have to answer to prove the key
+    // is theirs, until they have
+    key_challenge: Option<StaticSecret>,
+    key_proven: bool,
+    // the local account at the other end, for users on the Unix socket
+    account: Option<String>,
+    // tracked messages, until the client gives an ID to keep them under
+    // instead
+    acked: Acked,
+    client: Option<u64>,
+}
+
+impl User {
+    fn get_name(&self) -> String {
+        match self.name.as_ref() {
+            Some(name) => format!("{} [{:?}]", name, self.ip),
+            None => format!("anonymous [{}]", self.ip),
+        }
+    }
+
+    fn info(&self) -> UserInfo {
+        UserInfo {
+            name: self.get_name(),
+            connected_secs: self.connected_at.elapsed().as_secs(),
+            idle_secs: self.last_active.elapsed().as_secs(),
+            presence: self.presence,
+            status: self.status.clone(),
+        }
+    }
+}
+
+// How far a tracked message has got.
+pub(crate) enum Tracked {
+    // not seen before, and now on its way
+    New,
+    // the same number is still being sent, or its sender has gone
+    Sending,
+    // already sent out with this ID
+    Sent(u64),
+}
+
+// The outcome of checking an incoming event against the rate limits.
+enum RateCheck {
+    Allowed,
+    Limited,
+    Muted(Duration),
+    Disconnect,
+}
+
+// What we remember about a message after sending it out.
+struct SentMessage {
+    // `None` for messages from plugins, which only operators can change
+    author: Option<u64>,
+    // changes to the message go to everyone who saw it
+    recipients: Vec<u64>,
+    // who has reacted with each emoji
+    reactions: BTreeMap<String, BTreeSet<u64>>,
+}
+
+struct State {
+    counter: u64,
+    users: BTreeMap<u64, User>,
+    ip_buckets: HashMap<IpAddr, TokenBucket>,
+    config: Config,
+    bans: BanList,
+    message_counter: u64,
+    messages: BTreeMap<u64, SentMessage>,
+    transfers: Transfers,
+    mailboxes: Mailboxes,
+    // tracked messages by client ID, and when each client last sent one
+    client_acks: HashMap<u64, (Instant, Acked)>,
+}
+
+impl State {
+    fn add_user(
+        &mut self,
+        session: Session,
+        ip: SocketAddr,
+        account: Option<String>,
+        mut sink: EventSink,
+        mut stream: EventStream,
+    ) -> u64 {
+        self.counter += 1;
+
+        let id = self.counter;
+
+        let (sender, mut rx) = mpsc::channel::<Event>(QUEUE_SIZE);
+
+        let _task = tokio::spawn(async move {
+            loop {
+                select! {
+
+                    // from session to network
+                    event = rx.recv().fuse() => {
+                        if let Some(event) = event {
+                            let disconnect = matches!(event, Event::Disconnect(_));
+                            let _ = sink.send(event).await;
+
+                            // the session has asked for this user to be dropped
+                            if disconnect {
+                                session.drop_user(id).await;
+                                break;
+                            }
+                        }
+                    },
+
+                    // from network
+                    event = stream.next().fuse() => {
+                        let event = match event {
+                            Some(Ok(event)) => event,
+
+                            // the connection went away without saying goodbye
+                            Some(Err(err)) => {
+                                if is_codec_error(&err) {
+                                    warn!(kind = ?err.kind(), error = %err, "codec error");
+                                    let kind = format!("{:?}", err.kind());
+                                    session.metrics.codec_errors.with_label_values(&[&kind]).inc();
+                                } else {
+                                    debug!(error = %err, "connection lost");
+                                }
+                                session.drop_user(id).await;
+                                break;
+                            }
+                            None => {
+                                debug!("connection closed");
+                                session.drop_user(id).await;
+                                break;
+                            }
+                        };
+
+                        session.touch_user(id);
+
+                        if let Event::Leave() = event {
+                            session.drop_user(id).await;
+                            break;
+                        }
+
+                        // chunks of a transfer the recipient has accepted are
+                        // limited by the file quotas instead, or a transfer
+                        // would trip the limit in no time
+                        let rate = match &event {
+                            Event::FileChunk(transfer_id, _, _)
+                                if session.transfers(|transfers, _| transfers.is_sending(id, *transfer_id)) =>
+                            {
+                                RateCheck::Allowed
+                            }
+                            _ => session.check_rate(id),
+                        };
+                        match rate {
+                            RateCheck::Allowed => {}
+                            RateCheck::Limited => {
+                                let warning = "You are sending too fast, slow down.".to_string();
+                                let _ = sink.send(Event::Warning(warning)).await;
+                                continue;
+                            }
+                            RateCheck::Muted(left) => {
+                                let warning = format!("You are muted for another {} seconds.", left.as_secs() + 1);
+                                let _ = sink.send(Event::Warning(warning)).await;
+                                continue;
+                            }
+                            RateCheck::Disconnect => {
+                                warn!("disconnecting for flooding");
+                                let reason = "Disconnected for flooding.".to_string();
+                                let _ = sink.send(Event::Disconnect(reason)).await;
+                                session.drop_user(id).await;
+                                break;
+                            }
+                        }
+
+                        match event {
+                            Event::RequestJoin(name) => {
+                                // users who leave the name out go by their
+                                // account's, if we know it
+                                let name = match session.account(id) {
+                                    Some(account) if name.trim().is_empty() => account,
+                                    _ => name,
+                                };
+
+                                if let Some(reason) = session.name_ban(&name) {
+                                    info!(name = %name, "refused banned name");
+                                    let _ = sink.send(Event::Disconnect(reason)).await;
+                                    session.drop_user(id).await;
+                                    break;
+                                }
+
+                                let (old_name, name) = session.update_user(id, name);
+                                info!(name = %name, "joined");
+                                session.metrics.joins.inc();
+
+                                // joining again is a change of name so
+                                // everyone needs to drop the old one
+                                if let Some(old_name) = old_name {
+                                    session.broadcast(|| Event::Left(old_name.clone())).await;
+                                    federation::relay(&session, Relayed::Left(old_name));
+                                }
+                                session.broadcast(|| Event::Joined(name.clone())).await;
+                                federation::relay(&session, Relayed::Joined(name.clone()));
+
+                                // let the new arrival know who else is here
+                                let users = session.list_users();
+                                session.send_event(id, Event::UserList(users)).await;
+                                // someone who has proven their key and takes
+                                // on a new name may have mail waiting under
+                                // it, but only proving the key again pins it
+                                mailbox::claim(&session, id, false).await;
+
+                                let user = ChatUser { id, name };
+                                session.plugins.joined(&session, &user).await;
+                            }
+                            Event::MessageSend(msg) => {
+                                if let Err(Some(reason)) = session.chat(id, msg).await {
+                                    let _ = sink.send(Event::Warning(reason)).await;
+                                }
+                            }
+                            Event::ListUsers() => {
+                                let users = session.list_users();
+                                session.send_event(id, Event::UserList(users)).await;
+                            }
+                            Event::SetPresence(presence, status) => {
+                                if let Some(who) = session.set_presence(id, presence, status.clone()) {
+                                    session.broadcast(|| Event::PresenceChanged(who.clone(), presence, status.clone())).await;
+                                    federation::relay(&session, Relayed::Presence(who, presence, status));
+                                }
+                            }
+                            Event::Typing() => {
+                                if session.allow_typing(id) {
+                                    let who = session.get_name(id);
+                                    session.broadcast_except(id, || Event::UserTyping(who.clone())).await;
+                                }
+                            }
+                            event if moderation::is_moderation(&event) => {
+                                moderation::handle(&session, id, event).await;
+                            }
+                            event if messages::is_message_change(&event) => {
+                                messages::handle(&session, id, event).await;
+                            }
+                            event if files::is_file_transfer(&event) => {
+                                files::handle(&session, id, event).await;
+                            }
+                            event if direct::is_direct(&event) => {
+                                direct::handle(&session, id, event).await;
+                            }
+                            event if delivery::is_tracked(&event) => {
+                                delivery::handle(&session, id, event).await;
+                            }
+                            // only the server sends the rest
+                            event => {
+                                debug!(event = ?event, "ignoring a server event from a client");
+                                let warning = "The server doesn't take that event.".to_string();
+                                let _ = sink.send(Event::Warning(warning)).await;
+                            }
+                        }
+                    }
+                    complete => break,
+                }
+            }
+        }.instrument(info_span!("connection", user_id = id, peer = %ip)));
+
+        let now = Instant::now();
+        let limits = &self.config.rate_limit;
+        self.users.insert(
+            self.counter,
+            User {
+                name: None,
+                ip,
+                sender,
+                connected_at: now,
+                last_active: now,
+                presence: Presence::Online,
+                status: None,
+                last_typing: None,
+                bucket: TokenBucket::new(limits.burst, limits.refill_per_sec),
+                violations: 0,
+                last_violation: None,
+                muted_until: None,
+                operator: false,
+                public_key: None,
+                key_challenge: None,
+                key_proven: false,
+                account,
+                acked: VecDeque::new(),
+                client: None,
+            },
+        );
+
+        self.counter
+    }
+
+    fn get_name(&self, id: u64) -> String {
+        let user = self.users.get(&id).unwrap();
+        user.get_name()
+    }
+
+    fn update_user(&mut self, id: u64, name: String) -> (Option<String>, String) {
+        let user = self.users.get_mut(&id).unwrap();
+        let old_name = user.name.as_ref().map(|_| user.get_name());
+
+        user.name = Some(name);
+        let name = user.get_name();
+        self.grant_operator(id);
+        (old_name, name)
+    }
+
+    // Names listed in the config are made operators once they have proven
+    // the key that goes with the name there.
+    fn grant_operator(&mut self, id: u64) {
+        let user = match self.users.get_mut(&id) {
+            Some(user) => user,
+            None => return,
+        };
+        let (name, key) = match (&user.name, &user.public_key) {
+            (Some(name), Some(key)) if user.key_proven => (name, key),
+            _ => return,
+        };
+
+        let listed = self.config.operators.get(name).is_some_and(|listed| {
+            let listed = listed.split_whitespace().collect::<String>();
+            listed.eq_ignore_ascii_case(&direct::fingerprint(key).replace(' ', ""))
+        });
+        if listed {
+            user.operator = true;
+        }
+    }
+
+    fn users_where<F: Fn(&User) -> bool>(&self, filter: F) -> Vec<u64> {
+        self.users
+            .iter()
+            .filter(|(_, user)| filter(user))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // Where user `id`'s tracked messages are remembered.
+    fn acks(&mut self, id: u64) -> Option<&mut Acked> {
+        let user = self.users.get_mut(&id)?;
+        match user.client {
+            Some(client) => {
+                let (used, acked) = self
+                    .client_acks
+                    .entry(client)
+                    .or_insert_with(|| (Instant::now(), VecDeque::new()));
+                *used = Instant::now();
+                Some(acked)
+            }
+            None => Some(&mut user.acked),
+        }
+    }
+
+    fn remove_user(&mut self, id: u64) -> Option<User> {
+        let user = self.users.remove(&id)?;
+
+        // forget the IP's allowance once its last connection is gone
+        let ip = user.ip.ip();
+        if !self.users.values().any(|other| other.ip.ip() == ip) {
+            self.ip_buckets.remove(&ip);
+        }
+
+        Some(user)
+    }
+
+    fn set_presence(
+        &mut self,
+        id: u64,
+        presence: Presence,
+        status: Option<String>,
+    ) -> Option<String> {
+        let user = self.users.get_mut(&id)?;
+        if user.presence == presence && user.status == status {
+            return None;
+        }
+
+        user.presence = presence;
+        user.status = status;
+        Some(user.get_name())
+    }
+
+    fn allow_typing(&mut self, id: u64) -> bool {
+        let user = match self.users.get_mut(&id) {
+            Some(user) => user,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        match user.last_typing {
+            Some(last) if now.duration_since(last) < TYPING_INTERVAL => false,
+            _ => {
+                user.last_typing = Some(now);
+                true
+            }
+        }
+    }
+
+    fn check_rate(&mut self, id: u64) -> RateCheck {
+        let limits: &RateLimitConfig = &self.config.rate_limit;
+        let user = match self.users.get_mut(&id) {
+            Some(user) => user,
+            None => return RateCheck::Allowed,
+        };
+
+        let now = Instant::now();
+        if let Some(until) = user.muted_until {
+            if now < until {
+                return RateCheck::Muted(until - now);
+            }
+            user.muted_until = None;
+        }
+
+        // local users only have their own limit, as they all share loopback
+        let mut ip_bucket =
+            match user.account {
+                Some(_) => None,
+                None => Some(self.ip_buckets.entry(user.ip.ip()).or_insert_with(|| {
+                    TokenBucket::new(limits.ip_burst, limits.ip_refill_per_sec)
+                })),
+            };
+        // a request either limit turns down doesn't cost anything
+        if user.bucket.ready(now) && ip_bucket.as_mut().is_none_or(|bucket| bucket.ready(now)) {
+            user.bucket.try_take(now);
+            if let Some(bucket) = ip_bucket {
+                bucket.try_take(now);
+            }
+            return RateCheck::Allowed;
+        }
+
+        // violations are forgiven once a user has behaved for a while
+        let window = Duration::from_secs(limits.violation_window_secs);
+        if let Some(last) = user.last_violation {
+            if now.duration_since(last) > window {
+                user.violations = 0;
+            }
+        }
+        user.violations += 1;
+        user.last_violation = Some(now);
+
+        if user.violations <= limits.max_violations {
+            return RateCheck::Limited;
+        }
+
+        user.violations = 0;
+        match limits.action {
+            ViolationAction::Mute => {
+                let mute = Duration::from_secs(limits.mute_secs).min(MAX_MUTE);
+                user.muted_until = Some(now + mute);
+                RateCheck::Muted(mute)
+            }
+            ViolationAction::Disconnect => RateCheck::Disconnect,
+        }
+    }
+
+    // Local users all seem to come from loopback, so they are counted by
+    // account instead.
+    fn connections_from(&self, ip: IpAddr) -> usize {
+        self.users
+            .values()
+            .filter(|user| user.account.is_none() && user.ip.ip() == ip)
+            .count()
+    }
+
+    fn connections_by(&self, account: &str) -> usize {
+        self.users
+            .values()
+            .filter(|user| user.account.as_deref() == Some(account))
+            .count()
+    }
+
+    fn mute(&mut self, id: u64, duration: Option<Duration>) {
+        if let Some(user) = self.users.get_mut(&id) {
+            user.muted_until = duration.map(|duration| Instant::now() + duration.min(MAX_MUTE));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Session {
+    state: Arc<RwLock<State>>,
+    plugins: Arc<Plugins>,
+    metrics: Arc<Metrics>,
+    federation: Arc<Federation>,
+}
+
+impl Session {
+    pub(crate) fn new(config: Config, bans: BanList, plugins: Plugins) -> Self {
+        let node = config
+            .federation
+            .node_id
+            .clone()
+            .unwrap_or_else(|| config.listen.clone());
+        Session {
+            plugins: Arc::new(plugins),
+            metrics: Arc::new(Metrics::new()),
+            federation: Arc::new(Federation::new(node)),
+            state: Arc::new(RwLock::new(State {
+                counter: 0,
+                users: BTreeMap::new(),
+                ip_buckets: HashMap::new(),
+                config,
+                bans,
+                message_counter: 0,
+                messages: BTreeMap::new(),
+                transfers: Transfers::default(),
+                mailboxes: Mailboxes::default(),
+                client_acks: HashMap::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn add_user(&self, ip: SocketAddr, sink: EventSink, stream: EventStream) -> u64 {
+        self.add_user_as(ip, None, sink, stream)
+    }
+
+    // Adds a user whose local account is known.
+    pub(crate) fn add_user_as(
+        &self,
+        ip: SocketAddr,
+        account: Option<String>,
+        sink: EventSink,
+        stream: EventStream,
+    ) -> u64 {
+        self.state
+            .write()
+            .unwrap()
+            .add_user(self.clone(), ip, account, sink, stream)
+    }
+
+    fn account(&self, id: u64) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.users.get(&id)?.account.clone()
+    }
+
+    pub(crate) fn get_name(&self, id: u64) -> String {
+        self.state.read().unwrap().get_name(id)
+    }
+
+    fn update_user(&self, id: u64, name: String) -> (Option<String>, String) {
+        self.state.write().unwrap().update_user(id, name)
+    }
+
+    // Returns the user's name if the presence actually changed and needs to
+    // be announced.
+    fn set_presence(&self, id: u64, presence: Presence, status: Option<String>) -> Option<String> {
+        self.state
+            .write()
+            .unwrap()
+            .set_presence(id, presence, status)
+    }
+
+    fn allow_typing(&self, id: u64) -> bool {
+        self.state.write().unwrap().allow_typing(id)
+    }
+
+    fn check_rate(&self, id: u64) -> RateCheck {
+        self.state.write().unwrap().check_rate(id)
+    }
+
+    fn connections_from(&self, ip: IpAddr) -> usize {
+        self.state.read().unwrap().connections_from(ip)
+    }
+
+    fn connections_by(&self, account: &str) -> usize {
+        self.state.read().unwrap().connections_by(account)
+    }
+
+    fn max_connections_per_ip(&self) -> usize {
+        self.state
+            .read()
+            .unwrap()
+            .config
+            .rate_limit
+            .max_connections_per_ip
+    }
+
+    // Why a new connection from `ip` should be turned away, if it should.
+    pub(crate) fn refusal(&self, ip: IpAddr) -> Option<String> {
+        if let Some(reason) = self.ip_ban(ip) {
+            Some(reason)
+        } else if self.connections_from(ip) >= self.max_connections_per_ip() {
+            Some("Too many connections from your address.".to_string())
+        } else {
+            None
+        }
+    }
+
+    // The same for a new local connection from `account`. Bans and limits on
+    // addresses don't apply, as every local user has the same one.
+    pub(crate) fn local_refusal(&self, account: &str) -> Option<String> {
+        if self.connections_by(account) >= self.max_connections_per_ip() {
+            Some("Too many connections from your account.".to_string())
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn is_operator(&self, id: u64) -> bool {
+        let state = self.state.read().unwrap();
+        state.users.get(&id).is_some_and(|user| user.operator)
+    }
+
+    pub(crate) fn set_operator(&self, id: u64, operator: bool) {
+        if let Some(user) = self.state.write().unwrap().users.get_mut(&id) {
+            user.operator = operator;
+        }
+    }
+
+    pub(crate) fn users_named(&self, name: &str) -> Vec<u64> {
+        self.state
+            .read()
+            .unwrap()
+            .users_where(|user| user.name.as_deref() == Some(name))
+    }
+
+    pub(crate) fn set_public_key(&self, id: u64, key: Vec<u8>, challenge: StaticSecret) {
+        let mut state = self.state.write().unwrap();
+        if let Some(user) = state.users.get_mut(&id) {
+            user.public_key = Some(key);
+            user.key_challenge = Some(challenge);
+            user.key_proven = false;
+        }
+    }
+
+    // Checks user `id`'s answer to the challenge for their key. Each challenge
+    // can only be answered once.
+    pub(crate) fn prove_key(&self, id: u64, proof: &[u8]) -> bool {
+        let mut state = self.state.write().unwrap();
+        let user = match state.users.get_mut(&id) {
+            Some(user) => user,
+            None => return false,
+        };
+        let (challenge, key) = match (user.key_challenge.take(), &user.public_key) {
+            (Some(challenge), Some(key)) => (challenge, key),
+            _ => return false,
+        };
+
+        let proven = direct::key_proof(&challenge, key)
+            .is_some_and(|expected| admin::same(&expected, proof));
+        user.key_proven = proven;
+        state.grant_operator(id);
+        proven
+    }
+
+    // The name user `id` joined under and their key, if they have proven it.
+    pub(crate) fn proven_key(&self, id: u64) -> Option<(String, Vec<u8>)> {
+        let state = self.state.read().unwrap();
+        let user = state.users.get(&id)?;
+        if !user.key_proven {
+            return None;
+        }
+        Some((user.name.clone()?, user.public_key.clone()?))
+    }
+
+    // The name user `id` joined under, without their address.
+    pub(crate) fn bare_name(&self, id: u64) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.users.get(&id)?.name.clone()
+    }
+
+    pub(crate) fn public_key(&self, id: u64) -> Option<Vec<u8>> {
+        let state = self.state.read().unwrap();
+        state.users.get(&id)?.public_key.clone()
+    }
+
+    // The first user called `name` with a public key, along with their full
+    // name and the key.
+    pub(crate) fn public_key_of(&self, name: &str) -> Option<(u64, String, Vec<u8>)> {
+        let state = self.state.read().unwrap();
+        state
+            .users
+            .iter()
+            .filter(|(_, user)| user.name.as_deref() == Some(name))
+            .find_map(|(id, user)| {
+                let key = user.public_key.clone()?;
+                Some((*id, user.get_name(), key))
+            })
+    }
+
+    // Where user `id`'s tracked message `seq` has got to, starting it off if
+    // it is new.
+    pub(crate) fn track(&self, id: u64, seq: u64) -> Tracked {
+        let mut state = self.state.write().unwrap();
+        let acked = match state.acks(id) {
+            Some(acked) => acked,
+            None => return Tracked::Sending,
+        };
+        match acked.iter().find(|(acked, _)| *acked == seq) {
+            Some((_, Some(message_id))) => Tracked::Sent(*message_id),
+            Some((_, None)) => Tracked::Sending,
+            None => {
+                acked.push_back((seq, None));
+                if acked.len() > ACK_HISTORY {
+                    acked.pop_front();
+                }
+                Tracked::New
+            }
+        }
+    }
+
+    // Records the ID tracked message `seq` went out with, or forgets it if
+    // it didn't, so that it can be tried again.
+    pub(crate) fn remember_ack(&self, id: u64, seq: u64, message_id: Option<u64>) {
+        let mut state = self.state.write().unwrap();
+        if let Some(acked) = state.acks(id) {
+            match message_id {
+                Some(message_id) => {
+                    if let Some(entry) = acked.iter_mut().find(|(acked, _)| *acked == seq) {
+                        entry.1 = Some(message_id);
+                    }
+                }
+                None => acked.retain(|(acked, _)| *acked != seq),
+            }
+        }
+    }
+
+    // Keeps user `id`'s tracked messages under `client` from now on, along
+    // with any sent under it before they connected again.
+    pub(crate) fn set_client(&self, id: u64, client: u64) {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        let user = match state.users.get_mut(&id) {
+            Some(user) => user,
+            None => return,
+        };
+        user.client = Some(client);
+        let acked = mem::take(&mut user.acked);
+
+        if !state.client_acks.contains_key(&client) && state.client_acks.len() >= ACK_CLIENTS {
+            let oldest = state
+                .client_acks
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(client, _)| *client);
+            if let Some(oldest) = oldest {
+                state.client_acks.remove(&oldest);
+            }
+        }
+        let (used, known) = state
+            .client_acks
+            .entry(client)
+            .or_insert_with(|| (Instant::now(), VecDeque::new()));
+        *used = Instant::now();
+        known.extend(acked);
+        while known.len() > ACK_HISTORY {
+            known.pop_front();
+        }
+    }
+
+    // Users connected from `ip`, leaving out local ones.
+    pub(crate) fn users_from(&self, ip: IpAddr) -> Vec<u64> {
+        self.state
+            .read()
+            .unwrap()
+            .users_where(|user| user.account.is_none() && user.ip.ip() == ip)
+    }
+
+    pub(crate) fn mute(&self, id: u64, duration: Option<Duration>) {
+        self.state.write().unwrap().mute(id, duration);
+    }
+
+    pub(crate) fn add_ban(&self, ban: Ban) -> std::io::Result<()> {
+        self.state.write().unwrap().bans.add(ban)
+    }
+
+    pub(crate) fn remove_ban(&self, target: &str) -> std::io::Result<bool> {
+        self.state.write().unwrap().bans.remove(target)
+    }
+
+    // The reason to give a connection from `ip` if it is banned.
+    fn ip_ban(&self, ip: IpAddr) -> Option<String> {
+        let mut state = self.state.write().unwrap();
+        state.bans.find_ip(ip).map(moderation::ban_reason)
+    }
+
+    fn name_ban(&self, name: &str) -> Option<String> {
+        let mut state = self.state.write().unwrap();
+        state.bans.find_name(name).map(moderation::ban_reason)
+    }
+
+    fn touch_user(&self, id: u64) {
+        if let Some(user) = self.state.write().unwrap().users.get_mut(&id) {
+            user.last_active = Instant::now();
+        }
+    }
+
+    // Everyone connected, joined or not, with the details only an admin gets
+    // to see.
+    pub(crate) fn user_details(&self) -> Vec<UserDetails> {
+        let state = self.state.read().unwrap();
+        state
+            .users
+            .iter()
+            .map(|(id, user)| UserDetails {
+                id: *id,
+                name: user.name.clone(),
+                ip: user.ip,
+                connected_secs: user.connected_at.elapsed().as_secs(),
+                idle_secs: user.last_active.elapsed().as_secs(),
+                operator: user.operator,
+            })
+            .collect()
+    }
+
+    // Swaps in a new config. Listening addresses and the ban file are only
+    // read at startup, so changes to those need a restart.
+    // Switches to `config`, with the new rate limits applying to everyone
+    // already connected too.
+    pub(crate) fn set_config(&self, config: Config) {
+        let mut state = self.state.write().unwrap();
+        let limits = &config.rate_limit;
+        let now = Instant::now();
+        for user in state.users.values_mut() {
+            user.bucket
+                .set_limits(now, limits.burst, limits.refill_per_sec);
+        }
+        for bucket in state.ip_buckets.values_mut() {
+            bucket.set_limits(now, limits.ip_burst, limits.ip_refill_per_sec);
+        }
+        state.config = config;
+    }
+
+    pub(crate) fn admin_token(&self) -> Option<String> {
+        self.state.read().unwrap().config.admin_token.clone()
+    }
+
+    fn user_info(&self, id: u64) -> Option<UserInfo> {
+        let state = self.state.read().unwrap();
+        state
+            .users
+            .get(&id)
+            .filter(|user| user.name.is_some())
+            .map(User::info)
+    }
+
+    // Wraps a new connection so that its traffic is counted.
+    pub(crate) fn counted<T>(&self, transport: T) -> Counted<T> {
+        Counted::new(transport, self.metrics.clone())
+    }
+
+    // Brings the gauges up to date and renders every metric.
+    pub(crate) fn metrics(&self) -> String {
+        {
+            let state = self.state.read().unwrap();
+            self.metrics.connected_users.set(state.users.len() as i64);
+
+            // start afresh so that users who have left drop out
+            self.metrics.queue_depth.reset();
+            for (id, user) in &state.users {
+                let depth = QUEUE_SIZE - user.sender.capacity();
+                self.metrics
+                    .queue_depth
+                    .with_label_values(&[&id.to_string()])
+                    .set(depth as i64);
+            }
+        }
+
+        self.metrics.render()
+    }
+
+    // Only users that have joined are listed; connections that haven't sent
+    // a join request yet are left out.
+    pub(crate) fn local_users(&self) -> Vec<UserInfo> {
+        self.user_ids()
+            .into_iter()
+            .filter_map(|id| self.user_info(id))
+            .collect()
+    }
+
+    // Users here and on every server we're linked to.
+    pub(crate) fn list_users(&self) -> Vec<UserInfo> {
+        let mut users = self.local_users();
+        users.extend(self.federation.users());
+        users
+    }
+
+    fn remove_user(&self, id: u64) -> Option<String> {
+        let user = self.state.write().unwrap().remove_user(id)?;
+        user.name.as_ref().map(|_| user.get_name())
+    }
+
+    // Asks a user's connection to send them `reason` and hang up.
+    pub(crate) async fn disconnect(&self, id: u64, reason: String) {
+        self.send_event(id, Event::Disconnect(reason)).await;
+    }
+
+    // Removes a user and lets everyone else know that they've gone.
+    async fn drop_user(&self, id: u64) {
+        files::abandon(self, id).await;
+        mailbox::seen(self, id);
+
+        if let Some(name) = self.remove_user(id) {
+            info!(name = %name, "left");
+            self.metrics.leaves.inc();
+            self.broadcast(|| Event::Left(name.clone())).await;
+            federation::relay(self, Relayed::Left(name.clone()));
+            self.plugins.left(self, &name).await;
+        }
+    }
+
+    pub(crate) fn plugins(&self) -> &Plugins {
+        &self.plugins
+    }
+
+    pub(crate) fn federation(&self) -> &Federation {
+        &self.federation
+    }
+
+    // Sends a chat message from user `id` to everyone, returning its ID. If a
+    // plugin turns it down the error has the reason to give them, if any.
+    pub(crate) async fn chat(&self, id: u64, msg: String) -> Result<u64, Option<String>> {
+        if self.too_long(&msg) {
+            return Err(Some(TOO_LONG.to_string()));
+        }
+
+        let user = ChatUser {
+            id,
+            name: self.get_name(id),
+        };
+        let msg = match self.plugins.message(self, &user, msg).await {
+            Ok(msg) => msg,
+            Err(reason) => {
+                debug!("message vetoed by a plugin");
+                return Err(reason);
+            }
+        };
+
+        debug!(len = msg.len(), "message sent");
+        let everyone = self.user_ids();
+        // plugins can make a message longer, so it is checked again
+        let message_id = self
+            .post_message(everyone, Some(id), &user.name, &msg)
+            .await
+            .ok_or_else(|| Some(TOO_LONG.to_string()))?;
+        mentions::notify(self, Some(id), message_id, &user.name, &msg).await;
+        federation::relay(self, Relayed::Message(user.name.clone(), msg.clone()));
+        self.metrics.messages.inc();
+        self.plugins.command(self, &user, &msg).await;
+        Ok(message_id)
+    }
+
+    // Sends a chat message to `recipients` and remembers it so that it can
+    // be changed later. Returns the new message's ID, or `None` if it is too
+    // long to send.
+    pub(crate) async fn post_message(
+        &self,
+        recipients: Vec<u64>,
+        author: Option<u64>,
+        who: &str,
+        text: &str,
+    ) -> Option<u64> {
+        if self.too_long(text) {
+            debug!(len = text.len(), "message too long to send");
+            return None;
+        }
+
+        let message_id = {
+            let mut state = self.state.write().unwrap();
+            state.message_counter += 1;
+            let message_id = state.message_counter;
+
+            state.messages.insert(
+                message_id,
+                SentMessage {
+                    author,
+                    recipients: recipients.clone(),
+                    reactions: BTreeMap::new(),
+                },
+            );
+            if state.messages.len() > MESSAGE_HISTORY {
+                let oldest = *state.messages.keys().next().unwrap();
+                state.messages.remove(&oldest);
+            }
+
+            message_id
+        };
+
+        self.broadcast_to(recipients, || {
+            Event::MessageReceived(message_id, who.to_string(), text.to_string())
+        })
+        .await;
+        Some(message_id)
+    }
+
+    // Whether a message is longer than the config allows.
+    pub(crate) fn too_long(&self, text: &str) -> bool {
+        let config = &self.state.read().unwrap().config;
+        text.len() > config.max_message_len.min(MAX_MESSAGE_LEN)
+    }
+
+    // Who wrote a message and who it went to, if we still remember it.
+    pub(crate) fn message(&self, message_id: u64) -> Option<(Option<u64>, Vec<u64>)> {
+        let state = self.state.read().unwrap();
+        state
+            .messages
+            .get(&message_id)
+            .map(|message| (message.author, message.recipients.clone()))
+    }
+
+    pub(crate) fn forget_message(&self, message_id: u64) {
+        self.state.write().unwrap().messages.remove(&message_id);
+    }
+
+    // Adds user `id`'s reaction to a message, or takes it away if they had
+    // already reacted that way. Returns the message's reactions afterwards.
+    pub(crate) fn toggle_reaction(&self, message_id: u64, id: u64, emoji: &str) -> Vec<Reaction> {
+        let mut state = self.state.write().unwrap();
+        let message = match state.messages.get_mut(&message_id) {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+
+        let users = message.reactions.entry(emoji.to_string()).or_default();
+        if !users.remove(&id) {
+            users.insert(id);
+        }
+        if users.is_empty() {
+            message.reactions.remove(emoji);
+        }
+
+        message
+            .reactions
+            .iter()
+            .map(|(emoji, users)| Reaction {
+                emoji: emoji.clone(),
+                count: users.len() as u64,
+            })
+            .collect()
+    }
+
+    // Runs `f` against the file transfers under way and the limits on them.
+    pub(crate) fn transfers<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Transfers, &FileConfig) -> R,
+    {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        f(&mut state.transfers, &state.config.files)
+    }
+
+    // Runs `f` against the messages waiting for people who aren't here.
+    pub(crate) fn mailboxes<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Mailboxes, &MailboxConfig) -> R,
+    {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        f(&mut state.mailboxes, &state.config.mailbox)
+    }
+
+    pub(crate) fn user_ids(&self) -> Vec<u64> {
+        self.state.read().unwrap().users.keys().copied().collect()
+    }
+
+    pub(crate) async fn broadcast<F: Fn() -> Event>(&self, event_gen: F) {
+        self.broadcast_to(self.user_ids(), event_gen).await;
+    }
+
+    async fn broadcast_except<F: Fn() -> Event>(&self, except: u64, event_gen: F) {
+        let ids = self
+            .user_ids()
+            .into_iter()
+            .filter(|id| *id != except)
+            .collect();
+        self.broadcast_to(ids, event_gen).await;
+    }
+
+    pub(crate) async fn broadcast_to<F: Fn() -> Event>(&self, ids: Vec<u64>, event_gen: F) {
+        let timer = self.metrics.broadcast_seconds.start_timer();
+        let futs = ids
+            .into_iter()
+            .map(|dest_id| self.send_event(dest_id, event_gen()));
+        future::join_all(futs).await;
+        timer.observe_duration();
+    }
+
+    pub(crate) async fn send_event(&self, id: u64, evt: Event) {
+        let sender = {
+            let state = self.state.read().unwrap();
+            if let Some(user) = state.users.get(&id) {
+                user.sender.clone()
+            } else {
+                return;
+            }
+        };
+
+        // the user may have gone away since we looked them up
+        let _ = sender.send(evt).await;
+    }
+}
+
+// Errors from the codec, as opposed to the connection itself failing.
+fn is_codec_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+    )
+}
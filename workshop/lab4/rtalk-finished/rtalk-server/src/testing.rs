@@ -0,0 +1,388 @@
+// Helpers for testing code built on the server without binding real ports.
+// Clients are wired up to the server over in-memory pipes.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use futures_util::sink::SinkExt;
+use tokio::io::{duplex, DuplexStream};
+use tokio::time::timeout;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Framed};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use rtalk_codec::{Event, EventCodec};
+
+use crate::direct;
+use crate::{Config, Server};
+
+// How long `recv` waits before deciding that nothing is coming.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+// Every test client gets its own made up port so that clients can be told
+// apart by address.
+static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+// The config tests start from, which keeps nothing on disk between runs.
+pub fn config() -> Config {
+    Config {
+        ban_file: None,
+        ..Config::default()
+    }
+}
+
+// The secret half of the key operators prove in tests.
+pub const OPERATOR_KEY: [u8; 32] = [42; 32];
+
+// A config under which anyone called `name` who proves `OPERATOR_KEY` is an
+// operator.
+pub fn operator_config(name: &str) -> Config {
+    let key = PublicKey::from(&StaticSecret::from(OPERATOR_KEY));
+    let mut config = config();
+    config
+        .operators
+        .insert(name.to_string(), direct::fingerprint(key.as_bytes()));
+    config
+}
+
+pub fn server() -> Server {
+    server_with(config())
+}
+
+pub fn server_with(config: Config) -> Server {
+    Server::builder().config(config).build().unwrap()
+}
+
+pub struct TestClient {
+    framed: Framed<DuplexStream, EventCodec>,
+    pub peer: SocketAddr,
+}
+
+impl TestClient {
+    // Connects a client that appears to come from 127.0.0.1.
+    pub fn connect(server: &Server) -> Self {
+        TestClient::connect_from(server, IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    pub fn connect_from(server: &Server, ip: IpAddr) -> Self {
+        let peer = SocketAddr::new(ip, NEXT_PORT.fetch_add(1, Ordering::Relaxed));
+        let (client, server_end) = duplex(PIPE_SIZE);
+        server.connect(server_end, peer);
+
+        TestClient {
+            framed: EventCodec.framed(client),
+            peer,
+        }
+    }
+
+    // Connects and joins as `name`, returning once the server has answered
+    // with the user list.
+    pub async fn join(server: &Server, name: &str) -> Self {
+        let mut client = TestClient::connect(server);
+        client.send(Event::RequestJoin(name.to_string())).await;
+        client
+            .recv_until(|event| matches!(event, Event::UserList(_)))
+            .await;
+        client
+    }
+
+    // Publishes the public half of `secret` as our key and answers the
+    // server's challenge for it, returning the public key.
+    pub async fn prove_key(&mut self, secret: [u8; 32]) -> Vec<u8> {
+        let secret = StaticSecret::from(secret);
+        let key = PublicKey::from(&secret).as_bytes().to_vec();
+        self.send(Event::PublishKey(key.clone())).await;
+
+        let challenge = match self
+            .recv_until(|event| matches!(event, Event::KeyChallenge(_)))
+            .await
+        {
+            Event::KeyChallenge(challenge) => challenge,
+            _ => unreachable!(),
+        };
+        let proof = direct::key_proof(&secret, &challenge).expect("unusable challenge");
+        self.send(Event::KeyProof(proof)).await;
+        key
+    }
+
+    pub async fn send(&mut self, event: Event) {
+        self.framed.send(event).await.expect("send failed");
+    }
+
+    // The next event from the server, or `None` if the server hung up.
+    pub async fn try_recv(&mut self) -> io::Result<Option<Event>> {
+        match timeout(RECV_TIMEOUT, self.framed.next()).await {
+            Ok(event) => event.transpose(),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no event from the server",
+            )),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Event {
+        self.try_recv()
+            .await
+            .expect("receive failed")
+            .expect("server hung up")
+    }
+
+    // Skips events until one matches.
+    pub async fn recv_until<F: Fn(&Event) -> bool>(&mut self, matches: F) -> Event {
+        loop {
+            let event = self.recv().await;
+            if matches(&event) {
+                return event;
+            }
+        }
+    }
+
+    // Waits for the server to hang up, returning the last thing it said.
+    pub async fn recv_disconnect(&mut self) -> Option<Event> {
+        let mut last = None;
+        while let Ok(Some(event)) = self.try_recv().await {
+            last = Some(event);
+        }
+        last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rtalk_codec::MAX_FRAME;
+
+    use crate::ViolationAction;
+
+    fn said(event: &Event, text: &str) -> bool {
+        matches!(event, Event::MessageReceived(_, _, msg) if msg == text)
+    }
+
+    #[tokio::test]
+    async fn messages_reach_everyone() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        alice
+            .recv_until(|e| matches!(e, Event::Joined(who) if who.starts_with("bob ")))
+            .await;
+
+        bob.send(Event::MessageSend("hi alice".to_string())).await;
+        match alice.recv_until(|e| said(e, "hi alice")).await {
+            Event::MessageReceived(_, who, _) => assert!(who.starts_with("bob ")),
+            _ => unreachable!(),
+        }
+        bob.recv_until(|e| said(e, "hi alice")).await;
+
+        let names = server
+            .users()
+            .into_iter()
+            .map(|user| user.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn messages_too_long_to_pass_on_are_refused() {
+        // however long the config allows, the frame needs room for who the
+        // message is from
+        let server = server_with(Config {
+            max_message_len: usize::MAX,
+            ..config()
+        });
+        let mut alice = TestClient::join(&server, "alice").await;
+        let mut bob = TestClient::join(&server, "bob").await;
+
+        alice
+            .send(Event::MessageSend("x".repeat(MAX_FRAME - 100)))
+            .await;
+        alice
+            .recv_until(|e| warned(e, "That message is too long"))
+            .await;
+
+        // bob is still here to hear what comes next
+        alice.send(Event::MessageSend("sorry".to_string())).await;
+        bob.recv_until(|e| said(e, "sorry")).await;
+    }
+
+    #[tokio::test]
+    async fn messages_can_be_as_long_as_the_config_says() {
+        let server = server_with(Config {
+            max_message_len: 5,
+            ..config()
+        });
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        alice.send(Event::MessageSend("hello!".to_string())).await;
+        alice
+            .recv_until(|e| warned(e, "That message is too long"))
+            .await;
+        alice.send(Event::MessageSend("hello".to_string())).await;
+        let id = match alice.recv_until(|e| said(e, "hello")).await {
+            Event::MessageReceived(id, _, _) => id,
+            _ => unreachable!(),
+        };
+
+        // edits too
+        alice.send(Event::Edit(id, "hello!".to_string())).await;
+        alice
+            .recv_until(|e| warned(e, "That message is too long"))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn operators_can_kick() {
+        let server = server_with(operator_config("op"));
+        let mut op = TestClient::join(&server, "op").await;
+        op.prove_key(OPERATOR_KEY).await;
+        let mut troll = TestClient::join(&server, "troll").await;
+
+        // ordinary users can't kick anyone, not even by taking an
+        // operator's name
+        troll
+            .send(Event::Kick("op".to_string(), "nope".to_string()))
+            .await;
+        troll.recv_until(|e| matches!(e, Event::Warning(_))).await;
+        let mut impostor = TestClient::join(&server, "op").await;
+        impostor.prove_key([13; 32]).await;
+        impostor
+            .send(Event::Kick("troll".to_string(), "nope".to_string()))
+            .await;
+        impostor
+            .recv_until(|e| warned(e, "You need to be an operator"))
+            .await;
+
+        op.send(Event::Kick("troll".to_string(), "be nice".to_string()))
+            .await;
+        match troll.recv_disconnect().await {
+            Some(Event::Disconnect(reason)) => assert!(reason.contains("be nice")),
+            other => panic!("unexpected {:?}", other),
+        }
+        op.recv_until(|e| matches!(e, Event::Left(who) if who.starts_with("troll ")))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn mutes_can_be_as_long_as_anyone_likes() {
+        let server = server_with(operator_config("op"));
+        let mut op = TestClient::join(&server, "op").await;
+        op.prove_key(OPERATOR_KEY).await;
+        let mut troll = TestClient::join(&server, "troll").await;
+
+        op.send(Event::Mute("troll".to_string(), u64::MAX)).await;
+        troll
+            .recv_until(|e| matches!(e, Event::Warning(text) if text.contains("muted")))
+            .await;
+        troll.send(Event::MessageSend("hello?".to_string())).await;
+        troll
+            .recv_until(|e| matches!(e, Event::Warning(text) if text.starts_with("You are muted")))
+            .await;
+
+        // and the server carries on
+        op.send(Event::ListUsers()).await;
+        op.recv_until(|e| matches!(e, Event::UserList(_))).await;
+    }
+
+    #[tokio::test]
+    async fn server_events_from_clients_are_refused() {
+        let server = server();
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        alice.send(Event::Joined("mallory".to_string())).await;
+        alice.send(Event::UserList(Vec::new())).await;
+        alice.recv_until(|e| matches!(e, Event::Warning(_))).await;
+        alice.recv_until(|e| matches!(e, Event::Warning(_))).await;
+
+        // still connected, and nobody else turned up
+        alice.send(Event::ListUsers()).await;
+        match alice.recv_until(|e| matches!(e, Event::UserList(_))).await {
+            Event::UserList(users) => assert_eq!(users.len(), 1),
+            _ => unreachable!(),
+        }
+    }
+
+    fn flood_limits(action: ViolationAction) -> Config {
+        let mut config = config();
+        config.rate_limit.burst = 1;
+        config.rate_limit.refill_per_sec = 0.0;
+        config.rate_limit.max_violations = 2;
+        config.rate_limit.action = action;
+        config
+    }
+
+    fn warned(event: &Event, text: &str) -> bool {
+        matches!(event, Event::Warning(warning) if warning.starts_with(text))
+    }
+
+    #[tokio::test]
+    async fn flooding_gets_a_warning_then_a_mute() {
+        let server = server_with(flood_limits(ViolationAction::Mute));
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        for _ in 0..4 {
+            alice.send(Event::MessageSend("spam".to_string())).await;
+        }
+        // the first one goes through
+        alice
+            .recv_until(|e| warned(e, "You are sending too fast"))
+            .await;
+        alice
+            .recv_until(|e| warned(e, "You are sending too fast"))
+            .await;
+        alice.recv_until(|e| warned(e, "You are muted")).await;
+
+        alice.send(Event::MessageSend("spam".to_string())).await;
+        alice.recv_until(|e| warned(e, "You are muted")).await;
+    }
+
+    #[tokio::test]
+    async fn flooding_can_get_you_disconnected() {
+        let server = server_with(flood_limits(ViolationAction::Disconnect));
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        for _ in 0..4 {
+            alice.send(Event::MessageSend("spam".to_string())).await;
+        }
+        match alice.recv_disconnect().await {
+            Some(Event::Disconnect(reason)) => assert!(reason.contains("flooding")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(server.users().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunks_for_no_transfer_are_rate_limited() {
+        let server = server_with(flood_limits(ViolationAction::Mute));
+        let mut alice = TestClient::join(&server, "alice").await;
+
+        // the first is turned down as there's no such transfer, the second
+        // doesn't get that far
+        alice.send(Event::FileChunk(99, 0, vec![0; 10])).await;
+        alice.send(Event::FileChunk(99, 0, vec![0; 10])).await;
+        alice
+            .recv_until(|e| warned(e, "You are sending too fast"))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn connections_per_ip_are_capped() {
+        let mut config = config();
+        config.rate_limit.max_connections_per_ip = 1;
+        let server = server_with(config);
+
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let _first = TestClient::connect_from(&server, ip);
+        let mut second = TestClient::connect_from(&server, ip);
+
+        assert!(matches!(
+            second.recv_disconnect().await,
+            Some(Event::Disconnect(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+// Connections from the same machine over a Unix domain socket, for local
+// tools and sidecars. The kernel tells us which account is at the other end,
+// so those users don't have to pick a name.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+
+use tokio::net::{UnixListener, UnixStream};
+
+// The socket file of a listener, which is removed when this is dropped so
+// that the next server to start doesn't trip over it.
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Listens on a Unix socket at `path`. A socket file left behind by a server
+// that didn't shut down cleanly is replaced, but one that another server is
+// still listening on isn't.
+pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<(UnixListener, SocketFile)> {
+    let path = path.as_ref();
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is in the way of the socket", path.display()),
+            ));
+        }
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("something is already listening on {}", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let file = SocketFile {
+        path: path.to_path_buf(),
+    };
+    Ok((listener, file))
+}
+
+// The name of the account at the other end of `stream`.
+pub(crate) fn peer_account(stream: &UnixStream) -> io::Result<String> {
+    let uid = stream.peer_cred()?.uid();
+    Ok(account_name(uid).unwrap_or_else(|| format!("uid{}", uid)))
+}
+
+// Looks `uid` up in /etc/passwd. Accounts from elsewhere, such as LDAP,
+// aren't found and end up named after their number instead.
+fn account_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let entry_uid = fields.nth(1)?.parse::<u32>().ok()?;
+        if entry_uid == uid {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    use futures_util::sink::SinkExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::Decoder;
+
+    use rtalk_codec::{Event, EventCodec};
+
+    use crate::testing::{self, TestClient};
+
+    fn socket_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rtalk-{}-{}.sock", test, std::process::id()))
+    }
+
+    // Connects to the socket at `path` and asks to join, returning the first
+    // user list or disconnect the server answers with.
+    async fn join(path: &Path) -> Event {
+        let mut client = EventCodec.framed(UnixStream::connect(path).await.unwrap());
+        let _ = client.send(Event::RequestJoin(String::new())).await;
+        loop {
+            match client.next().await {
+                Some(Ok(event @ (Event::UserList(_) | Event::Disconnect(_)))) => return event,
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn local_users_are_named_after_their_account() {
+        let path = socket_path("account");
+        let (listener, _file) = bind_unix(&path).unwrap();
+
+        let server = testing::server();
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve_unix(listener).await });
+
+        // we made the socket file, so it belongs to the account we run as
+        let uid = fs::metadata(&path).unwrap().uid();
+        let account = account_name(uid).unwrap_or_else(|| format!("uid{}", uid));
+
+        match join(&path).await {
+            Event::UserList(users) => assert!(users[0].name.starts_with(&format!("{} ", account))),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_users_are_limited_by_account_not_address() {
+        let path = socket_path("limits");
+        let (listener, _file) = bind_unix(&path).unwrap();
+
+        let mut config = testing::config();
+        config.rate_limit.max_connections_per_ip = 1;
+        let server = testing::server_with(config);
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve_unix(listener).await });
+
+        // someone on loopback doesn't use up the local users' allowance...
+        let _alice = TestClient::join(&server, "alice").await;
+        let mut first = EventCodec.framed(UnixStream::connect(&path).await.unwrap());
+        first.send(Event::RequestJoin(String::new())).await.unwrap();
+        loop {
+            match first.next().await {
+                Some(Ok(Event::UserList(users))) => {
+                    assert_eq!(users.len(), 2);
+                    break;
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        // ...but the account has its own
+        match join(&path).await {
+            Event::Disconnect(reason) => assert!(reason.contains("your account")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn socket_files_are_cleaned_up() {
+        let path = socket_path("cleanup");
+
+        // a crashed server leaves its socket file behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let (_listener, file) = bind_unix(&path).unwrap();
+        let err = bind_unix(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use futures::{future, SinkExt, StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_util::codec::{Decoder, Encoder};
+
+use rtalk_codec::{Event, EventCodec};
+
+use crate::session::Session;
+
+// Accepts WebSocket connections and adds them to the same session as the TCP
+// users. Each frame carries one event, either encoded with `EventCodec` in a
+// binary frame or as JSON in a text frame.
+pub async fn serve(listener: TcpListener, session: Session) -> io::Result<()> {
+    loop {
+        let (socket, ip) = listener.accept().await?;
+
+        // the handshake needs a round trip, so don't hold up everyone else
+        // while it happens
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accept(socket, ip, session).await {
+                tracing::debug!(peer = %ip, error = %err, "WebSocket handshake failed");
+            }
+        });
+    }
+}
+
+pub(crate) async fn accept<T>(transport: T, ip: SocketAddr, session: Session) -> Result<(), WsError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ws = tokio_tungstenite::accept_async(session.counted(transport)).await?;
+    let (sink, stream) = ws.split();
+
+    // we answer in whatever form the client last spoke to us in, JSON until
+    // we hear otherwise
+    let binary = Arc::new(AtomicBool::new(false));
+
+    let stream = stream.map_err(to_io_error).try_filter_map({
+        let binary = binary.clone();
+        move |msg| future::ready(decode(msg, &binary))
+    });
+    let mut sink = sink
+        .sink_map_err(to_io_error)
+        .with(move |event: Event| future::ready(encode(event, &binary)));
+
+    if let Some(reason) = session.refusal(ip.ip()) {
+        tracing::info!(peer = %ip, reason = %reason, "connection refused");
+        let _ = sink.send(Event::Disconnect(reason)).await;
+        return Ok(());
+    }
+
+    session.add_user(ip, Box::pin(sink), Box::pin(stream));
+    Ok(())
+}
+
+fn to_io_error(err: WsError) -> io::Error {
+    io::Error::other(err)
+}
+
+fn decode(msg: Message, binary: &AtomicBool) -> io::Result<Option<Event>> {
+    match msg {
+        Message::Binary(data) => {
+            binary.store(true, Ordering::Relaxed);
+
+            let mut buf = BytesMut::from(&data[..]);
+            match EventCodec.decode(&mut buf)? {
+                Some(event) if buf.is_empty() => Ok(Some(event)),
+                _ => Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "A binary frame must hold exactly one event",
+                )),
+            }
+        }
+        Message::Text(text) => {
+            binary.store(false, Ordering::Relaxed);
+
+            serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+        }
+        Message::Close(_) => Err(io::Error::from(ErrorKind::ConnectionAborted)),
+
+        // tungstenite answers pings on its own
+        _ => Ok(None),
+    }
+}
+
+fn encode(event: Event, binary: &AtomicBool) -> io::Result<Message> {
+    if binary.load(Ordering::Relaxed) {
+        let mut buf = BytesMut::new();
+        EventCodec.encode(event, &mut buf)?;
+        Ok(Message::Binary(buf.to_vec()))
+    } else {
+        serde_json::to_string(&event)
+            .map(Message::Text)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}